@@ -43,10 +43,72 @@ pub trait App<C: ArrayLength<u8>, R: ArrayLength<u8>>: Aid {
 
     /// Deselects the app. This is the result of another app getting selected.
     /// App should clear any sensitive state and reset security indicators.
-    fn deselect(&mut self);
+    /// `interface` is the one the dispatcher is currently serving, for apps whose
+    /// policy differs between contact and contactless (e.g. PIN caching).
+    fn deselect(&mut self, interface: Interface);
 
     /// Given parsed APDU for app when selected.
-    /// Write response data back to buf, and return length of payload.  Return APDU Error code on error.
-    fn call(&mut self, interface: Interface, apdu: &Command<C>, reply: &mut Data<R>) -> Result;
+    /// Write response data back to buf, and return the status word of a successful
+    /// response, usually `Success` or else a warning (`62XX`, `63XX`). It is sent after
+    /// the last part of a chained response. Return APDU Error code on error.
+    ///
+    /// `chained` tells whether the APDU was reassembled from a command chain. Its content
+    /// is the same as if it had been sent as a single (extended length) APDU.
+    ///
+    /// `reply` is empty on entry, in `select` as well. It is the dispatcher's buffer,
+    /// reused for every app and call.
+    ///
+    /// Commands in the proprietary class range (CLA `0x80` and up, except the chaining bit)
+    /// are passed on unchecked. Apps that accept them check the CLA themselves; apps
+    /// that don't should decline them in `can_handle`.
+    fn call(&mut self, interface: Interface, chained: bool, apdu: &Command<C>, reply: &mut Data<R>) -> iso7816::Result<Status>;
+
+    /// Whether responses of this app may be chained to the reader via `61XX` and GetResponse.
+    /// Apps that always produce short responses can opt out, in which case a response
+    /// that does not fit in a single reply is answered with an error instead.
+    fn supports_get_response(&self) -> bool {
+        true
+    }
+
+    /// Whether `select` always produces the same response and has no side effects
+    /// when the app is already selected. If so, the dispatcher answers a repeated SELECT
+    /// of the current app from a cache, without calling `select` again. Only responses of
+    /// up to 256 bytes are cached.
+    fn select_is_stable(&self) -> bool {
+        false
+    }
+
+    /// Whether an identical command arriving again right after the previous one (e.g.
+    /// a contactless reader retransmitting because it missed the reply) should be answered
+    /// by replaying the previous reply, instead of calling the app a second time.
+    fn allow_retransmit_dedup(&self) -> bool {
+        false
+    }
+
+    /// Whether the app handles secure messaging itself. If not, the dispatcher rejects
+    /// commands whose CLA indicates secure messaging with `SecureMessagingNotSupported`.
+    fn supports_secure_messaging(&self) -> bool {
+        false
+    }
+
+    /// Whether the app should be deselected after answering a command with `status`,
+    /// e.g. to force a fresh SELECT after a failed PIN verification. Following commands
+    /// are then answered with `NotFound` until an app is selected again.
+    fn deselect_on_error(&self, _status: Status) -> bool {
+        false
+    }
+
+    /// Whether the app has files, selected by identifier or path (SELECT with P1 other
+    /// than `0x04`). If so, these SELECTs are passed to `call`, otherwise the dispatcher
+    /// answers them with `FunctionNotSupported`.
+    fn supports_file_selection(&self) -> bool {
+        false
+    }
+
+    /// Whether `call` handles commands with this header. If not, the dispatcher answers
+    /// `InstructionNotSupportedOrInvalid` itself, without calling the app.
+    fn can_handle(&self, _class: u8, _instruction: u8, _p1: u8, _p2: u8) -> bool {
+        true
+    }
 
 }
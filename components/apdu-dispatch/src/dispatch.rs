@@ -20,12 +20,16 @@ use iso7816::{
     Result,
     Status,
     command::FromSliceError,
+    command::class::{Class, SecureMessaging},
 };
 
 pub use iso7816::Interface;
 
+#[derive(Debug)]
 pub enum RequestType {
     Select(Aid),
+    /// SELECT of a file within the selected app, see `ApduDispatch::apdu_type`.
+    SelectFile,
     GetResponse,
     NewCommand,
     None,
@@ -33,61 +37,420 @@ pub enum RequestType {
 
 use interchange::Responder;
 
-#[derive(PartialEq)]
+/// Which interface `poll` checks first for a new request.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Fairness {
+    /// Always check contactless first (the default).
+    #[default]
+    ContactlessPriority,
+    /// Alternate, checking first the interface that was not serviced last.
+    RoundRobin,
+}
+
+/// Which apps may be selected, to disable some without removing them from the firmware.
+///
+/// An entry matches every app whose AID starts with it, so a RID covers all apps of a vendor.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AidFilter {
+    /// Every app (the default).
+    #[default]
+    AllowAll,
+    /// Only the apps matching an entry. With no entries, no app can be selected.
+    Allow(&'static [&'static [u8]]),
+    /// Every app except those matching an entry.
+    Deny(&'static [&'static [u8]]),
+}
+
+impl AidFilter {
+    pub fn allows(&self, aid: &[u8]) -> bool {
+        let matches = |entries: &[&[u8]]| entries.iter().any(|entry| aid.starts_with(entry));
+        match self {
+            AidFilter::AllowAll => true,
+            AidFilter::Allow(entries) => matches(entries),
+            AidFilter::Deny(entries) => !matches(entries),
+        }
+    }
+}
+
+/// Why the dispatcher answered a request with an error status itself, instead of
+/// passing it to an app. Errors returned by apps are theirs to report.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DispatchError {
+    /// The message is not an APDU.
+    Malformed,
+    /// The CLA byte asks for a logical channel or secure messaging that is not supported.
+    ClassNotSupported(Status),
+    /// A command chain is longer than the buffer. It is dropped, and the command that
+    /// overflowed is answered `NotEnoughMemory`, as are any further commands with the
+    /// chaining bit, without being reported again. The next command without it is
+    /// handled as a fresh command.
+    ChainTooLong,
+    /// A SELECT matches no app, or only apps that are filtered out.
+    UnknownAid,
+    /// A command arrived with no app selected.
+    NoAppSelected,
+    /// The selected app declined the command upfront, see `App::can_handle`.
+    Declined,
+    /// A SELECT by file identifier or path, to an app without files.
+    FileSelectionNotSupported,
+    /// The selected app took longer than the response timeout.
+    TimedOut,
+    /// A GET RESPONSE without a response to continue, or with a different class.
+    UnexpectedGetResponse,
+    /// The response is too long for one reply, and the app does not chain.
+    ResponseTooLong,
+    /// The dispatcher's own state was inconsistent.
+    InvalidState,
+    /// `poll` was passed no apps at all.
+    NoApps,
+}
+
+/// What a call to `ApduDispatch::poll` did.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PollResult {
+    /// The interface with a response waiting, if any.
+    pub responded: Option<Interface>,
+    /// Why the dispatcher answered with an error status, if it did.
+    pub error: Option<DispatchError>,
+}
+
+/// How the AID of a SELECT is compared to the AIDs of the apps.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MatchPolicy {
+    /// An app is selected by its AID, possibly followed by more bytes (the default).
+    #[default]
+    Prefix,
+    /// As `Prefix`, and also by its AID right-truncated to no less than
+    /// `right_truncated_length` (ISO 7816-4 partial DF name).
+    RightTruncated,
+    /// An app is only selected by exactly its AID.
+    Exact,
+}
+
+impl MatchPolicy {
+    pub fn selects(&self, app: &dyn App<CommandSize, ResponseSize>, aid: &[u8]) -> bool {
+        match self {
+            MatchPolicy::Prefix => aid.starts_with(app.aid()),
+            MatchPolicy::RightTruncated => aid.starts_with(app.aid())
+                || (app.aid().starts_with(aid) && aid.len() >= app.right_truncated_length()),
+            MatchPolicy::Exact => aid == app.aid(),
+        }
+    }
+}
+
+/// Observes the raw APDUs received and sent by the dispatcher, e.g. to record them.
+///
+/// Responses include the status word, and chained commands are seen one by one.
+pub trait ApduTap {
+    fn command(&mut self, interface: Interface, apdu: &[u8]);
+    fn response(&mut self, interface: Interface, apdu: &[u8]);
+}
+
+/// Rewrites a raw APDU in place before it is parsed, see `ApduDispatch::set_normalizer`.
+pub type Normalizer = dyn FnMut(&mut heapless::Vec<u8, interchanges::Size>);
+
+/// Apps beyond this number are most likely a mistake: devices have a small, fixed set
+/// of apps. `validate_apps` rejects more; `poll` still serves them.
+pub const MAX_APPS: usize = 16;
+
+/// Two apps whose AIDs are equal, or one a prefix of the other.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AidConflict {
+    pub first: &'static [u8],
+    pub second: &'static [u8],
+}
+
+/// Why `ApduDispatch::validate_apps` rejected a set of apps.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InvalidApps {
+    /// More than `MAX_APPS` apps, this many.
+    TooMany(usize),
+    AidConflict(AidConflict),
+}
+
+impl From<AidConflict> for InvalidApps {
+    fn from(conflict: AidConflict) -> Self {
+        Self::AidConflict(conflict)
+    }
+}
+
+/// Renders an AID as uppercase hex without separators, e.g. `A0000006472F0001`.
+///
+/// This is the canonical textual form, used in logs and suitable for comparisons.
+#[derive(Clone, Copy)]
+pub struct AidDisplay<'a>(pub &'a Aid);
+
+impl core::fmt::Display for AidDisplay<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::fmt::Debug for AidDisplay<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Clone, PartialEq)]
 enum RawApduBuffer {
     None,
-    Request(Command),
-    Response(response::Data),
+    // a command, or the part of a chain received so far, from `interface`
+    Request { command: Command, interface: Interface },
+    // `class` is the CLA of the command answered (without the chaining bit), and
+    // `interface` the one it came from, to match GetResponse against
+    Response { data: response::Data, class: u8, interface: Interface },
+}
+
+// Only print lengths, the buffers may be several kilobytes.
+impl core::fmt::Debug for RawApduBuffer {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RawApduBuffer::None => f.write_str("None"),
+            RawApduBuffer::Request { command, interface } => f.debug_struct("Request")
+                .field("command", &format_args!("{} bytes", command.data().len()))
+                .field("interface", interface)
+                .finish(),
+            RawApduBuffer::Response { data, class, interface } => f.debug_struct("Response")
+                .field("data", &format_args!("{} bytes", data.len()))
+                .field("class", &format_args!("{:02X}", class))
+                .field("interface", interface)
+                .finish(),
+        }
+    }
+}
+
+/// State kept separately for each interface: ISO 7816-4 gives each its own
+/// selection context, so a dual-interface card can be used over both at once.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct PerInterface<T> {
+    contact: T,
+    contactless: T,
+}
+
+impl<T> PerInterface<T> {
+    fn get(&self, interface: Interface) -> &T {
+        match interface {
+            Interface::Contact => &self.contact,
+            Interface::Contactless => &self.contactless,
+        }
+    }
+
+    fn get_mut(&mut self, interface: Interface) -> &mut T {
+        match interface {
+            Interface::Contact => &mut self.contact,
+            Interface::Contactless => &mut self.contactless,
+        }
+    }
+}
+
+/// The dispatcher's view of the exchange in progress: the selected app, the interface,
+/// and a partially received command chain or partially sent response.
+///
+/// Taken with `ApduDispatch::snapshot` and put back with `ApduDispatch::restore`,
+/// e.g. for a fuzzer to explore the chaining and GET RESPONSE states from a given one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateSnapshot {
+    current_aid: PerInterface<Option<Aid>>,
+    current_interface: Interface,
+    raw: RawApduBuffer,
+    was_request_chained: PerInterface<bool>,
+    discarding_chain: PerInterface<Option<Status>>,
+    response_status: Status,
+    secure_messaging_allowed: PerInterface<bool>,
+}
+
+/// What the dispatcher holds on to between APDUs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BufferState {
+    Empty,
+    /// A command chain is being received, with this many data bytes so far.
+    BufferingRequest(usize),
+    /// A response is being sent in parts, with this many bytes still to GET.
+    BufferedResponse(usize),
+}
+
+/// The exchange in progress, as seen by `ApduDispatch::debug_state`. Unlike a
+/// `StateSnapshot`, it borrows from the dispatcher and can be printed.
+#[derive(Clone, Copy, Debug)]
+pub struct DispatchDebug<'a> {
+    /// The app selected on `interface`.
+    pub selected_aid: Option<AidDisplay<'a>>,
+    /// The interface of the last request.
+    pub interface: Interface,
+    pub buffer: BufferState,
+    pub was_request_chained: bool,
+}
+
+/// Assembles a reply for the interchange: response data followed by a status word.
+struct ResponseBuilder {
+    message: interchanges::Data,
+}
+
+impl ResponseBuilder {
+    /// The data must leave room for the status word.
+    fn new(data: &[u8]) -> Self {
+        debug_assert!(data.len() + 2 <= interchanges::SIZE);
+        Self { message: interchanges::Data::try_from_slice(data).unwrap() }
+    }
+
+    fn status(mut self, status: Status) -> interchanges::Data {
+        let sw: [u8; 2] = status.into();
+        self.message.extend_from_slice(&sw).unwrap();
+        self.message
+    }
 }
 
+#[derive(Debug)]
 struct ApduBuffer {
     pub raw: RawApduBuffer,
 }
 
+/// A command chain is longer than the command buffer.
+struct BufferFull;
+
 impl ApduBuffer {
-    fn request(&mut self, command: &iso7816::Command<impl heapless_bytes::ArrayLength<u8>>) {
+    /// Fails if a chained command no longer fits. The buffered part is then incomplete.
+    fn request(&mut self, command: &iso7816::Command<impl heapless_bytes::ArrayLength<u8>>, interface: Interface) -> core::result::Result<(), BufferFull> {
         match &mut self.raw {
-            RawApduBuffer::Request(buffered) => {
-                buffered.extend_from_command(command).ok();
+            RawApduBuffer::Request { command: buffered, interface: from } if *from == interface => {
+                buffered.extend_from_command(command).map_err(|_| BufferFull)
             }
             _ => {
                 if self.raw != RawApduBuffer::None {
                     info!("Was buffering the last response, but aborting that now for this new request.");
                 }
                 let mut new_cmd = iso7816::Command::try_from(&[0,0,0,0]).unwrap();
-                new_cmd.extend_from_command(command).ok();
-                self.raw = RawApduBuffer::Request(new_cmd);
+                let result = new_cmd.extend_from_command(command).map_err(|_| BufferFull);
+                self.raw = RawApduBuffer::Request { command: new_cmd, interface };
+                result
             }
         }
     }
 
 
-    fn response(&mut self, response: &response::Data) {
-        self.raw = RawApduBuffer::Response(response.clone());
+    // The response replaces the command it answers, and keeps the command's class.
+    fn response(&mut self, response: &[u8], interface: Interface) {
+        let class = match &self.raw {
+            RawApduBuffer::Request { command, .. } => command.class().into_inner() & !(1 << 4),
+            // not reached, only buffered commands are answered
+            _ => 0,
+        };
+        self.raw = RawApduBuffer::Response { data: response::Data::try_from_slice(response).unwrap(), class, interface };
     }
 
 }
 
+/// A cached SELECT response, see `App::select_is_stable`. Typical ones ("U2F_V2",
+/// a fixed FCI) are a few bytes, so this does not need a full response buffer.
+type SelectCache = heapless_bytes::Bytes<heapless::consts::U256>;
+
+/// Measures how long an app takes in `select` or `call`, if the `timing` feature is on.
+/// Otherwise it is zero-sized and does nothing.
+struct Stopwatch {
+    #[cfg(feature = "timing")]
+    start: std::time::Instant,
+}
+
+impl Stopwatch {
+    #[inline(always)]
+    fn start() -> Self {
+        Self {
+            #[cfg(feature = "timing")]
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+/// Apps taking longer than this in `select` or `call` are reported (with the `timing` feature).
+#[cfg(feature = "timing")]
+pub const DEFAULT_SLOW_CALL_THRESHOLD: core::time::Duration = core::time::Duration::from_millis(100);
+
 pub struct ApduDispatch {
-    // or currently_selected_aid, or...
-    current_aid: Option<Aid>,
+    // the app selected on each interface
+    current_aid: PerInterface<Option<Aid>>,
     contact: Responder<interchanges::Contact>,
     contactless: Responder<interchanges::Contactless>,
     current_interface: Interface,
 
     buffer: ApduBuffer,
-    was_request_chained: bool,
+    // apps write their replies here, cleared before each `select` and `call`
+    reply: response::Data,
+    was_request_chained: PerInterface<bool>,
+    // a chain did not fit or was interrupted by the other interface, its remaining
+    // chained commands are dropped and answered with this status
+    discarding_chain: PerInterface<Option<Status>>,
+    // status word the app answered with, sent after the last part of the response
+    response_status: Status,
+
+    // last successful SELECT response of the app selected on each interface, if it
+    // declared it stable and the response is short
+    select_cache: PerInterface<Option<(Aid, SelectCache)>>,
+
+    // maximum number of response bytes sent per chunk when chaining
+    negotiated_max_le: usize,
+
+    // fingerprint of the last command and its reply, for apps that opt into deduplication
+    last_command: Option<(u64, interchanges::Data)>,
+    // fingerprint of the command being answered, recorded with the reply
+    pending_fingerprint: Option<u64>,
+
+    fairness: Fairness,
+    last_serviced: Interface,
+    lock_response_chain: bool,
+
+    aid_filter: AidFilter,
+    match_policy: MatchPolicy,
+
+    // whether the current app accepts secure messaging CLA bits
+    secure_messaging_allowed: PerInterface<bool>,
+    // answer a SELECT the app replies to without data with an FCI naming the app
+    select_fci: bool,
+
+    #[cfg(feature = "timing")]
+    slow_call_threshold: core::time::Duration,
+    #[cfg(feature = "timing")]
+    slow_calls: usize,
+    // budget for an app's `call`, and the status replied instead once it is exceeded
+    #[cfg(feature = "timing")]
+    response_timeout: Option<(core::time::Duration, Status)>,
+
+    // apps for `poll_registered`
+    apps: Option<&'static mut [&'static mut dyn App<CommandSize, ResponseSize>]>,
+
+    tap: Option<&'static mut dyn ApduTap>,
+    normalizer: Option<&'static mut Normalizer>,
+
+    // reported by `poll`
+    error: Option<DispatchError>,
+
+    // set during `process_raw`, which takes the reply instead of the interchange
+    capture_reply: bool,
+    captured_reply: Option<interchanges::Data>,
 }
 
 impl ApduDispatch
 {
-    fn apdu_type(apdu: &iso7816::Command<impl heapless_bytes::ArrayLength<u8>>) -> RequestType {
+    /// The P1 of a SELECT gives the selection mode (ISO 7816-4, 11.2.2):
+    /// - `0x04`: by DF name, i.e. AID; the dispatcher selects an app
+    /// - `0x00` to `0x03`: by file identifier (MF/DF/EF, child DF, EF, parent DF)
+    /// - `0x08`, `0x09`: by path, from the MF or from the current DF
+    ///
+    /// Selecting a file is up to the selected app, see `App::supports_file_selection`.
+    fn apdu_type(apdu: &iso7816::Command<impl heapless_bytes::ArrayLength<u8>>) -> Result<RequestType> {
         if apdu.instruction() == Instruction::Select && (apdu.p1 & 0x04) != 0 {
-            RequestType::Select(Aid::try_from_slice(apdu.data()).unwrap())
+            // no app has an AID this long
+            let aid = Aid::try_from_slice(apdu.data()).map_err(|_| Status::NotFound)?;
+            Ok(RequestType::Select(aid))
+        } else if apdu.instruction() == Instruction::Select {
+            Ok(RequestType::SelectFile)
         } else if apdu.instruction() == Instruction::GetResponse {
-            RequestType::GetResponse
+            Ok(RequestType::GetResponse)
         } else {
-            RequestType::NewCommand
+            Ok(RequestType::NewCommand)
         }
     }
 
@@ -96,31 +459,292 @@ impl ApduDispatch
         contactless: Responder<interchanges::Contactless>,
     ) -> ApduDispatch {
         ApduDispatch {
-            current_aid: None,
+            current_aid: PerInterface::default(),
             contact: contact,
             contactless: contactless,
             current_interface: Interface::Contact,
-            was_request_chained: false,
+            was_request_chained: PerInterface::default(),
+            discarding_chain: PerInterface::default(),
+            response_status: Status::Success,
             buffer: ApduBuffer {
                 raw: RawApduBuffer::None,
             },
+            reply: response::Data::new(),
+            select_cache: PerInterface::default(),
+            negotiated_max_le: 256,
+            last_command: None,
+            pending_fingerprint: None,
+            fairness: Fairness::default(),
+            last_serviced: Interface::Contactless,
+            lock_response_chain: false,
+            secure_messaging_allowed: PerInterface::default(),
+            select_fci: false,
+            #[cfg(feature = "timing")]
+            slow_call_threshold: DEFAULT_SLOW_CALL_THRESHOLD,
+            #[cfg(feature = "timing")]
+            slow_calls: 0,
+            #[cfg(feature = "timing")]
+            response_timeout: None,
+            apps: None,
+            tap: None,
+            normalizer: None,
+            aid_filter: AidFilter::default(),
+            match_policy: MatchPolicy::default(),
+            error: None,
+            capture_reply: false,
+            captured_reply: None,
         }
     }
 
+    /// Pass every APDU received and sent to the tap.
+    pub fn set_tap(&mut self, tap: &'static mut dyn ApduTap) {
+        self.tap = Some(tap);
+    }
+
+    /// Rewrite every APDU received before it is parsed, to work around readers known
+    /// to send malformed ones. The tap still sees the APDU as received.
+    pub fn set_normalizer(&mut self, normalizer: &'static mut Normalizer) {
+        self.normalizer = Some(normalizer);
+    }
+
+    /// If set, a SELECT the app answers without data is answered with a minimal FCI
+    /// (`6F` containing the DF name `84`) holding the app's full AID, for hosts
+    /// selecting by a right-truncated AID (see `MatchPolicy::RightTruncated`).
+    pub fn set_select_fci(&mut self, enabled: bool) {
+        self.select_fci = enabled;
+    }
+
+    /// Set which apps may be selected. A SELECT of any other app is answered with
+    /// `NotFound`, as if it was not registered.
+    pub fn set_aid_filter(&mut self, filter: AidFilter) {
+        self.aid_filter = filter;
+    }
+
+    /// Set how much of an app's AID a SELECT must name, see `MatchPolicy`.
+    pub fn set_match_policy(&mut self, policy: MatchPolicy) {
+        self.match_policy = policy;
+    }
+
+    /// Set how requests arriving on both interfaces are prioritized.
+    pub fn set_fairness(&mut self, fairness: Fairness) {
+        self.fairness = fairness;
+    }
+
+    /// Set whether requests from the other interface wait while a chained response
+    /// is being fetched with GetResponse, instead of interrupting the chain.
+    ///
+    /// A reader that never fetches the rest keeps the other interface waiting,
+    /// until it sends a different command or `abort_request` is called.
+    pub fn set_response_chain_lock(&mut self, enabled: bool) {
+        self.lock_response_chain = enabled;
+    }
+
+    /// Set the duration above which an app's `select` or `call` is reported as slow.
+    #[cfg(feature = "timing")]
+    pub fn set_slow_call_threshold(&mut self, threshold: core::time::Duration) {
+        self.slow_call_threshold = threshold;
+    }
+
+    /// Number of `select` and `call` invocations that exceeded the threshold so far.
+    ///
+    /// Apps that need to wait (e.g. for user presence) should reply later from `poll`
+    /// instead of blocking; on-device, a blocking app trips the watchdog.
+    #[cfg(feature = "timing")]
+    pub fn slow_calls(&self) -> usize {
+        self.slow_calls
+    }
+
+    /// Set how long an app's `call` may take. Past this, its reply is discarded, the reader
+    /// gets `status` instead (e.g. `WrongLength` to have it retry), and the app is deselected.
+    ///
+    /// The call is not interrupted, so this only helps a reader that is still listening.
+    #[cfg(feature = "timing")]
+    pub fn set_response_timeout(&mut self, timeout: core::time::Duration, status: Status) {
+        self.response_timeout = Some((timeout, status));
+    }
+
+    #[cfg(feature = "timing")]
+    fn timed_out(&self, stopwatch: &Stopwatch) -> Option<Status> {
+        let (timeout, status) = self.response_timeout?;
+        if stopwatch.start.elapsed() > timeout {
+            Some(status)
+        } else {
+            None
+        }
+    }
+
+    #[cfg(not(feature = "timing"))]
+    fn timed_out(&self, _stopwatch: &Stopwatch) -> Option<Status> {
+        None
+    }
+
+    #[cfg(feature = "timing")]
+    fn check_stopwatch(&mut self, stopwatch: Stopwatch, what: &str, aid: &[u8]) {
+        let elapsed = stopwatch.start.elapsed();
+        if elapsed > self.slow_call_threshold {
+            self.slow_calls += 1;
+            let aid = Aid::try_from_slice(aid).unwrap();
+            warn!("{} of app {} took {} ms", what, AidDisplay(&aid), elapsed.as_millis());
+        }
+    }
+
+    #[cfg(not(feature = "timing"))]
+    #[inline(always)]
+    fn check_stopwatch(&mut self, _stopwatch: Stopwatch, _what: &str, _aid: &[u8]) {}
+
+    // FNV-1a over interface and command, to recognize retransmitted frames
+    fn fingerprint(interface: Interface, command: &Command) -> u64 {
+        let interface = match interface {
+            Interface::Contact => 0u8,
+            Interface::Contactless => 1u8,
+        };
+        let header = [
+            interface,
+            command.class().into_inner(),
+            command.instruction().into(),
+            command.p1,
+            command.p2,
+        ];
+        header.iter().chain(command.data().iter()).fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+    }
+
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            current_aid: self.current_aid.clone(),
+            current_interface: self.current_interface,
+            raw: self.buffer.raw.clone(),
+            was_request_chained: self.was_request_chained,
+            discarding_chain: self.discarding_chain,
+            response_status: self.response_status,
+            secure_messaging_allowed: self.secure_messaging_allowed,
+        }
+    }
+
+    /// For debugging interop issues: the selected app, and whether a command chain or
+    /// a response is buffered at this moment.
+    pub fn debug_state(&self) -> DispatchDebug<'_> {
+        DispatchDebug {
+            selected_aid: self.selected_aid().map(AidDisplay),
+            interface: self.current_interface,
+            buffer: match &self.buffer.raw {
+                RawApduBuffer::None => BufferState::Empty,
+                RawApduBuffer::Request { command, .. } => BufferState::BufferingRequest(command.data().len()),
+                RawApduBuffer::Response { data, .. } => BufferState::BufferedResponse(data.len()),
+            },
+            was_request_chained: *self.was_request_chained.get(self.current_interface),
+        }
+    }
+
+    /// How many GetResponse exchanges the buffered response still needs, in chunks of
+    /// the negotiated maximum Le. 0 if no response is buffered.
+    pub fn pending_response_rounds(&self) -> usize {
+        match &self.buffer.raw {
+            RawApduBuffer::Response { data, .. } if !data.is_empty() =>
+                1 + (data.len() - 1) / self.negotiated_max_le,
+            _ => 0,
+        }
+    }
+
+    /// Return to the state of `snapshot`. The cached SELECT response and the last
+    /// command (for retransmissions) are dropped, not restored.
+    pub fn restore(&mut self, snapshot: &StateSnapshot) {
+        self.select_cache = PerInterface::default();
+        self.last_command = None;
+        self.current_aid = snapshot.current_aid.clone();
+        self.current_interface = snapshot.current_interface;
+        self.buffer.raw = snapshot.raw.clone();
+        self.was_request_chained = snapshot.was_request_chained;
+        self.discarding_chain = snapshot.discarding_chain;
+        self.response_status = snapshot.response_status;
+        self.secure_messaging_allowed = snapshot.secure_messaging_allowed;
+    }
+
+    /// Answers the request being handled with `status`, and forgets the selected app
+    /// without calling its `deselect`. For a runner that caught a panic of an app
+    /// with `catch_unwind`: the request would otherwise stay unanswered, and the
+    /// dispatcher busy. The app's own state may be left inconsistent by the panic.
+    pub fn abort_request(&mut self, status: Status) {
+        self.capture_reply = false;
+        self.pending_fingerprint = None;
+        *self.discarding_chain.get_mut(self.current_interface) = None;
+        self.forget_selected_app();
+        self.reply_error(status);
+    }
+
+    /// Set the maximum response size the reader announced it can accept
+    /// (e.g. the FSD after PPS/ATS negotiation). Chained responses are sent in chunks
+    /// of this size, instead of the default 256 bytes.
+    pub fn set_negotiated_max_le(&mut self, max_le: usize) {
+        // leave room for the status word
+        self.negotiated_max_le = core::cmp::min(max_le, interchanges::SIZE - 2).max(1);
+    }
+
+    /// Check that there are at most `MAX_APPS` apps, and that no two apps have the same
+    /// AID, or an AID that is a prefix of another's. Otherwise, which app is selected
+    /// depends on the order of `apps`.
+    ///
+    /// Meant to be called once at startup. App sets relying on SELECT "next occurrence"
+    /// have overlapping AIDs on purpose, and will not pass.
+    pub fn validate_apps(
+        apps: &[&mut dyn App<CommandSize, ResponseSize>]
+    ) -> core::result::Result<(), InvalidApps> {
+        if apps.len() > MAX_APPS {
+            info!("{} apps, at most {} expected", apps.len(), MAX_APPS);
+            return Err(InvalidApps::TooMany(apps.len()));
+        }
+        for (i, app) in apps.iter().enumerate() {
+            for other in apps[i + 1..].iter() {
+                let (first, second) = (app.aid(), other.aid());
+                if first.starts_with(second) || second.starts_with(first) {
+                    return Err(AidConflict { first, second }.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
     // It would be nice to store `current_app` instead of constantly looking up by AID,
     // but that won't work due to ownership rules
+    //
+    // If `after` is the AID of an app matching `aid`, the search starts after that app
+    // (SELECT "next occurrence"). Apps not passing `filter` are skipped.
+    // Which apps match is up to `policy`.
     fn find_app<'a, 'b>(
         aid: Option<&Aid>,
-        apps: &'a mut [&'b mut dyn App<CommandSize, ResponseSize>]
+        apps: &'a mut [&'b mut dyn App<CommandSize, ResponseSize>],
+        after: Option<&Aid>,
+        filter: &AidFilter,
+        policy: MatchPolicy,
     ) -> Option<&'a mut &'b mut dyn App<CommandSize, ResponseSize>> {
 
         // match aid {
         //     Some(aid) => apps.iter_mut().find(|app| aid.starts_with(app.rid())),
         //     None => None,
         // }
+        aid.and_then(move |aid| {
+            let start = after.and_then(|after|
+                apps.iter().position(|app|
+                    policy.selects(&**app, aid) && **after == *app.aid()
+                )
+            ).map(|position| position + 1).unwrap_or(0);
+
+            apps[start..].iter_mut().find(|app|
+                policy.selects(&***app, aid) && filter.allows(app.aid())
+            )
+        })
+    }
+
+    // The selected app is looked up by its exact AID, as several apps may match
+    // the AID it was selected with.
+    fn find_selected_app<'a, 'b>(
+        aid: Option<&Aid>,
+        apps: &'a mut [&'b mut dyn App<CommandSize, ResponseSize>]
+    ) -> Option<&'a mut &'b mut dyn App<CommandSize, ResponseSize>> {
         aid.and_then(move |aid|
             apps.iter_mut().find(|app|
-                aid.starts_with(app.aid())
+                **aid == *app.aid()
             )
         )
     }
@@ -144,54 +768,136 @@ impl ApduDispatch
 
 
     #[inline(never)]
+    /// Of the CLA byte, the dispatcher handles:
+    /// - the chaining bit (b5), by buffering until the last command of a chain
+    /// - the logical channel (b1-b2, or b1-b4 in the further interindustry range); only the
+    ///   basic channel 0 is supported, anything else is `LogicalChannelNotSupported`
+    /// - the secure messaging indication (b3-b4, or b6 in the further interindustry range),
+    ///   rejected with `SecureMessagingNotSupported` unless the selected app opts in
+    ///
+    /// Proprietary classes are passed through unchecked.
+    fn check_class(&self, class: Class) -> Result<()> {
+        if let Some(channel) = class.channel() {
+            if channel != 0 {
+                info!("Logical channel {} not supported.", channel);
+                return Err(Status::LogicalChannelNotSupported);
+            }
+        }
+
+        match class.secure_messaging() {
+            SecureMessaging::None | SecureMessaging::Unknown => Ok(()),
+            _ if *self.secure_messaging_allowed.get(self.current_interface) => Ok(()),
+            _ => {
+                info!("Secure messaging not supported.");
+                Err(Status::SecureMessagingNotSupported)
+            }
+        }
+    }
+
     fn buffer_chained_apdu_if_needed(&mut self, command: iso7816::Command<impl heapless_bytes::ArrayLength<u8>>, inferface: Interface) -> RequestType {
 
         self.current_interface = inferface;
+        // A command from one interface interrupts a chain being received on the other,
+        // which is dropped: the reader may have gone away mid-chain, e.g. a card pulled
+        // from the field, and must not keep the other interface waiting.
+        if let RawApduBuffer::Request { interface, .. } = self.buffer.raw {
+            if interface != inferface {
+                info!("Dropping a chain on the other interface.");
+                *self.discarding_chain.get_mut(interface) = Some(Status::ConditionsOfUseNotSatisfied);
+                self.buffer.raw = RawApduBuffer::None;
+            }
+        }
+        if let Err(status) = self.check_class(command.class()) {
+            self.error = Some(DispatchError::ClassNotSupported(status));
+            self.reply_error(status);
+            return RequestType::None;
+        }
+        // The rest of a chain that did not fit or was interrupted is dropped, as it would
+        // otherwise start a new chain of its own. Its parts only get a status, the overflow
+        // was already reported. Any command without the chaining bit is a fresh command.
+        if let Some(status) = *self.discarding_chain.get(inferface) {
+            if command.class().chain().not_the_last() {
+                info!("Dropping the rest of a chain.");
+                self.reply_error(status);
+                return RequestType::None;
+            }
+            *self.discarding_chain.get_mut(inferface) = None;
+        }
         // iso 7816-4 5.1.1
         // check Apdu level chaining and buffer if necessary.
         if !command.class().chain().not_the_last() {
 
             let is_chaining = match &self.buffer.raw {
-                RawApduBuffer::Request(_) => true,
+                RawApduBuffer::Request { .. } => true,
                 _ => false,
             };
 
             if is_chaining {
-                self.buffer.request(&command);
-
+                if self.buffer.request(&command, inferface).is_err() {
+                    info!("Chained command does not fit.");
+                    self.error = Some(DispatchError::ChainTooLong);
+                    self.reply_error(Status::NotEnoughMemory);
+                    return RequestType::None;
+                }
                 // Response now needs to be chained.
-                self.was_request_chained = true;
+                *self.was_request_chained.get_mut(self.current_interface) = true;
                 info!("combined chained commands.");
 
                 RequestType::NewCommand
             } else {
-                if self.buffer.raw == RawApduBuffer::None {
-                    self.was_request_chained = false;
-                }
-                let apdu_type = Self::apdu_type(&command);
-                match Self::apdu_type(&command) {
-                    // Keep buffer the same in case of GetResponse
-                    RequestType::GetResponse => (),
+                let apdu_type = match Self::apdu_type(&command) {
+                    Ok(apdu_type) => apdu_type,
+                    Err(status) => {
+                        info!("Invalid request: {:?}", status);
+                        // the only invalid request is a SELECT of an overlong AID
+                        self.error = Some(DispatchError::UnknownAid);
+                        self.reply_error(status);
+                        return RequestType::None;
+                    }
+                };
+                match apdu_type {
+                    // Keep buffer the same in case of GetResponse,
+                    // unless it is not for the command that produced the response
+                    RequestType::GetResponse => {
+                        // the chaining bit is the only one allowed to differ
+                        let class = command.class().into_inner() & !(1 << 4);
+                        if let RawApduBuffer::Response { class: expected, interface, .. } = self.buffer.raw {
+                            // the response stays for the interface it belongs to
+                            if interface != self.current_interface {
+                                info!("GetResponse for a response on the other interface");
+                                self.error = Some(DispatchError::UnexpectedGetResponse);
+                                self.respond(&Status::ConditionsOfUseNotSatisfied.into()).ok();
+                                return RequestType::None;
+                            }
+                            if class != expected {
+                                info!("GetResponse class {:02X} does not match {:02X}", class, expected);
+                                self.error = Some(DispatchError::UnexpectedGetResponse);
+                                self.reply_error(Status::UnspecifiedCheckingError);
+                                return RequestType::None;
+                            }
+                        }
+                    }
                     // Overwrite for everything else.
-                    _ => self.buffer.request(&command),
+                    _ => {
+                        *self.was_request_chained.get_mut(self.current_interface) = false;
+                        // a single command always fits
+                        self.buffer.request(&command, inferface).ok();
+                    }
                 }
                 apdu_type
             }
         } else {
-            match inferface {
-                // acknowledge
-                Interface::Contact => {
-                    self.contact.respond(&Status::Success.try_into().unwrap())
-                        .expect("Could not respond");
-                }
-                Interface::Contactless => {
-                    self.contactless.respond(&Status::Success.try_into().unwrap())
-                        .expect("Could not respond");
-                }
+            info!("chaining {} bytes", command.data().len());
+            if self.buffer.request(&command, inferface).is_err() {
+                info!("Chained command does not fit.");
+                *self.discarding_chain.get_mut(inferface) = Some(Status::NotEnoughMemory);
+                self.error = Some(DispatchError::ChainTooLong);
+                self.reply_error(Status::NotEnoughMemory);
+                return RequestType::None;
             }
 
-            info!("chaining {} bytes", command.data().len());
-            self.buffer.request(&command);
+            // acknowledge
+            self.respond_to(inferface, &Status::Success.try_into().unwrap()).ok();
 
             // Nothing for the application to consume yet.
             RequestType::None
@@ -224,81 +930,141 @@ impl ApduDispatch
     fn check_for_request(&mut self) -> RequestType {
         if !self.busy() {
 
-            // Check to see if we have gotten a message, giving priority to contactless.
-            let (message, interface) = if let Some(message) = self.contactless.take_request() {
+            // Only the interface fetching a chained response is served until it is done.
+            if self.response_chain_locked() {
+                let message = match self.current_interface {
+                    Interface::Contact => self.contact.take_request(),
+                    Interface::Contactless => self.contactless.take_request(),
+                };
+                return match message {
+                    Some(message) => {
+                        let interface = self.current_interface;
+                        self.last_serviced = interface;
+                        self.handle_message(&message, interface)
+                    }
+                    None => RequestType::None,
+                };
+            }
+
+            // Check to see if we have gotten a message, by default giving priority to contactless.
+            let contact_first = match self.fairness {
+                Fairness::ContactlessPriority => false,
+                Fairness::RoundRobin => self.last_serviced == Interface::Contactless,
+            };
+            let (message, interface) = if contact_first {
+                if let Some(message) = self.contact.take_request() {
+                    (message, Interface::Contact)
+                } else if let Some(message) = self.contactless.take_request() {
+                    (message, Interface::Contactless)
+                } else {
+                    return RequestType::None;
+                }
+            } else if let Some(message) = self.contactless.take_request() {
                 (message, Interface::Contactless)
             } else if let Some(message) = self.contact.take_request() {
                 (message, Interface::Contact)
             } else {
                 return RequestType::None;
             };
-
-            // Parse the message as an APDU.
-            match Self::parse_apdu::<interchanges::Size>(&message) {
-                Ok(command) => {
-                    // The Apdu may be standalone or part of a chain.
-                    self.buffer_chained_apdu_if_needed(command, interface)
-                },
-                Err(response) => {
-                    // If not a valid APDU, return error and don't pass to app.
-                    info!("Invalid apdu");
-                    match interface {
-                        Interface::Contactless =>
-                            self.contactless.respond(&response.into()).expect("cant respond"),
-                        Interface::Contact =>
-                            self.contact.respond(&response.into()).expect("cant respond"),
-                    }
-                    RequestType::None
-                }
-            }
+            self.last_serviced = interface;
+            self.handle_message(&message, interface)
 
         } else {
             RequestType::None
         }
     }
 
+    fn response_chain_locked(&self) -> bool {
+        self.lock_response_chain && match &self.buffer.raw {
+            RawApduBuffer::Response { data, .. } => !data.is_empty(),
+            _ => false,
+        }
+    }
+
+    fn handle_message(&mut self, message: &interchanges::Data, interface: Interface) -> RequestType {
+        if let Some(tap) = self.tap.as_mut() {
+            tap.command(interface, message);
+        }
+
+        let normalized;
+        let message = match self.normalizer.as_mut() {
+            Some(normalize) => {
+                let mut copy = message.clone();
+                normalize(&mut copy);
+                normalized = copy;
+                &normalized
+            }
+            None => message,
+        };
+
+        // Parse the message as an APDU.
+        match Self::parse_apdu::<interchanges::Size>(message) {
+            Ok(command) => {
+                // The Apdu may be standalone or part of a chain.
+                self.buffer_chained_apdu_if_needed(command, interface)
+            },
+            Err(response) => {
+                // If not a valid APDU, return error and don't pass to app.
+                info!("Invalid apdu");
+                self.error = Some(DispatchError::Malformed);
+                self.respond_to(interface, &response.into()).ok();
+                RequestType::None
+            }
+        }
+    }
+
     #[inline(never)]
     fn reply_error (&mut self, status: Status) {
-        self.respond(&status.into());
+        self.respond(&status.into()).ok();
         self.buffer.raw = RawApduBuffer::None;
     }
 
     #[inline(never)]
-    fn handle_reply(&mut self,) {
+    fn handle_reply(&mut self, chaining_allowed: bool) {
         // Consider if we need to reply via chaining method.
         // If the reader is using chaining, we will simply
         // reply 61XX, and put the response in a buffer.
         // It is up to the reader to then send GetResponse
-        // requests, to which we will return up to 256 bytes at a time.
+        // requests, to which we will return up to `negotiated_max_le` bytes at a time.
         let (new_state, response) = match &mut self.buffer.raw {
-            RawApduBuffer::Request(_) | RawApduBuffer::None => {
+            RawApduBuffer::Request { .. } | RawApduBuffer::None => {
                 info!("Unexpected GetResponse request.");
+                self.error = Some(DispatchError::UnexpectedGetResponse);
                 (
                     RawApduBuffer::None,
-                    Status::UnspecifiedCheckingError.into()
+                    Status::ConditionsOfUseNotSatisfied.into()
                 )
             }
-            RawApduBuffer::Response(res) => {
+            RawApduBuffer::Response { data: res, .. } if !chaining_allowed => {
+                // The app does not chain, so the response has to fit in one reply.
+                if res.len() + 2 <= interchanges::SIZE {
+                    (RawApduBuffer::None, ResponseBuilder::new(res).status(self.response_status))
+                } else {
+                    info!("Response of {} bytes does not fit and app does not chain.", res.len());
+                    self.error = Some(DispatchError::ResponseTooLong);
+                    (RawApduBuffer::None, Status::WrongLength.into())
+                }
+            }
+            RawApduBuffer::Response { data: res, class, interface } => {
 
-                if self.was_request_chained || res.len() > interchanges::SIZE {
+                if *self.was_request_chained.get(self.current_interface) || res.len() + 2 > interchanges::SIZE {
 
-                    // Send 256 bytes max at a time.
-                    let boundary = core::cmp::min(256, res.len());
+                    // Send `negotiated_max_le` (by default 256) bytes max at a time.
+                    let boundary = core::cmp::min(self.negotiated_max_le, res.len());
 
                     let to_send = &res[..boundary];
                     let remaining = &res[boundary..];
-                    let mut message = interchanges::Data::try_from_slice(to_send).unwrap();
-                    let return_code = if remaining.len() > 255 {
+                    let status = if remaining.len() > 255 {
                         // XX = 00 indicates more than 255 bytes of data
-                        0x6100u16
+                        Status::MoreAvailable(0)
                     } else if remaining.len() > 0 {
-                        0x6100u16 + (remaining.len() as u16)
+                        Status::MoreAvailable(remaining.len() as u8)
                     } else {
-                        // Last chunk has success code
-                        0x9000
+                        // Last chunk has the app's status
+                        self.response_status
                     };
-                    message.extend_from_slice(&return_code.to_be_bytes()).ok();
-                    if return_code == 0x9000 {
+                    let message = ResponseBuilder::new(to_send).status(status);
+                    if remaining.is_empty() {
                         (
                             RawApduBuffer::None,
                             message
@@ -306,32 +1072,33 @@ impl ApduDispatch
                     } else {
                         info!("Still {} bytes in response buffer", remaining.len());
                         (
-                            RawApduBuffer::Response(response::Data::try_from_slice(remaining).unwrap()),
+                            RawApduBuffer::Response { data: response::Data::try_from_slice(remaining).unwrap(), class: *class, interface: *interface },
                             message
                         )
                     }
 
                 } else {
-                    // Add success code
-                    res.extend_from_slice(&[0x90,00]).ok();
-                    (RawApduBuffer::None, interchanges::Data::try_from_slice(&res.as_slice()).unwrap())
+                    // Add the app's status
+                    (RawApduBuffer::None, ResponseBuilder::new(res).status(self.response_status))
                 }
 
             }
         };
         self.buffer.raw = new_state;
-        self.respond(&response);
+        self.respond(&response).ok();
 
     }
 
     #[inline(never)]
-    fn handle_app_response(&mut self, response: &Result<()>, data: &response::Data) {
+    // The app's reply is in `self.reply`.
+    fn handle_app_response(&mut self, response: &Result<Status>, chaining_allowed: bool) {
         // put message into the response buffer
         match response {
-            Ok(()) => {
-                info!("buffered the response of {} bytes.", data.len());
-                self.buffer.response(data);
-                self.handle_reply();
+            Ok(status) => {
+                info!("buffered the response of {} bytes.", self.reply.len());
+                self.response_status = *status;
+                self.buffer.response(&self.reply, self.current_interface);
+                self.handle_reply(chaining_allowed);
             }
             Err(status) => {
                 // Just reply the error immediately.
@@ -342,7 +1109,7 @@ impl ApduDispatch
     }
 
     #[inline(never)]
-    fn handle_app_select<'a>(&mut self, apps: &'a mut [&'a mut dyn App<CommandSize, ResponseSize>], aid: Aid) {
+    fn handle_app_select(&mut self, apps: &mut [&mut dyn App<CommandSize, ResponseSize>], aid: Aid) {
         // three cases:
         // - currently selected app has different AID -> deselect it, to give it
         //   the chance to clear sensitive state
@@ -353,35 +1120,88 @@ impl ApduDispatch
         // For PIV, "SELECT" is NOP if it was already selected, but this is
         // not necessarily the case for other apps
 
+        // ISO 7816-4, P2 = xxxxxx10: select the next app matching the AID
+        let next_occurrence = match &self.buffer.raw {
+            RawApduBuffer::Request { command: apdu, .. } => (apdu.p2 & 0x03) == 0x02,
+            _ => {
+                info!("Unexpected buffer state.");
+                self.error = Some(DispatchError::InvalidState);
+                self.reply_error(Status::UnspecifiedCheckingError);
+                return;
+            }
+        };
+        let after = if next_occurrence { self.selected_aid().cloned() } else { None };
+        let app_aid = Self::find_app(Some(&aid), apps, after.as_ref(), &self.aid_filter, self.match_policy)
+            .map(|app| Aid::try_from_slice(app.aid()).unwrap());
+
         // if there is a selected app with a different AID, deselect it
-        if let Some(current_aid) = self.current_aid.as_ref() {
-            if *current_aid != *aid {
-                let app = Self::find_app(self.current_aid.as_ref(), apps).unwrap();
-                // for now all apps will be happy with this.
-                app.deselect();
-                self.current_aid = None;
+        if let Some(current_aid) = self.selected_aid() {
+            if Some(current_aid) != app_aid.as_ref() {
+                // the app may be missing if `poll` was passed different apps in the meantime
+                if let Some(app) = Self::find_selected_app(self.selected_aid(), apps) {
+                    if !self.selected_elsewhere() {
+                        app.deselect(self.current_interface);
+                    }
+                }
+                self.forget_selected_app();
             }
         }
 
         // select specified app in any case
-        if let Some(app) = Self::find_app(Some(&aid), apps) {
-            info!("Selected app");
-            let mut response = response::Data::new();
+        if let Some(app) = Self::find_selected_app(app_aid.as_ref(), apps) {
+            let app_aid = app_aid.unwrap();
+            info!("Selected app {}", AidDisplay(&app_aid));
+            let chaining_allowed = app.supports_get_response();
+            let stable = app.select_is_stable();
+
+            // re-SELECT of the current app with a stable response: skip the app
+            if stable && self.selected_aid() == Some(&app_aid) {
+                if let Some((cached_aid, cached)) = self.select_cache.get_mut(self.current_interface).take() {
+                    if cached_aid == app_aid {
+                        info!("Using cached select response");
+                        self.response_status = Status::Success;
+                        self.buffer.response(&cached, self.current_interface);
+                        self.handle_reply(chaining_allowed);
+                        *self.select_cache.get_mut(self.current_interface) = Some((cached_aid, cached));
+                        return;
+                    }
+                }
+            }
+
+            self.reply.clear();
+            let stopwatch = Stopwatch::start();
             let result = match &self.buffer.raw {
-                RawApduBuffer::Request(apdu) => {
-                    app.select(apdu, &mut response)
+                RawApduBuffer::Request { command: apdu, .. } => {
+                    app.select(apdu, &mut self.reply)
+                }
+                _ => {
+                    info!("Unexpected buffer state.");
+                    self.error = Some(DispatchError::InvalidState);
+                    Err(Status::UnspecifiedCheckingError)
                 }
-                _ => panic!("Unexpected buffer state."),
             };
+            self.check_stopwatch(stopwatch, "select", app.aid());
+            if result.is_ok() && self.reply.is_empty() && self.select_fci {
+                // AIDs are at most 16 bytes, so both lengths fit in one byte
+                self.reply.extend_from_slice(&[0x6F, app_aid.len() as u8 + 2, 0x84, app_aid.len() as u8]).unwrap();
+                self.reply.extend_from_slice(&app_aid).unwrap();
+            }
             if result.is_ok() {
-                self.current_aid = Some(aid);
+                // longer responses are not worth the memory, they are produced again
+                *self.select_cache.get_mut(self.current_interface) = match SelectCache::try_from_slice(&self.reply) {
+                    Ok(cached) if stable => Some((app_aid.clone(), cached)),
+                    _ => None,
+                };
+                *self.current_aid.get_mut(self.current_interface) = Some(app_aid);
+                *self.secure_messaging_allowed.get_mut(self.current_interface) = app.supports_secure_messaging();
             }
 
-            self.handle_app_response(&result, &response);
+            self.handle_app_response(&result.map(|()| Status::Success), chaining_allowed);
 
 
         } else {
-            info!("could not find app by aid: {}", hex_str!(&aid));
+            info!("could not find app by aid: {}", AidDisplay(&aid));
+            self.error = Some(DispatchError::UnknownAid);
             self.reply_error(Status::NotFound);
         };
 
@@ -389,47 +1209,222 @@ impl ApduDispatch
 
 
     #[inline(never)]
-    fn handle_app_command<'a>(&mut self, apps: &'a mut [&'a mut dyn App<CommandSize, ResponseSize>]) {
+    fn handle_app_command(&mut self, apps: &mut [&mut dyn App<CommandSize, ResponseSize>]) {
         // if there is a selected app, send it the command
-        let mut response = response::Data::new();
-        if let Some(app) = Self::find_app(self.current_aid.as_ref(), apps) {
+        if let Some(app) = Self::find_selected_app(self.selected_aid(), apps) {
+            let fingerprint = match &self.buffer.raw {
+                RawApduBuffer::Request { command: apdu, .. } if app.allow_retransmit_dedup() => {
+                    Some(Self::fingerprint(self.current_interface, apdu))
+                }
+                _ => None,
+            };
+
+            // a retransmission of the last command: replay the reply instead of calling the app
+            if let Some((last_fingerprint, reply)) = self.last_command.take() {
+                if Some(last_fingerprint) == fingerprint {
+                    info!("Replaying reply to retransmitted command");
+                    self.buffer.raw = RawApduBuffer::None;
+                    self.respond(&reply).ok();
+                    self.last_command = Some((last_fingerprint, reply));
+                    return;
+                }
+            }
+
+            if let RawApduBuffer::Request { command: apdu, .. } = &self.buffer.raw {
+                if !app.can_handle(apdu.class().into_inner(), apdu.instruction().into(), apdu.p1, apdu.p2) {
+                    info!("App declined the command");
+                    self.error = Some(DispatchError::Declined);
+                    self.reply_error(Status::InstructionNotSupportedOrInvalid);
+                    return;
+                }
+            }
+
+            self.reply.clear();
+            let stopwatch = Stopwatch::start();
             let result = match &self.buffer.raw {
-                RawApduBuffer::Request(apdu) => {
+                RawApduBuffer::Request { command: apdu, .. } => {
                     // TODO this isn't very clear
-                    app.call(self.current_interface, apdu, &mut response)
+                    app.call(self.current_interface, *self.was_request_chained.get(self.current_interface), apdu, &mut self.reply)
+                }
+                _ => {
+                    info!("Unexpected buffer state.");
+                    self.error = Some(DispatchError::InvalidState);
+                    Err(Status::UnspecifiedCheckingError)
                 }
-                _ => panic!("Unexpected buffer state."),
             };
-            self.handle_app_response(&result, &response);
+            let timed_out = self.timed_out(&stopwatch);
+            self.check_stopwatch(stopwatch, "call", app.aid());
+            if let Some(status) = timed_out {
+                info!("App stalled, deselecting it");
+                self.error = Some(DispatchError::TimedOut);
+                self.reply_error(status);
+                if !self.selected_elsewhere() {
+                    app.deselect(self.current_interface);
+                }
+                self.forget_selected_app();
+                return;
+            }
+            let chaining_allowed = app.supports_get_response();
+            self.pending_fingerprint = fingerprint;
+            self.handle_app_response(&result, chaining_allowed);
+            self.pending_fingerprint = None;
+
+            if let Err(status) = result {
+                if app.deselect_on_error(status) {
+                    info!("Deselecting app after error");
+                    if !self.selected_elsewhere() {
+                        app.deselect(self.current_interface);
+                    }
+                    self.forget_selected_app();
+                    return;
+                }
+            }
+
+            // only complete replies can be replayed
+            if self.buffer.raw != RawApduBuffer::None {
+                self.last_command = None;
+            }
 
         } else {
             // TODO: correct error?
+            self.error = Some(DispatchError::NoAppSelected);
             self.reply_error(Status::NotFound);
         };
     }
 
-    pub fn poll<'a>(
+    // the app selected on the interface of the request being handled
+    fn selected_aid(&self) -> Option<&Aid> {
+        self.current_aid.get(self.current_interface).as_ref()
+    }
+
+    // whether the current app is selected on the other interface too, and must stay so
+    fn selected_elsewhere(&self) -> bool {
+        let other = match self.current_interface {
+            Interface::Contact => Interface::Contactless,
+            Interface::Contactless => Interface::Contact,
+        };
+        self.selected_aid().is_some() && self.current_aid.get(other).as_ref() == self.selected_aid()
+    }
+
+    // after the current interface's app was deselected
+    fn forget_selected_app(&mut self) {
+        *self.current_aid.get_mut(self.current_interface) = None;
+        *self.select_cache.get_mut(self.current_interface) = None;
+        *self.secure_messaging_allowed.get_mut(self.current_interface) = false;
+        self.last_command = None;
+    }
+
+    /// Registers the apps once, for use with `poll_registered`.
+    pub fn register_apps(&mut self, apps: &'static mut [&'static mut dyn App<CommandSize, ResponseSize>]) {
+        self.apps = Some(apps);
+    }
+
+    /// Like `poll`, with the apps passed to `register_apps`.
+    /// Without registered apps, every SELECT and command is answered with `NotFound`.
+    pub fn poll_registered(&mut self) -> PollResult {
+        match self.apps.take() {
+            Some(apps) => {
+                let result = self.poll(apps);
+                self.apps = Some(apps);
+                result
+            }
+            None => self.poll(&mut []),
+        }
+    }
+
+    /// Handles at most one request. Error statuses are sent as before, `PollResult::error`
+    /// additionally says why, e.g. to count malformed APDUs.
+    pub fn poll(
         &mut self,
-        apps: &'a mut [&'a mut dyn App<CommandSize, ResponseSize>],
-    ) -> Option<Interface> {
+        apps: &mut [&mut dyn App<CommandSize, ResponseSize>],
+    ) -> PollResult {
 
         // Only take on one transaction at a time.
+        self.error = None;
         let request_type = self.check_for_request();
+        self.handle_request(apps, request_type);
 
+        // slight priority to contactless.
+        let responded = if self.contactless.state() == interchange::State::Responded {
+            Some(Interface::Contactless)
+        } else if self.contact.state() == interchange::State::Responded {
+            Some(Interface::Contact)
+        } else {
+            None
+        };
+        PollResult { responded, error: self.error.take() }
+    }
+
+    /// Runs a single APDU through the same steps as `poll`, but takes it from `message`
+    /// instead of the interchanges, and returns the reply instead of sending it.
+    ///
+    /// This is meant for tests and diagnostics (e.g. a debug console), not for the
+    /// transport loop. Returns `None` while a request from an interchange is in progress,
+    /// or if the message does not fit in an interchange.
+    pub fn process_raw(
+        &mut self,
+        interface: Interface,
+        message: &[u8],
+        apps: &mut [&mut dyn App<CommandSize, ResponseSize>],
+    ) -> Option<interchanges::Data> {
+        if self.busy() {
+            return None;
+        }
+        let message = interchanges::Data::try_from_slice(message).ok()?;
+
+        self.capture_reply = true;
+        let request_type = self.handle_message(&message, interface);
+        self.handle_request(apps, request_type);
+        self.capture_reply = false;
+        self.error = None;
+
+        self.captured_reply.take()
+    }
+
+    fn handle_request(&mut self, apps: &mut [&mut dyn App<CommandSize, ResponseSize>], request_type: RequestType) {
         // if there is a new request:
         // - if it's a select, handle appropriately
         // - else pass it on to currently selected app
         // if there is no new request, poll currently selected app
+        if apps.is_empty() {
+            // e.g. `poll_registered` before `register_apps`: answer without looking at
+            // the selection or buffered response of apps passed earlier
+            if let RequestType::None = request_type {
+                return;
+            }
+            info!("No apps to dispatch to");
+            self.forget_selected_app();
+            self.error = Some(DispatchError::NoApps);
+            self.reply_error(Status::NotFound);
+            return;
+        }
+
         match request_type {
             // SELECT case
             RequestType::Select(aid) => {
                 info!("Select");
+                self.last_command = None;
                 self.handle_app_select(apps,aid);
             }
 
+            RequestType::SelectFile => {
+                info!("Select file");
+                let supported = Self::find_selected_app(self.selected_aid(), apps)
+                    .map(|app| app.supports_file_selection());
+                if supported == Some(false) {
+                    self.error = Some(DispatchError::FileSelectionNotSupported);
+                    self.reply_error(Status::FunctionNotSupported);
+                } else {
+                    // without a selected app, this is answered `NotFound`
+                    self.handle_app_command(apps);
+                }
+            }
+
             RequestType::GetResponse => {
                 info!("GetResponse");
-                self.handle_reply();
+                self.last_command = None;
+                // a buffered response only exists if its app allowed chaining
+                self.handle_reply(true);
             }
 
             // command that is not a special command -- goes to app.
@@ -441,25 +1436,43 @@ impl ApduDispatch
             RequestType::None => {
             }
         }
-
-        // slight priority to contactless.
-        if self.contactless.state() == interchange::State::Responded {
-            Some(Interface::Contactless)
-        } else if self.contact.state() == interchange::State::Responded {
-            Some(Interface::Contact)
-        } else {
-            None
-        }
     }
 
     #[inline(never)]
-    fn respond(&mut self, message: &interchanges::Data){
+    fn respond(&mut self, message: &interchanges::Data) -> core::result::Result<(), ()> {
         debug!("<<< {}", hex_str!(message.as_slice(), sep:""));
-        match self.current_interface {
-            Interface::Contactless =>
-                self.contactless.respond(&message).expect("cant respond"),
-            Interface::Contact =>
-                self.contact.respond(&message).expect("cant respond"),
+        let fingerprint = self.pending_fingerprint.take();
+        self.respond_to(self.current_interface, message)?;
+        if let Some(fingerprint) = fingerprint {
+            self.last_command = Some((fingerprint, message.clone()));
+        }
+        Ok(())
+    }
+
+    /// Fails if the interchange does not expect a response, e.g. because the reader canceled
+    /// the request in the meantime. The response is then dropped, and a cancelation is
+    /// acknowledged so the interface can take new requests.
+    fn respond_to(&mut self, interface: Interface, message: &interchanges::Data) -> core::result::Result<(), ()> {
+        let result = if self.capture_reply {
+            self.captured_reply = Some(message.clone());
+            Ok(())
+        } else {
+            match interface {
+                Interface::Contactless => self.contactless.respond(message),
+                Interface::Contact => self.contact.respond(message),
+            }
+        };
+        if result.is_ok() {
+            if let Some(tap) = self.tap.as_mut() {
+                tap.response(interface, message);
+            }
+        } else {
+            info!("Could not respond, dropping {} bytes", message.len());
+            match interface {
+                Interface::Contactless => self.contactless.acknowledge_cancel().ok(),
+                Interface::Contact => self.contact.acknowledge_cancel().ok(),
+            };
         }
+        result
     }
 }
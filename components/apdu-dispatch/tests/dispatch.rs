@@ -17,6 +17,8 @@ use interchange::Interchange;
 
 use heapless_bytes::Bytes;
 
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 #[macro_use]
 extern crate serial_test;
 
@@ -69,10 +71,10 @@ impl App for TestApp1 {
         Ok(Default::default())
     }
 
-    fn deselect(&mut self) {
+    fn deselect(&mut self, _: dispatch::Interface) {
     }
 
-    fn call (&mut self, _: dispatch::Interface, apdu: &Command, reply: &mut response::Data) -> AppResult {
+    fn call (&mut self, _: dispatch::Interface, chained: bool, apdu: &Command, reply: &mut response::Data) -> iso7816::Result<Status> {
         println!("TestApp1::call");
         match apdu.instruction().into() {
             0x10 => {
@@ -83,14 +85,29 @@ impl App for TestApp1 {
                 reply.push(0).unwrap();
                 reply.push(0).unwrap();
                 reply.extend_from_slice(apdu.data()).unwrap();
-                Ok(())
+                Ok(Status::Success)
+            }
+            // Report whether the command was reassembled from a chain
+            0x16 => {
+                reply.push(chained as u8).unwrap();
+                Ok(Status::Success)
+            }
+            // Report whether the command had a body, even without data
+            0x18 => {
+                reply.push(apdu.has_body() as u8).unwrap();
+                Ok(Status::Success)
+            }
+            // Block, instead of deferring the reply to `poll`
+            0x17 => {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                Ok(Status::Success)
             }
             // For measuring the stack burden of dispatch
             0x15 => {
                 let mut buf = Bytes::new();
                 let addr = (&buf as *const response::Data ) as u32;
                 reply.extend_from_slice(&addr.to_be_bytes()).unwrap();
-                Ok(())
+                Ok(Status::Success)
             }
             _ => 
                 Err(Status::InstructionNotSupportedOrInvalid)
@@ -118,10 +135,10 @@ impl App for TestApp2 {
         Ok(Default::default())
     }
 
-    fn deselect(&mut self) {
+    fn deselect(&mut self, _: dispatch::Interface) {
     }
 
-    fn call (&mut self, _: dispatch::Interface, apdu: &Command, reply: &mut response::Data) -> AppResult {
+    fn call (&mut self, _: dispatch::Interface, _: bool, apdu: &Command, reply: &mut response::Data) -> iso7816::Result<Status> {
         println!("TestApp2::call");
         match apdu.instruction().into() {
             0x20 => {
@@ -131,7 +148,7 @@ impl App for TestApp2 {
                 reply.push(0).unwrap();
                 reply.push(0).unwrap();
                 reply.extend_from_slice(apdu.data()).unwrap();
-                Ok(())
+                Ok(Status::Success)
             },
             0x30 => {
                 // Return 2KB bytes of byte-truncated fibonacci
@@ -142,13 +159,415 @@ impl App for TestApp2 {
                         next
                     ).unwrap();
                 }
-                Ok(())
+                Ok(Status::Success)
+            }
+            _ =>
+                Err(Status::InstructionNotSupportedOrInvalid)
+        }
+    }
+
+}
+
+pub struct TestApp3 {}
+
+impl Aid for TestApp3 {
+    fn aid(&self) -> &'static [u8] {
+        &[ 0x0Au8, 1, 0, 0, 4]
+    }
+
+    fn right_truncated_length(&self) -> usize {
+        5
+    }
+}
+
+// This app never chains its responses
+impl App for TestApp3 {
+
+    fn select(&mut self, _apdu: &Command, _reply: &mut response::Data) -> AppResult {
+        Ok(Default::default())
+    }
+
+    fn deselect(&mut self, _: dispatch::Interface) {
+    }
+
+    fn call (&mut self, _: dispatch::Interface, _: bool, apdu: &Command, reply: &mut response::Data) -> iso7816::Result<Status> {
+        println!("TestApp3::call");
+        match apdu.instruction().into() {
+            0x10 => {
+                reply.extend_from_slice(apdu.data()).unwrap();
+                Ok(Status::Success)
+            }
+            0x30 => {
+                // Return more than fits in a single reply
+                for i in 0..interchanges::SIZE {
+                    reply.push(i as u8).unwrap();
+                }
+                Ok(Status::Success)
+            }
+            _ =>
+                Err(Status::InstructionNotSupportedOrInvalid)
+        }
+    }
+
+    fn supports_get_response(&self) -> bool {
+        false
+    }
+
+}
+
+static STABLE_APP_SELECTS: AtomicUsize = AtomicUsize::new(0);
+
+// `long` makes the SELECT response too long to be cached
+pub struct StableApp {
+    long: bool,
+}
+
+impl Aid for StableApp {
+    fn aid(&self) -> &'static [u8] {
+        &[ 0x0Au8, 1, 0, 0, 5]
+    }
+
+    fn right_truncated_length(&self) -> usize {
+        5
+    }
+}
+
+// This app always answers SELECT the same way
+impl App for StableApp {
+
+    fn select(&mut self, _apdu: &Command, reply: &mut response::Data) -> AppResult {
+        STABLE_APP_SELECTS.fetch_add(1, Ordering::SeqCst);
+        reply.extend_from_slice(b"U2F_V2").unwrap();
+        if self.long {
+            reply.resize(300, 0x55).unwrap();
+        }
+        Ok(())
+    }
+
+    fn deselect(&mut self, _: dispatch::Interface) {
+    }
+
+    fn call (&mut self, _: dispatch::Interface, _: bool, _apdu: &Command, _reply: &mut response::Data) -> iso7816::Result<Status> {
+        Err(Status::InstructionNotSupportedOrInvalid)
+    }
+
+    fn select_is_stable(&self) -> bool {
+        true
+    }
+
+}
+
+// Two apps where the AID of the first is a prefix of the AID of the second
+pub struct OverlappingApp1 {}
+pub struct OverlappingApp2 {}
+
+impl Aid for OverlappingApp1 {
+    fn aid(&self) -> &'static [u8] {
+        &[ 0x0Au8, 1, 0, 0, 6]
+    }
+
+    fn right_truncated_length(&self) -> usize {
+        5
+    }
+}
+
+impl Aid for OverlappingApp2 {
+    fn aid(&self) -> &'static [u8] {
+        &[ 0x0Au8, 1, 0, 0, 6, 1]
+    }
+
+    fn right_truncated_length(&self) -> usize {
+        5
+    }
+}
+
+impl App for OverlappingApp1 {
+
+    fn select(&mut self, _apdu: &Command, reply: &mut response::Data) -> AppResult {
+        reply.push(1).unwrap();
+        Ok(())
+    }
+
+    fn deselect(&mut self, _: dispatch::Interface) {
+    }
+
+    fn call (&mut self, _: dispatch::Interface, _: bool, _apdu: &Command, reply: &mut response::Data) -> iso7816::Result<Status> {
+        reply.push(1).unwrap();
+        Ok(Status::Success)
+    }
+
+}
+
+impl App for OverlappingApp2 {
+
+    fn select(&mut self, _apdu: &Command, reply: &mut response::Data) -> AppResult {
+        reply.push(2).unwrap();
+        Ok(())
+    }
+
+    fn deselect(&mut self, _: dispatch::Interface) {
+    }
+
+    fn call (&mut self, _: dispatch::Interface, _: bool, _apdu: &Command, reply: &mut response::Data) -> iso7816::Result<Status> {
+        reply.push(2).unwrap();
+        Ok(Status::Success)
+    }
+
+}
+
+pub struct CounterApp {
+    counter: u8,
+}
+
+impl Aid for CounterApp {
+    fn aid(&self) -> &'static [u8] {
+        &[ 0x0Au8, 1, 0, 0, 7]
+    }
+
+    fn right_truncated_length(&self) -> usize {
+        5
+    }
+}
+
+// This app increments a counter on Ins code 0x40, and reads it on 0x41.
+// Ins code 0x42 fails verification, which deselects the app.
+// It also accepts secure messaging CLA bits.
+impl App for CounterApp {
+
+    fn select(&mut self, _apdu: &Command, _reply: &mut response::Data) -> AppResult {
+        Ok(())
+    }
+
+    fn deselect(&mut self, _: dispatch::Interface) {
+    }
+
+    fn call (&mut self, _: dispatch::Interface, _: bool, apdu: &Command, reply: &mut response::Data) -> iso7816::Result<Status> {
+        match apdu.instruction().into() {
+            0x40 => {
+                self.counter += 1;
+                reply.push(self.counter).unwrap();
+                Ok(Status::Success)
+            }
+            0x41 => {
+                reply.push(self.counter).unwrap();
+                Ok(Status::Success)
             }
+            0x42 =>
+                Err(Status::SecurityStatusNotSatisfied),
             _ =>
                 Err(Status::InstructionNotSupportedOrInvalid)
         }
     }
 
+    fn deselect_on_error(&self, status: Status) -> bool {
+        status == Status::SecurityStatusNotSatisfied
+    }
+
+    fn allow_retransmit_dedup(&self) -> bool {
+        true
+    }
+
+    fn supports_secure_messaging(&self) -> bool {
+        true
+    }
+
+}
+
+// This app holds the contact requester, and cancels its request on any command,
+// like a reader that gives up while the app is working on the response.
+pub struct CancelingApp {
+    requester: interchange::Requester<interchanges::Contact>,
+}
+
+impl Aid for CancelingApp {
+    fn aid(&self) -> &'static [u8] {
+        &[ 0x0Au8, 1, 0, 0, 8]
+    }
+
+    fn right_truncated_length(&self) -> usize {
+        5
+    }
+}
+
+impl App for CancelingApp {
+
+    fn select(&mut self, _apdu: &Command, _reply: &mut response::Data) -> AppResult {
+        Ok(())
+    }
+
+    fn deselect(&mut self, _: dispatch::Interface) {
+    }
+
+    fn call (&mut self, _: dispatch::Interface, _: bool, _apdu: &Command, reply: &mut response::Data) -> iso7816::Result<Status> {
+        self.requester.cancel().unwrap();
+        reply.push(1).unwrap();
+        Ok(Status::Success)
+    }
+
+}
+
+pub struct FileApp {}
+
+impl Aid for FileApp {
+    fn aid(&self) -> &'static [u8] {
+        &[ 0x0Au8, 1, 0, 0, 0x0A]
+    }
+
+    fn right_truncated_length(&self) -> usize {
+        5
+    }
+}
+
+// This app has files. SELECTing one replies with P1 followed by the file identifier or path.
+impl App for FileApp {
+
+    fn select(&mut self, _apdu: &Command, _reply: &mut response::Data) -> AppResult {
+        Ok(())
+    }
+
+    fn deselect(&mut self, _: dispatch::Interface) {
+    }
+
+    fn call (&mut self, _: dispatch::Interface, _: bool, apdu: &Command, reply: &mut response::Data) -> iso7816::Result<Status> {
+        assert_eq!(apdu.instruction(), iso7816::Instruction::Select);
+        assert_eq!(apdu.p1 & 0x04, 0, "SELECT by AID was passed to the app");
+        reply.push(apdu.p1).unwrap();
+        reply.extend_from_slice(apdu.data()).unwrap();
+        Ok(Status::Success)
+    }
+
+    fn supports_file_selection(&self) -> bool {
+        true
+    }
+
+}
+
+pub struct InterfaceApp {
+    deselected_from: Option<dispatch::Interface>,
+}
+
+impl Aid for InterfaceApp {
+    fn aid(&self) -> &'static [u8] {
+        &[ 0x0Au8, 1, 0, 0, 0x0B]
+    }
+
+    fn right_truncated_length(&self) -> usize {
+        5
+    }
+}
+
+// This app remembers the interface it was last deselected from.
+impl App for InterfaceApp {
+
+    fn select(&mut self, _apdu: &Command, _reply: &mut response::Data) -> AppResult {
+        Ok(())
+    }
+
+    fn deselect(&mut self, interface: dispatch::Interface) {
+        self.deselected_from = Some(interface);
+    }
+
+    fn call (&mut self, _: dispatch::Interface, _: bool, _apdu: &Command, _reply: &mut response::Data) -> iso7816::Result<Status> {
+        Ok(Status::Success)
+    }
+
+}
+
+pub struct PickyApp {}
+
+impl Aid for PickyApp {
+    fn aid(&self) -> &'static [u8] {
+        &[ 0x0Au8, 1, 0, 0, 9]
+    }
+
+    fn right_truncated_length(&self) -> usize {
+        5
+    }
+}
+
+// This app declines Ins code 0x50 upfront, and echoes everything else.
+impl App for PickyApp {
+
+    fn select(&mut self, _apdu: &Command, _reply: &mut response::Data) -> AppResult {
+        Ok(())
+    }
+
+    fn deselect(&mut self, _: dispatch::Interface) {
+    }
+
+    fn call (&mut self, _: dispatch::Interface, _: bool, apdu: &Command, reply: &mut response::Data) -> iso7816::Result<Status> {
+        let ins: u8 = apdu.instruction().into();
+        assert_ne!(ins, 0x50, "declined command was passed to the app");
+        reply.extend_from_slice(apdu.data()).unwrap();
+        Ok(Status::Success)
+    }
+
+    fn can_handle(&self, _class: u8, instruction: u8, _p1: u8, _p2: u8) -> bool {
+        instruction != 0x50
+    }
+
+}
+
+// This app has a long AID, and may be selected by its RID alone.
+pub struct LongAidApp {}
+
+impl Aid for LongAidApp {
+    fn aid(&self) -> &'static [u8] {
+        &[ 0xA0u8, 0, 0, 6, 0x47, 0x2F, 0, 1]
+    }
+
+    fn right_truncated_length(&self) -> usize {
+        5
+    }
+}
+
+impl App for LongAidApp {
+
+    fn select(&mut self, _apdu: &Command, _reply: &mut response::Data) -> AppResult {
+        Ok(())
+    }
+
+    fn deselect(&mut self, _: dispatch::Interface) {
+    }
+
+    fn call (&mut self, _: dispatch::Interface, _: bool, _apdu: &Command, _reply: &mut response::Data) -> iso7816::Result<Status> {
+        Ok(Status::Success)
+    }
+
+}
+
+pub struct WarningApp {}
+
+impl Aid for WarningApp {
+    fn aid(&self) -> &'static [u8] {
+        &[ 0x0Au8, 1, 0, 0, 0x0C]
+    }
+
+    fn right_truncated_length(&self) -> usize {
+        5
+    }
+}
+
+// This app succeeds with a warning: 6281 (part of the data may be corrupted),
+// with P1 bytes of data.
+impl App for WarningApp {
+
+    fn select(&mut self, _apdu: &Command, _reply: &mut response::Data) -> AppResult {
+        Ok(())
+    }
+
+    fn deselect(&mut self, _: dispatch::Interface) {
+    }
+
+    fn call (&mut self, _: dispatch::Interface, _: bool, apdu: &Command, reply: &mut response::Data) -> iso7816::Result<Status> {
+        let length = match apdu.p1 {
+            0 => 300,
+            p1 => p1 as usize,
+        };
+        reply.resize(length, 0x55).unwrap();
+        Ok(Status::WarningMemoryUnchanged(0x81))
+    }
+
 }
 
 pub struct PanicApp {}
@@ -170,11 +589,11 @@ impl App for PanicApp {
         panic!("Dont call the panic app");
     }
 
-    fn deselect(&mut self) {
+    fn deselect(&mut self, _: dispatch::Interface) {
         panic!("Dont call the panic app");
     }
 
-    fn call (&mut self, _: dispatch::Interface, _apdu: &Command, _reply: &mut response::Data) -> AppResult {
+    fn call (&mut self, _: dispatch::Interface, _: bool, _apdu: &Command, _reply: &mut response::Data) -> iso7816::Result<Status> {
         panic!("Dont call the panic app");
     }
 
@@ -182,6 +601,13 @@ impl App for PanicApp {
 
 fn run_apdus(
     apdu_response_pairs: &[&[u8]],
+){
+    run_apdus_with_max_le(apdu_response_pairs, None)
+}
+
+fn run_apdus_with_max_le(
+    apdu_response_pairs: &[&[u8]],
+    negotiated_max_le: Option<usize>,
 ){
     assert!(apdu_response_pairs.len() > 0);
     assert!((apdu_response_pairs.len() & 1) == 0);
@@ -196,6 +622,9 @@ fn run_apdus(
         .expect("could not setup iso14443 ApduInterchange");
 
     let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    if let Some(max_le) = negotiated_max_le {
+        apdu_dispatch.set_negotiated_max_le(max_le);
+    }
     Delogger::flush();
 
     let mut app0 = PanicApp{};
@@ -203,6 +632,11 @@ fn run_apdus(
     let mut app2 = PanicApp{};
     let mut app3 = TestApp2{};
     let mut app4 = PanicApp{};
+    let mut app5 = TestApp3{};
+    let mut app6 = StableApp{ long: false };
+    let mut app7 = OverlappingApp1{};
+    let mut app8 = OverlappingApp2{};
+    let mut app9 = CounterApp{ counter: 0 };
 
     // for i in 0..apdu_response_pairs.len() {
         // print!("- "); 
@@ -221,7 +655,7 @@ fn run_apdus(
         contact_requester.request(&interchanges::Data::try_from_slice(&raw_req).unwrap())
             .expect("could not deposit command");
 
-        apdu_dispatch.poll(&mut[&mut app0, &mut app1, &mut app2, &mut app3, &mut app4]);
+        apdu_dispatch.poll(&mut[&mut app0, &mut app1, &mut app2, &mut app3, &mut app4, &mut app5, &mut app6, &mut app7, &mut app8, &mut app9]);
         Delogger::flush();
 
         let response = contact_requester.take_response().unwrap();
@@ -240,11 +674,67 @@ fn run_apdus(
     }
 }
 
-#[test]
-#[serial]
-fn malformed_apdus(){
-    run_apdus(
-        &[
+// Drives the dispatcher like a reader on either interface, in a single thread:
+// each exchange deposits a command, polls until the response is there, and takes it.
+struct Reader {
+    dispatch: apdu_dispatch::dispatch::ApduDispatch,
+    contact: interchange::Requester<interchanges::Contact>,
+    contactless: interchange::Requester<interchanges::Contactless>,
+}
+
+impl Reader {
+    fn new() -> Self {
+        unsafe { interchanges::Contact::reset_claims() };
+        unsafe { interchanges::Contactless::reset_claims() };
+        let (contact, contact_responder) = interchanges::Contact::claim()
+            .expect("could not setup ccid ApduInterchange");
+        let (contactless, contactless_responder) = interchanges::Contactless::claim()
+            .expect("could not setup iso14443 ApduInterchange");
+        let dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+        Self { dispatch, contact, contactless }
+    }
+
+    // One command and its response, status word included.
+    fn exchange(&mut self, interface: dispatch::Interface, apdu: &[u8], apps: &mut [&mut dyn App<command::Size, response::Size>]) -> Vec<u8> {
+        let message = interchanges::Data::try_from_slice(apdu).unwrap();
+        match interface {
+            dispatch::Interface::Contact => self.contact.request(&message).expect("could not deposit command"),
+            dispatch::Interface::Contactless => self.contactless.request(&message).expect("could not deposit command"),
+        };
+        // the request may wait for the other interface, or a response come later
+        for _ in 0..10 {
+            self.dispatch.poll(apps);
+            let response = match interface {
+                dispatch::Interface::Contact => self.contact.take_response(),
+                dispatch::Interface::Contactless => self.contactless.take_response(),
+            };
+            if let Some(response) = response {
+                return response.to_vec();
+            }
+        }
+        panic!("no response to {:02X?}", apdu);
+    }
+
+    // The complete response, fetching the rest with GetResponse while the status is 61XX.
+    fn send(&mut self, interface: dispatch::Interface, apdu: &[u8], apps: &mut [&mut dyn App<command::Size, response::Size>]) -> Vec<u8> {
+        // GetResponse uses the class of the command, without the chaining bit
+        let class = apdu[0] & !0x10;
+        let mut data = Vec::new();
+        let mut response = self.exchange(interface, apdu, apps);
+        while let [.., 0x61, le] = response[..] {
+            data.extend_from_slice(&response[..response.len() - 2]);
+            response = self.exchange(interface, &[class, 0xC0, 0x00, 0x00, le], apps);
+        }
+        data.extend_from_slice(&response);
+        data
+    }
+}
+
+#[test]
+#[serial]
+fn malformed_apdus(){
+    run_apdus(
+        &[
             // Too short
             &[0x00u8,],
             &[0x6F, 0x00],
@@ -266,6 +756,9 @@ fn malformed_apdus(){
             // Invalid extended length
             &[0x00u8,0x00,0x00,0x00,0xff,0x00,0x05,1,1,1,1,1],
             &[0x6F, 0x00],
+            // Lc = 0, which is neither short nor extended
+            &[0x00u8,0x00,0x00,0x00,0x00,0x05],
+            &[0x6F, 0x00],
             // sanity check with Valid APDU with extended length
             &[0x00u8,0x00,0x00,0x00,0x00,0x00,0x05,1,1,1,1,1],
             &[0x6A, 0x82],
@@ -313,6 +806,72 @@ fn select_not_found(){
     )
 }
 
+#[test]
+#[serial]
+fn select_overlong_aid(){
+    run_apdus(
+        &[
+            // Select, with an AID longer than 16 bytes
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x11,
+                0x0A, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            // Not found
+            &[0x6A, 0x82],
+
+            // Select
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+            // Ok
+            &[0x90, 0x00],
+        ]
+    )
+}
+
+#[test]
+#[serial]
+fn select_empty_aid(){
+    run_apdus(
+        &[
+            // Select, without an AID
+            &[0x00u8, 0xA4, 0x04, 0x00],
+            // Not found
+            &[0x6A, 0x82],
+
+            // Select
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+            // Ok
+            &[0x90, 0x00],
+        ]
+    )
+}
+
+#[test]
+#[serial]
+fn selected_app_no_longer_passed(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (mut contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app1 = TestApp1{};
+    let mut app2 = TestApp2{};
+
+    // Select 1
+    contact_requester.request(&interchanges::Data::try_from_slice(
+        &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+    ).unwrap()).expect("could not deposit command");
+    apdu_dispatch.poll(&mut [&mut app1]);
+    assert_eq!(contact_requester.take_response().unwrap().as_slice(), &[0x90, 0x00]);
+
+    // Select 2, without app 1 around to be deselected
+    contact_requester.request(&interchanges::Data::try_from_slice(
+        &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x02],
+    ).unwrap()).expect("could not deposit command");
+    apdu_dispatch.poll(&mut [&mut app2]);
+    assert_eq!(contact_requester.take_response().unwrap().as_slice(), &[0x90, 0x00]);
+}
+
 #[test]
 #[serial]
 fn echo_1(){
@@ -331,6 +890,73 @@ fn echo_1(){
     )
 }
 
+#[test]
+#[serial]
+fn logical_channel_not_supported(){
+    run_apdus(
+        &[
+            // Select
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+            // Ok
+            &[0x90, 0x00],
+
+            // Echo on logical channel 1
+            &[0x01u8, 0x10, 0x00, 0x00, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05],
+            // Logical channel not supported
+            &[0x68, 0x81],
+
+            // Echo on logical channel 4 (further interindustry class)
+            &[0x40u8, 0x10, 0x00, 0x00, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05],
+            // Logical channel not supported
+            &[0x68, 0x81],
+
+            // Echo on the basic channel still works
+            &[0x00u8, 0x10, 0x00, 0x00, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05],
+            // Echo + Ok
+            &[0x00u8, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x90, 0x00],
+        ]
+    )
+}
+
+#[test]
+#[serial]
+fn secure_messaging(){
+    run_apdus(
+        &[
+            // Select
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+            // Ok
+            &[0x90, 0x00],
+
+            // Echo, with standard secure messaging
+            &[0x0Cu8, 0x10, 0x00, 0x00, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05],
+            // Secure messaging not supported
+            &[0x68, 0x82],
+
+            // Echo, with proprietary secure messaging, chained
+            &[0x14u8, 0x10, 0x00, 0x00, 0x02, 0x01, 0x02],
+            // Secure messaging not supported
+            &[0x68, 0x82],
+
+            // Select app that accepts secure messaging
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x07],
+            // Ok
+            &[0x90, 0x00],
+
+            // Increment, with standard secure messaging
+            &[0x0Cu8, 0x40, 0x00, 0x00],
+            // 1 + Ok
+            &[0x01, 0x90, 0x00],
+
+            // Secure messaging is only allowed for the app that accepts it
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+            &[0x90, 0x00],
+            &[0x0Cu8, 0x10, 0x00, 0x00, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05],
+            &[0x68, 0x82],
+        ]
+    )
+}
+
 #[test]
 #[serial]
 fn echo_with_cla_bits_set(){
@@ -349,6 +975,32 @@ fn echo_with_cla_bits_set(){
     )
 }
 
+#[test]
+#[serial]
+fn proprietary_class(){
+    run_apdus(
+        &[
+            // Select
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+            &[0x90, 0x00],
+
+            // Echo; in the interindustry range, this would be logical channel 1
+            &[0x81u8, 0x10, 0x00, 0x00, 0x01, 0x01],
+            &[0x00u8, 0x00, 0x00, 0x00, 0x00, 0x01, 0x90, 0x00],
+
+            // Echo; in the interindustry range, this would be secure messaging
+            &[0x84u8, 0x10, 0x00, 0x00, 0x01, 0x02],
+            &[0x00u8, 0x00, 0x00, 0x00, 0x00, 0x02, 0x90, 0x00],
+
+            // Echo, chained
+            &[0x90u8, 0x10, 0x00, 0x00, 0x01, 0x03],
+            &[0x90, 0x00],
+            &[0x80u8, 0x10, 0x00, 0x00, 0x01, 0x04],
+            &[0x00u8, 0x00, 0x00, 0x00, 0x00, 0x03, 0x04, 0x90, 0x00],
+        ]
+    )
+}
+
 #[test]
 #[serial]
 fn echo_wrong_instruction(){
@@ -440,6 +1092,42 @@ fn deselect (){
     )
 }
 
+#[test]
+#[serial]
+fn deselect_on_error (){
+    run_apdus(
+        &[
+            // Select app that deselects on failed verification
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x07],
+            &[0x90, 0x00],
+
+            // Increment
+            &[0x00u8, 0x40, 0x00, 0x00],
+            &[0x01, 0x90, 0x00],
+
+            // Other errors keep the app selected
+            &[0x00u8, 0x43, 0x00, 0x00],
+            &[0x6d, 0x00],
+            &[0x00u8, 0x41, 0x00, 0x00],
+            &[0x01, 0x90, 0x00],
+
+            // Failed verification
+            &[0x00u8, 0x42, 0x00, 0x00],
+            &[0x69, 0x82],
+
+            // No app selected
+            &[0x00u8, 0x41, 0x00, 0x00],
+            &[0x6a, 0x82],
+
+            // Select again
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x07],
+            &[0x90, 0x00],
+            &[0x00u8, 0x41, 0x00, 0x00],
+            &[0x01, 0x90, 0x00],
+        ]
+    )
+}
+
 #[test]
 #[serial]
 fn extended_length_echo (){
@@ -568,7 +1256,7 @@ fn chained_apdu_1 (){
             // Get Response
             &[0x00u8, 0xC0, 0x00, 0x00],
             // Error
-            &[ 0x6F,0x00 ],
+            &[ 0x69,0x85 ],
         ]
     )
 }
@@ -686,6 +1374,45 @@ fn multiple_chained_apdu_1 (){
     )
 }
 
+#[test]
+#[serial]
+fn get_response_class_mismatch(){
+    let mut fibonacci = vec![0u8, 1, 1];
+    for i in 3..2048 {
+        fibonacci.push(fibonacci[i - 1].wrapping_add(fibonacci[i - 2]));
+    }
+    let mut first_chunk = fibonacci[..256].to_vec();
+    first_chunk.extend_from_slice(&[0x61, 0x00]);
+    let mut second_chunk = fibonacci[256..512].to_vec();
+    second_chunk.extend_from_slice(&[0x61, 0x00]);
+
+    run_apdus(
+        &[
+            // Select 2
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x02],
+            &[0x90, 0x00u8],
+
+            // Chained command to get long fibonacci back
+            &[0x10u8, 0x30, 0x00, 0x00, 0x01, 0x01],
+            &[0x90, 0x00],
+            &[0x00u8, 0x30, 0x00, 0x00, 0x01, 0x01],
+            &first_chunk,
+
+            // GetResponse in the class of the command
+            &[0x00u8, 0xC0, 0x00, 0x00],
+            &second_chunk,
+
+            // GetResponse with another class
+            &[0x80u8, 0xC0, 0x00, 0x00],
+            &[0x6F, 0x00],
+
+            // The response was discarded
+            &[0x00u8, 0xC0, 0x00, 0x00],
+            &[0x69, 0x85],
+        ]
+    )
+}
+
 #[test]
 #[serial]
 fn test_chained_fibonacci_response(){
@@ -852,7 +1579,7 @@ fn multiple_chained_apdu_interruption (){
 
             // GetResponse no longer works
             &[0x00u8, 0xC0, 0x00, 0x00],
-            &[ 0x6F,0x00 ],
+            &[ 0x69,0x85 ],
 
             // Check that new chaining transaction works
             &[0x10u8, 0x20, 0x00, 0x00, 0xFF,
@@ -923,52 +1650,1544 @@ fn chaining_with_unknown_class_range(){
 
 #[test]
 #[serial]
-fn check_stack_burden(){
+fn unexpected_get_response(){
+    run_apdus(
+        &[
+            // Select 1
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+            &[0x90, 0x00u8],
+
+            // Nothing is buffered
+            &[0x00u8, 0xC0, 0x00, 0x00],
+            &[0x69, 0x85],
+        ]
+    )
+}
+
+#[test]
+#[serial]
+fn app_without_get_response(){
+    run_apdus(
+        &[
+            // Select 3
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x04],
+            &[0x90, 0x00u8],
+
+            // Set chaining bit
+            &[0x10u8, 0x10, 0x00, 0x00, 0x02, 0x01, 0x02],
+            &[0x90, 0x00],
+
+            // Chained request, but the short response comes back at once
+            &[0x00u8, 0x10, 0x00, 0x00, 0x02, 0x03, 0x04],
+            &[0x01, 0x02, 0x03, 0x04, 0x90, 0x00],
+
+            // Response does not fit, and is not chained
+            &[0x00u8, 0x30, 0x00, 0x00],
+            &[0x67, 0x00],
+
+            // Nothing to get
+            &[0x00u8, 0xC0, 0x00, 0x00],
+            &[0x69, 0x85],
+        ]
+    )
+}
+
+#[test]
+#[serial]
+fn stable_select_is_cached(){
+    STABLE_APP_SELECTS.store(0, Ordering::SeqCst);
+    run_apdus(
+        &[
+            // Select stable app
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x05],
+            &[b'U', b'2', b'F', b'_', b'V', b'2', 0x90, 0x00],
+
+            // Select it again
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x05],
+            &[b'U', b'2', b'F', b'_', b'V', b'2', 0x90, 0x00],
+        ]
+    );
+    assert_eq!(STABLE_APP_SELECTS.load(Ordering::SeqCst), 1);
+
+    STABLE_APP_SELECTS.store(0, Ordering::SeqCst);
+    run_apdus(
+        &[
+            // Select stable app
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x05],
+            &[b'U', b'2', b'F', b'_', b'V', b'2', 0x90, 0x00],
+
+            // Select 1
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+            &[0x90, 0x00u8],
+
+            // After deselection, the app is asked again
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x05],
+            &[b'U', b'2', b'F', b'_', b'V', b'2', 0x90, 0x00],
+        ]
+    );
+    assert_eq!(STABLE_APP_SELECTS.load(Ordering::SeqCst), 2);
+}
 
+#[test]
+#[serial]
+fn select_cache_per_interface(){
     unsafe { interchanges::Contact::reset_claims() };
     unsafe { interchanges::Contactless::reset_claims() };
-
-    let (mut contact_requester, contact_responder) = interchanges::Contact::claim()
+    let (_contact_requester, contact_responder) = interchanges::Contact::claim()
         .expect("could not setup ccid ApduInterchange");
-
     let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
         .expect("could not setup iso14443 ApduInterchange");
 
     let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut stable = StableApp{ long: false };
+    let select: &[u8] = &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x05];
+    let u2f_v2: &[u8] = &[b'U', b'2', b'F', b'_', b'V', b'2', 0x90, 0x00];
+    let fresh = apdu_dispatch.snapshot();
+
+    STABLE_APP_SELECTS.store(0, Ordering::SeqCst);
+    for &interface in &[dispatch::Interface::Contact, dispatch::Interface::Contactless] {
+        for _ in 0..2 {
+            let response = apdu_dispatch.process_raw(interface, select, &mut [&mut stable]).unwrap();
+            assert_eq!(response.as_slice(), u2f_v2);
+        }
+    }
+    // each interface asks the app once
+    assert_eq!(STABLE_APP_SELECTS.load(Ordering::SeqCst), 2);
+
+    // a long response is not cached
+    apdu_dispatch.restore(&fresh);
+    let mut long = StableApp{ long: true };
+    STABLE_APP_SELECTS.store(0, Ordering::SeqCst);
+    for _ in 0..2 {
+        let response = apdu_dispatch.process_raw(dispatch::Interface::Contact, select, &mut [&mut long]).unwrap();
+        assert_eq!(response.len(), 300 + 2);
+    }
+    assert_eq!(STABLE_APP_SELECTS.load(Ordering::SeqCst), 2);
+}
 
-    let mut app1 = TestApp1{};
+#[test]
+#[serial]
+fn select_next_occurrence(){
+    run_apdus(
+        &[
+            // Select first occurrence
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x06, 0x0A, 0x01, 0x00, 0x00, 0x06, 0x01],
+            &[0x01, 0x90, 0x00],
 
-    contact_requester.request(&interchanges::Data::try_from_slice(
-        &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
-    ).unwrap()).expect("could not deposit command");
+            // Commands go to the selected app
+            &[0x00u8, 0x10, 0x00, 0x00],
+            &[0x01, 0x90, 0x00],
 
-    apdu_dispatch.poll(&mut[&mut app1]);
+            // Select next occurrence
+            &[0x00u8, 0xA4, 0x04, 0x02, 0x06, 0x0A, 0x01, 0x00, 0x00, 0x06, 0x01],
+            &[0x02, 0x90, 0x00],
 
-    let response = contact_requester.take_response().unwrap();
+            // Commands go to the newly selected app
+            &[0x00u8, 0x10, 0x00, 0x00],
+            &[0x02, 0x90, 0x00],
 
-    print!(">> "); 
-    dump_hex(&response);
+            // No more occurrences
+            &[0x00u8, 0xA4, 0x04, 0x02, 0x06, 0x0A, 0x01, 0x00, 0x00, 0x06, 0x01],
+            &[0x6A, 0x82],
 
-    contact_requester.request(&interchanges::Data::try_from_slice(
-        &[0x00u8, 0x15, 0x00, 0x00],
-    ).unwrap()).expect("could not deposit command");
+            // Starting over
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x06, 0x0A, 0x01, 0x00, 0x00, 0x06, 0x01],
+            &[0x01, 0x90, 0x00],
+        ]
+    )
+}
 
-    apdu_dispatch.poll(&mut[&mut app1]);
+#[test]
+#[serial]
+fn negotiated_max_le(){
 
-    let response = contact_requester.take_response().unwrap();
+    let mut expected = response::Data::new();
+    expected.extend_from_slice(&[0,1,1]).unwrap();
+    for i in 3..2048 {
+        let next = ((expected[i-1] as u32 + expected[i - 2] as u32) & 0xff) as u8;
+        expected.push(next).unwrap();
+    }
 
-    print!(">> "); 
-    dump_hex(&response);
+    let mut first = expected[..1024].to_vec();
+    first.extend_from_slice(&[0x61, 0x00]);
+    let mut last = expected[1024..].to_vec();
+    last.extend_from_slice(&[0x90, 0x00]);
 
-    let payload: [u8; 4] = [response[0], response[1], response[2], response[3], ];
-    let min_stack = u32::from_be_bytes(payload);
-    let max_stack = (&response as *const interchanges::Data) as u32;
+    // Only one GetResponse is needed, instead of seven with the default of 256 bytes.
+    run_apdus_with_max_le(
+        &[
+            // Select 2
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x02],
+            &[0x90, 0x00u8],
 
-    // Last checked:
-    // Burden: 43744 bytes with Large apdu for Command & Response, not returning Data.
-    println!("Burden: {} bytes", max_stack - min_stack);
+            // Set chaining bit
+            &[0x10u8, 0x30, 0x00, 0x00, 0x01, 0x01],
+            &[0x90, 0x00],
+
+            // Send last command
+            &[0x00u8, 0x30, 0x00, 0x00, 0x01, 0x01],
+            &first,
+
+            // Get Response
+            &[0x00u8, 0xC0, 0x00, 0x00],
+            &last,
+        ],
+        Some(1024),
+    )
+}
+
+#[test]
+#[serial]
+fn chained_flag(){
+    run_apdus(
+        &[
+            // Select 1
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+            &[0x90, 0x00u8],
+
+            // Single APDU
+            &[0x00u8, 0x16, 0x00, 0x00, 0x01, 0xAA],
+            &[0x00, 0x90, 0x00],
+
+            // Set chaining bit
+            &[0x10u8, 0x16, 0x00, 0x00, 0x01, 0xAA],
+            &[0x90, 0x00],
+
+            // Reassembled command is flagged
+            &[0x00u8, 0x16, 0x00, 0x00, 0x01, 0xBB],
+            &[0x01, 0x90, 0x00],
+
+            // Single APDU again
+            &[0x00u8, 0x16, 0x00, 0x00, 0x01, 0xAA],
+            &[0x00, 0x90, 0x00],
+        ]
+    )
+}
+
+#[test]
+#[serial]
+fn retransmitted_command(){
+    run_apdus(
+        &[
+            // Select counter app
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x07],
+            &[0x90, 0x00u8],
+
+            // Increment
+            &[0x00u8, 0x40, 0x00, 0x00],
+            &[0x01, 0x90, 0x00],
+
+            // Same frame again: reply is replayed, counter unchanged
+            &[0x00u8, 0x40, 0x00, 0x00],
+            &[0x01, 0x90, 0x00],
+
+            &[0x00u8, 0x41, 0x00, 0x00],
+            &[0x01, 0x90, 0x00],
+
+            // After a different command, the same command is processed again
+            &[0x00u8, 0x40, 0x00, 0x00],
+            &[0x02, 0x90, 0x00],
+        ]
+    )
+}
+
+// Keep both interfaces busy with SELECTs, count how often each is answered.
+fn serviced_per_interface(fairness: dispatch::Fairness) -> (usize, usize) {
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (mut contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (mut contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    apdu_dispatch.set_fairness(fairness);
+
+    let mut app1 = TestApp1{};
+    let select = interchanges::Data::try_from_slice(
+        &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+    ).unwrap();
+
+    contact_requester.request(&select).expect("could not deposit command");
+    contactless_requester.request(&select).expect("could not deposit command");
+
+    let (mut contact, mut contactless) = (0, 0);
+    for _ in 0..10 {
+        apdu_dispatch.poll(&mut[&mut app1]);
+
+        if let Some(response) = contact_requester.take_response() {
+            assert_eq!(response.as_slice(), &[0x90, 0x00]);
+            contact += 1;
+            contact_requester.request(&select).expect("could not deposit command");
+        }
+        if let Some(response) = contactless_requester.take_response() {
+            assert_eq!(response.as_slice(), &[0x90, 0x00]);
+            contactless += 1;
+            contactless_requester.request(&select).expect("could not deposit command");
+        }
+    }
+    (contact, contactless)
+}
+
+#[test]
+#[serial]
+fn fairness(){
+    // by default, contactless traffic starves the contact interface
+    assert_eq!(serviced_per_interface(dispatch::Fairness::ContactlessPriority), (0, 10));
+    assert_eq!(serviced_per_interface(dispatch::Fairness::RoundRobin), (5, 5));
+}
+
+#[cfg(feature = "timing")]
+#[test]
+#[serial]
+fn slow_calls(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (mut contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    apdu_dispatch.set_slow_call_threshold(std::time::Duration::from_millis(10));
+
+    let mut app1 = TestApp1{};
+    for (apdu, slow_calls) in [
+        // Select
+        &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01][..],
+        // Echo
+        &[0x00u8, 0x10, 0x00, 0x00, 0x01, 0x01][..],
+        // Block
+        &[0x00u8, 0x17, 0x00, 0x00][..],
+    ].iter().zip([0, 0, 1].iter()) {
+        contact_requester.request(&interchanges::Data::try_from_slice(apdu).unwrap())
+            .expect("could not deposit command");
+        apdu_dispatch.poll(&mut[&mut app1]);
+        assert!(contact_requester.take_response().unwrap().ends_with(&[0x90, 0x00]));
+        assert_eq!(apdu_dispatch.slow_calls(), *slow_calls);
+    }
+}
+
+#[cfg(feature = "timing")]
+#[test]
+#[serial]
+fn response_timeout(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (mut contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    apdu_dispatch.set_response_timeout(std::time::Duration::from_millis(10), Status::WrongLength);
+
+    let mut app1 = TestApp1{};
+    for (apdu, response) in [
+        // Select
+        &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01][..],
+        &[0x90, 0x00][..],
+        // Echo
+        &[0x00u8, 0x10, 0x00, 0x00, 0x01, 0x01][..],
+        &[0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x90, 0x00][..],
+        // Block, past the timeout
+        &[0x00u8, 0x17, 0x00, 0x00][..],
+        &[0x67, 0x00][..],
+        // the app was deselected
+        &[0x00u8, 0x10, 0x00, 0x00, 0x01, 0x01][..],
+        &[0x6a, 0x82][..],
+    ].chunks(2).map(|pair| (pair[0], pair[1])) {
+        contact_requester.request(&interchanges::Data::try_from_slice(apdu).unwrap())
+            .expect("could not deposit command");
+        let error = apdu_dispatch.poll(&mut[&mut app1]).error;
+        assert_eq!(contact_requester.take_response().unwrap().as_slice(), response);
+        match apdu[1] {
+            0x17 => assert_eq!(error, Some(dispatch::DispatchError::TimedOut)),
+            0x10 if response == [0x6a, 0x82] => assert_eq!(error, Some(dispatch::DispatchError::NoAppSelected)),
+            _ => assert_eq!(error, None),
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn registered_apps(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (mut contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+
+    let mut exchange = |apdu_dispatch: &mut apdu_dispatch::dispatch::ApduDispatch, apdu: &[u8]| {
+        contact_requester.request(&interchanges::Data::try_from_slice(apdu).unwrap())
+            .expect("could not deposit command");
+        apdu_dispatch.poll_registered();
+        contact_requester.take_response().unwrap()
+    };
+
+    let select = [0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01];
+
+    // nothing registered yet
+    assert_eq!(exchange(&mut apdu_dispatch, &select).as_slice(), &[0x6A, 0x82]);
+
+    let app1: &'static mut TestApp1 = Box::leak(Box::new(TestApp1{}));
+    let apps: &'static mut [&'static mut dyn App<command::Size, response::Size>; 1] = Box::leak(Box::new([app1]));
+    apdu_dispatch.register_apps(apps);
+
+    assert_eq!(exchange(&mut apdu_dispatch, &select).as_slice(), &[0x90, 0x00]);
+    assert_eq!(
+        exchange(&mut apdu_dispatch, &[0x00u8, 0x10, 0x00, 0x00, 0x01, 0x01]).as_slice(),
+        &[0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x90, 0x00],
+    );
+}
+
+#[test]
+#[serial]
+fn no_apps(){
+    use apdu_dispatch::dispatch::DispatchError;
+
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (mut contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app2 = TestApp2{};
+    let mut exchange = |apdu_dispatch: &mut apdu_dispatch::dispatch::ApduDispatch, apps: &mut [&mut dyn App<command::Size, response::Size>], apdu: &[u8]| {
+        contact_requester.request(&interchanges::Data::try_from_slice(apdu).unwrap())
+            .expect("could not deposit command");
+        let error = apdu_dispatch.poll(apps).error;
+        (contact_requester.take_response().unwrap().as_slice().to_vec(), error)
+    };
+
+    let select: &[u8] = &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x02];
+    let get_response: &[u8] = &[0x00u8, 0xC0, 0x00, 0x00];
+    let command: &[u8] = &[0x00u8, 0x20, 0x00, 0x00, 0x01, 0x01];
+    let not_found = (vec![0x6A, 0x82], Some(DispatchError::NoApps));
+
+    // nothing selected, nothing buffered
+    for apdu in [select, get_response, command].iter() {
+        assert_eq!(exchange(&mut apdu_dispatch, &mut [], apdu), not_found);
+    }
+
+    // Select 2, and leave a chained response behind
+    assert_eq!(exchange(&mut apdu_dispatch, &mut [&mut app2], select), (vec![0x90, 0x00], None));
+    assert_eq!(exchange(&mut apdu_dispatch, &mut [&mut app2], &[0x10u8, 0x30, 0x00, 0x00, 0x01, 0x01]), (vec![0x90, 0x00], None));
+    let (response, _) = exchange(&mut apdu_dispatch, &mut [&mut app2], &[0x00u8, 0x30, 0x00, 0x00, 0x01, 0x01]);
+    assert_eq!(response[response.len() - 2], 0x61);
+
+    // the buffered response is not served, and the selection is dropped
+    assert_eq!(exchange(&mut apdu_dispatch, &mut [], get_response), not_found);
+    assert_eq!(exchange(&mut apdu_dispatch, &mut [&mut app2], get_response).0, vec![0x69, 0x85]);
+    assert_eq!(exchange(&mut apdu_dispatch, &mut [], command), not_found);
+    assert_eq!(exchange(&mut apdu_dispatch, &mut [&mut app2], command), (vec![0x6A, 0x82], Some(DispatchError::NoAppSelected)));
+
+    // a chain is still buffered, its last command is answered
+    assert_eq!(exchange(&mut apdu_dispatch, &mut [], &[0x10u8, 0x20, 0x00, 0x00, 0x01, 0x01]), (vec![0x90, 0x00], None));
+    assert_eq!(exchange(&mut apdu_dispatch, &mut [], command), not_found);
+
+    // and selecting works again
+    assert_eq!(exchange(&mut apdu_dispatch, &mut [&mut app2], select), (vec![0x90, 0x00], None));
+}
+
+#[test]
+#[serial]
+fn process_raw(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (mut contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app0 = PanicApp{};
+    let mut app1 = TestApp1{};
+    let interface = dispatch::Interface::Contact;
+
+    let mut exchange = |apdu: &[u8]| {
+        apdu_dispatch.process_raw(interface, apdu, &mut [&mut app0, &mut app1]).unwrap()
+    };
+
+    // Select
+    assert_eq!(exchange(&[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01]).as_slice(), &[0x90, 0x00]);
+
+    // Echo, chained
+    assert_eq!(exchange(&[0x10u8, 0x10, 0x00, 0x00, 0x02, 0x01, 0x02]).as_slice(), &[0x90, 0x00]);
+    assert_eq!(
+        exchange(&[0x00u8, 0x10, 0x00, 0x00, 0x01, 0x03]).as_slice(),
+        &[0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x90, 0x00],
+    );
+
+    // Wrong instruction, and a malformed APDU
+    assert_eq!(exchange(&[0x00u8, 0x11, 0x00, 0x00]).as_slice(), &[0x6d, 0x00]);
+    assert_eq!(exchange(&[0x00u8, 0x10]).as_slice(), &[0x6f, 0x00]);
+
+    // nothing went through the interchange
+    assert_eq!(contact_requester.state(), interchange::State::Idle);
+    assert!(contact_requester.take_response().is_none());
+}
+
+fn run_filtered(filter: dispatch::AidFilter, apdu_response_pairs: &[&[u8]]) {
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (_contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    apdu_dispatch.set_aid_filter(filter);
+    let mut app1 = TestApp1{};
+    let mut app2 = TestApp2{};
+
+    for pair in apdu_response_pairs.chunks(2) {
+        let response = apdu_dispatch.process_raw(dispatch::Interface::Contact, pair[0], &mut [&mut app1, &mut app2]).unwrap();
+        assert_eq!(response.as_slice(), pair[1]);
+    }
+}
+
+#[test]
+#[serial]
+fn aid_allowlist(){
+    run_filtered(
+        dispatch::AidFilter::Allow(&[&[0x0A, 0x01, 0x00, 0x00, 0x02]]),
+        &[
+            // Select 1, registered but not allowed
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+            &[0x6a, 0x82],
+
+            // Select 2
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x02],
+            &[0x90, 0x00],
+        ]
+    );
+}
+
+#[test]
+#[serial]
+fn aid_denylist(){
+    run_filtered(
+        dispatch::AidFilter::Deny(&[&[0x0A, 0x01, 0x00, 0x00, 0x01]]),
+        &[
+            // Select 1, registered but denied
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+            &[0x6a, 0x82],
+
+            // Select 2
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x02],
+            &[0x90, 0x00],
+        ]
+    );
+
+    // a prefix denies all apps starting with it
+    run_filtered(
+        dispatch::AidFilter::Deny(&[&[0x0A, 0x01, 0x00, 0x00]]),
+        &[
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x02],
+            &[0x6a, 0x82],
+        ]
+    );
+}
+
+#[test]
+#[serial]
+fn declined_command(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (_contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app = PickyApp{};
+
+    for pair in [
+        // Select
+        &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x09][..],
+        &[0x90, 0x00][..],
+        // Declined by the app, answered by the dispatcher
+        &[0x00u8, 0x50, 0x00, 0x00, 0x01, 0x01][..],
+        &[0x6d, 0x00][..],
+        // Passed to the app
+        &[0x00u8, 0x51, 0x00, 0x00, 0x01, 0x01][..],
+        &[0x01, 0x90, 0x00][..],
+    ].chunks(2) {
+        let response = apdu_dispatch.process_raw(dispatch::Interface::Contact, pair[0], &mut [&mut app]).unwrap();
+        assert_eq!(response.as_slice(), pair[1]);
+    }
+}
+
+#[test]
+#[serial]
+fn canceled_request(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app = CancelingApp { requester: contact_requester };
+
+    let select = interchanges::Data::try_from_slice(
+        &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x08],
+    ).unwrap();
+    app.requester.request(&select).expect("could not deposit command");
+    apdu_dispatch.poll(&mut [&mut app]);
+    assert_eq!(app.requester.take_response().unwrap().as_slice(), &[0x90, 0x00]);
+
+    // the request is canceled before the app's reply: no panic, and nothing is answered
+    let command = interchanges::Data::try_from_slice(&[0x00u8, 0x10, 0x00, 0x00]).unwrap();
+    app.requester.request(&command).expect("could not deposit command");
+    assert!(apdu_dispatch.poll(&mut [&mut app]).responded.is_none());
+    assert!(app.requester.take_response().is_none());
+
+    // the interface is usable again
+    assert_eq!(app.requester.state(), interchange::State::Idle);
+    app.requester.request(&select).expect("could not deposit command");
+    apdu_dispatch.poll(&mut [&mut app]);
+    assert_eq!(app.requester.take_response().unwrap().as_slice(), &[0x90, 0x00]);
+}
+
+// Collects the APDUs seen by the dispatcher, commands are marked with `true`.
+struct Tap {
+    seen: std::sync::Arc<std::sync::Mutex<Vec<(bool, Vec<u8>)>>>,
+}
+
+impl dispatch::ApduTap for Tap {
+    fn command(&mut self, _: dispatch::Interface, apdu: &[u8]) {
+        self.seen.lock().unwrap().push((true, apdu.to_vec()));
+    }
+
+    fn response(&mut self, _: dispatch::Interface, apdu: &[u8]) {
+        self.seen.lock().unwrap().push((false, apdu.to_vec()));
+    }
+}
+
+#[test]
+#[serial]
+fn tap(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (mut contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    apdu_dispatch.set_tap(Box::leak(Box::new(Tap { seen: seen.clone() })));
+
+    let mut app1 = TestApp1{};
+    let select = [0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01];
+    let malformed = [0x00u8, 0x10, 0x00];
+    for apdu in [&select[..], &malformed[..]].iter() {
+        contact_requester.request(&interchanges::Data::try_from_slice(apdu).unwrap())
+            .expect("could not deposit command");
+        apdu_dispatch.poll(&mut [&mut app1]);
+        contact_requester.take_response().unwrap();
+    }
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 4);
+    assert_eq!(seen[0], (true, select.to_vec()));
+    assert_eq!(seen[1], (false, vec![0x90, 0x00]));
+    assert_eq!(seen[2], (true, malformed.to_vec()));
+    assert!(!seen[3].0);
+}
+
+#[test]
+fn validate_apps(){
+    use apdu_dispatch::dispatch::{ApduDispatch, AidConflict, InvalidApps, MAX_APPS};
+
+    let mut app1 = TestApp1{};
+    let mut app2 = TestApp2{};
+    let mut app3 = TestApp3{};
+    assert_eq!(ApduDispatch::validate_apps(&[&mut app1, &mut app2, &mut app3]), Ok(()));
+
+    // duplicate
+    let mut app1 = TestApp1{};
+    let mut app2 = TestApp1{};
+    assert_eq!(
+        ApduDispatch::validate_apps(&[&mut app1, &mut app2]),
+        Err(InvalidApps::AidConflict(AidConflict { first: &[0x0A, 1, 0, 0, 1], second: &[0x0A, 1, 0, 0, 1] })),
+    );
+
+    // prefix overlap, in either order
+    let mut app1 = TestApp2{};
+    let mut app2 = OverlappingApp2{};
+    let mut app3 = OverlappingApp1{};
+    assert_eq!(
+        ApduDispatch::validate_apps(&[&mut app1, &mut app2, &mut app3]),
+        Err(InvalidApps::AidConflict(AidConflict { first: &[0x0A, 1, 0, 0, 6, 1], second: &[0x0A, 1, 0, 0, 6] })),
+    );
+
+    // too many, checked before the AIDs
+    let mut apps: Vec<TestApp1> = (0..MAX_APPS + 1).map(|_| TestApp1{}).collect();
+    let apps: Vec<&mut dyn App<command::Size, response::Size>> = apps.iter_mut()
+        .map(|app| app as &mut dyn App<command::Size, response::Size>)
+        .collect();
+    assert_eq!(ApduDispatch::validate_apps(&apps), Err(InvalidApps::TooMany(MAX_APPS + 1)));
+    assert!(matches!(ApduDispatch::validate_apps(&apps[..MAX_APPS]), Err(InvalidApps::AidConflict(_))));
+}
+
+#[test]
+#[serial]
+fn check_stack_burden(){
+
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+
+    let (mut contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+
+    let mut app1 = TestApp1{};
+
+    contact_requester.request(&interchanges::Data::try_from_slice(
+        &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+    ).unwrap()).expect("could not deposit command");
+
+    apdu_dispatch.poll(&mut[&mut app1]);
+
+    let response = contact_requester.take_response().unwrap();
+
+    print!(">> "); 
+    dump_hex(&response);
+
+    contact_requester.request(&interchanges::Data::try_from_slice(
+        &[0x00u8, 0x15, 0x00, 0x00],
+    ).unwrap()).expect("could not deposit command");
+
+    apdu_dispatch.poll(&mut[&mut app1]);
+
+    let response = contact_requester.take_response().unwrap();
+
+    print!(">> "); 
+    dump_hex(&response);
+
+    let payload: [u8; 4] = [response[0], response[1], response[2], response[3], ];
+    let min_stack = u32::from_be_bytes(payload);
+    let max_stack = (&response as *const interchanges::Data) as u32;
+
+    // Last checked:
+    // Burden: 43744 bytes with Large apdu for Command & Response, not returning Data.
+    // Burden: 14008 bytes (release), apps replying into a buffer owned by the dispatcher.
+    println!("Burden: {} bytes", max_stack - min_stack);
 
     // Uncomment to see stack burden printed out
     // assert!(false);
 
-}
\ No newline at end of file
+}
+#[test]
+fn aid_display() {
+    use apdu_dispatch::dispatch::AidDisplay;
+
+    // U2F
+    let aid = iso7816::Aid::try_from_slice(&[0xA0, 0x00, 0x00, 0x06, 0x47, 0x2F, 0x00, 0x01]).unwrap();
+    assert_eq!(format!("{}", AidDisplay(&aid)), "A0000006472F0001");
+    assert_eq!(format!("{:?}", AidDisplay(&aid)), "A0000006472F0001");
+
+    let aid = iso7816::Aid::new();
+    assert_eq!(format!("{}", AidDisplay(&aid)), "");
+}
+
+#[test]
+#[serial]
+fn normalizer(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (_contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app1 = TestApp1{};
+    let mut app2 = TestApp2{};
+
+    // Select 1, from a reader that counts the Lc byte itself in Lc
+    let select: &[u8] = &[0x00u8, 0xA4, 0x04, 0x00, 0x06, 0x0A, 0x01, 0x00, 0x00, 0x01];
+
+    let response = apdu_dispatch.process_raw(dispatch::Interface::Contact, select, &mut [&mut app1, &mut app2]).unwrap();
+    assert_ne!(response.as_slice(), &[0x90, 0x00]);
+
+    apdu_dispatch.set_normalizer(Box::leak(Box::new(|apdu: &mut heapless::Vec<u8, interchanges::Size>| {
+        if apdu.len() > 5 && apdu[4] as usize == apdu.len() - 4 {
+            apdu[4] -= 1;
+        }
+    })));
+    let response = apdu_dispatch.process_raw(dispatch::Interface::Contact, select, &mut [&mut app1, &mut app2]).unwrap();
+    assert_eq!(response.as_slice(), &[0x90, 0x00]);
+
+    // the app is selected and receives commands, well-formed ones pass unchanged
+    let response = apdu_dispatch.process_raw(dispatch::Interface::Contact, &[0x00u8, 0x10, 0x00, 0x00, 0x01, 0xAA, 0x00], &mut [&mut app1, &mut app2]).unwrap();
+    assert_eq!(response.as_slice(), &[0x00, 0x00, 0x00, 0x00, 0x00, 0xAA, 0x90, 0x00]);
+}
+
+#[test]
+#[serial]
+fn snapshot_restore(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (_contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app1 = TestApp1{};
+    let mut app2 = TestApp2{};
+    let mut process = |apdu_dispatch: &mut apdu_dispatch::dispatch::ApduDispatch, apdu: &[u8]| {
+        apdu_dispatch.process_raw(dispatch::Interface::Contact, apdu, &mut [&mut app1, &mut app2]).unwrap()
+    };
+
+    // Select 1
+    let response = process(&mut apdu_dispatch, &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01]);
+    assert_eq!(response.as_slice(), &[0x90, 0x00]);
+
+    // first part of a chain, reporting whether it was chained
+    let response = process(&mut apdu_dispatch, &[0x10u8, 0x16, 0x00, 0x00, 0x02, 0xAA, 0xBB]);
+    assert_eq!(response.as_slice(), &[0x90, 0x00]);
+    let mid_chain = apdu_dispatch.snapshot();
+
+    let last_part: &[u8] = &[0x00u8, 0x16, 0x00, 0x00, 0x01, 0xCC];
+    let response = process(&mut apdu_dispatch, last_part);
+    assert_eq!(response.as_slice(), &[0x01, 0x90, 0x00]);
+
+    // the chain is done, the same command now stands alone
+    let response = process(&mut apdu_dispatch, last_part);
+    assert_eq!(response.as_slice(), &[0x00, 0x90, 0x00]);
+    assert_ne!(apdu_dispatch.snapshot(), mid_chain);
+
+    // back in the middle of the chain
+    apdu_dispatch.restore(&mid_chain);
+    assert_eq!(apdu_dispatch.snapshot(), mid_chain);
+    let response = process(&mut apdu_dispatch, last_part);
+    assert_eq!(response.as_slice(), &[0x01, 0x90, 0x00]);
+}
+
+#[test]
+#[serial]
+fn select_fci(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (_contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app1 = TestApp1{};
+    let mut long = LongAidApp{};
+
+    // Select by RID only
+    let select: &[u8] = &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0xA0, 0x00, 0x00, 0x06, 0x47];
+    let fci: &[u8] = &[0x6F, 0x0A, 0x84, 0x08, 0xA0, 0x00, 0x00, 0x06, 0x47, 0x2F, 0x00, 0x01, 0x90, 0x00];
+
+    // only if right-truncated AIDs are opted into
+    let response = apdu_dispatch.process_raw(dispatch::Interface::Contact, select, &mut [&mut app1, &mut long]).unwrap();
+    assert_eq!(response.as_slice(), &[0x6A, 0x82]);
+
+    apdu_dispatch.set_match_policy(dispatch::MatchPolicy::RightTruncated);
+    let response = apdu_dispatch.process_raw(dispatch::Interface::Contact, select, &mut [&mut app1, &mut long]).unwrap();
+    assert_eq!(response.as_slice(), &[0x90, 0x00]);
+
+    apdu_dispatch.set_select_fci(true);
+    let response = apdu_dispatch.process_raw(dispatch::Interface::Contact, select, &mut [&mut app1, &mut long]).unwrap();
+    assert_eq!(response.as_slice(), fci);
+
+    // shorter than the RID is not enough
+    let response = apdu_dispatch.process_raw(dispatch::Interface::Contact,
+        &[0x00u8, 0xA4, 0x04, 0x00, 0x04, 0xA0, 0x00, 0x00, 0x06], &mut [&mut app1, &mut long]).unwrap();
+    assert_eq!(response.as_slice(), &[0x6A, 0x82]);
+
+    // Select by full AID
+    let response = apdu_dispatch.process_raw(dispatch::Interface::Contact,
+        &[0x00u8, 0xA4, 0x04, 0x00, 0x08, 0xA0, 0x00, 0x00, 0x06, 0x47, 0x2F, 0x00, 0x01], &mut [&mut app1, &mut long]).unwrap();
+    assert_eq!(response.as_slice(), fci);
+}
+
+#[test]
+#[serial]
+fn reply_buffer_reused(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (_contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app1 = TestApp1{};
+    let mut process = |apdu_dispatch: &mut apdu_dispatch::dispatch::ApduDispatch, apdu: &[u8]| {
+        apdu_dispatch.process_raw(dispatch::Interface::Contact, apdu, &mut [&mut app1]).unwrap()
+    };
+
+    // Select 1
+    let response = process(&mut apdu_dispatch, &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01]);
+    assert_eq!(response.as_slice(), &[0x90, 0x00]);
+
+    // Echo
+    let response = process(&mut apdu_dispatch, &[0x00u8, 0x10, 0x00, 0x00, 0x03, 0xAA, 0xBB, 0xCC]);
+    assert_eq!(response.as_slice(), &[0x00, 0x00, 0x00, 0x00, 0x00, 0xAA, 0xBB, 0xCC, 0x90, 0x00]);
+
+    // a shorter reply, nothing of the echo is left
+    let response = process(&mut apdu_dispatch, &[0x00u8, 0x16, 0x00, 0x00]);
+    assert_eq!(response.as_slice(), &[0x00, 0x90, 0x00]);
+
+    // an error, and no data
+    let response = process(&mut apdu_dispatch, &[0x00u8, 0x7F, 0x00, 0x00]);
+    assert_eq!(response.as_slice(), &[0x6D, 0x00]);
+
+    // re-SELECT
+    let response = process(&mut apdu_dispatch, &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01]);
+    assert_eq!(response.as_slice(), &[0x90, 0x00]);
+}
+
+#[test]
+#[serial]
+fn exact_match(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (_contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app1 = TestApp1{};
+    let mut overlapping1 = OverlappingApp1{};
+    let mut overlapping2 = OverlappingApp2{};
+    let mut long = LongAidApp{};
+    let mut process = |apdu_dispatch: &mut apdu_dispatch::dispatch::ApduDispatch, apdu: &[u8]| {
+        apdu_dispatch.process_raw(dispatch::Interface::Contact, apdu, &mut [&mut app1, &mut overlapping1, &mut overlapping2, &mut long]).unwrap()
+    };
+
+    // Select 1, with a PIX appended
+    let select_pix: &[u8] = &[0x00u8, 0xA4, 0x04, 0x00, 0x06, 0x0A, 0x01, 0x00, 0x00, 0x01, 0xFF];
+    // Select the second overlapping app, which the first one's AID is a prefix of
+    let select_overlapping: &[u8] = &[0x00u8, 0xA4, 0x04, 0x00, 0x06, 0x0A, 0x01, 0x00, 0x00, 0x06, 0x01];
+    // Select the app with the long AID by its RID
+    let select_rid: &[u8] = &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0xA0, 0x00, 0x00, 0x06, 0x47];
+
+    assert_eq!(process(&mut apdu_dispatch, select_pix).as_slice(), &[0x90, 0x00]);
+    assert_eq!(process(&mut apdu_dispatch, select_overlapping).as_slice(), &[0x01, 0x90, 0x00]);
+    assert_eq!(process(&mut apdu_dispatch, select_rid).as_slice(), &[0x6A, 0x82]);
+
+    apdu_dispatch.set_match_policy(dispatch::MatchPolicy::RightTruncated);
+    assert_eq!(process(&mut apdu_dispatch, select_pix).as_slice(), &[0x90, 0x00]);
+    assert_eq!(process(&mut apdu_dispatch, select_overlapping).as_slice(), &[0x01, 0x90, 0x00]);
+    assert_eq!(process(&mut apdu_dispatch, select_rid).as_slice(), &[0x90, 0x00]);
+
+    apdu_dispatch.set_match_policy(dispatch::MatchPolicy::Exact);
+    assert_eq!(process(&mut apdu_dispatch, select_pix).as_slice(), &[0x6A, 0x82]);
+    assert_eq!(process(&mut apdu_dispatch, select_overlapping).as_slice(), &[0x02, 0x90, 0x00]);
+    assert_eq!(process(&mut apdu_dispatch, select_rid).as_slice(), &[0x6A, 0x82]);
+
+    // Select 1
+    let response = process(&mut apdu_dispatch, &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01]);
+    assert_eq!(response.as_slice(), &[0x90, 0x00]);
+}
+
+#[test]
+#[serial]
+fn poll_errors(){
+    use apdu_dispatch::dispatch::DispatchError;
+
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (mut contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app1 = TestApp1{};
+    let mut app3 = TestApp3{};
+    let mut picky = PickyApp{};
+    let mut poll = |apdu_dispatch: &mut apdu_dispatch::dispatch::ApduDispatch, apdu: &[u8]| {
+        contact_requester.request(&interchanges::Data::try_from_slice(apdu).unwrap())
+            .expect("could not deposit command");
+        let result = apdu_dispatch.poll(&mut [&mut app1, &mut app3, &mut picky]);
+        assert_eq!(result.responded, Some(dispatch::Interface::Contact));
+        let response = contact_requester.take_response().unwrap();
+        (response.as_slice().to_vec(), result.error)
+    };
+
+    // Echo, with no app selected
+    assert_eq!(poll(&mut apdu_dispatch, &[0x00u8, 0x10, 0x00, 0x00]), (vec![0x6A, 0x82], Some(DispatchError::NoAppSelected)));
+    // Unknown AID
+    assert_eq!(
+        poll(&mut apdu_dispatch, &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x0F]),
+        (vec![0x6A, 0x82], Some(DispatchError::UnknownAid)),
+    );
+    // Nothing to get
+    assert_eq!(
+        poll(&mut apdu_dispatch, &[0x00u8, 0xC0, 0x00, 0x00]),
+        (vec![0x69, 0x85], Some(DispatchError::UnexpectedGetResponse)),
+    );
+
+    // Select 1, which succeeds without error
+    let select1: &[u8] = &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01];
+    assert_eq!(poll(&mut apdu_dispatch, select1), (vec![0x90, 0x00], None));
+    // Not an APDU
+    let (_, error) = poll(&mut apdu_dispatch, &[0x00u8, 0x10, 0x00]);
+    assert_eq!(error, Some(DispatchError::Malformed));
+    // Echo on logical channel 1
+    assert_eq!(
+        poll(&mut apdu_dispatch, &[0x01u8, 0x10, 0x00, 0x00]),
+        (vec![0x68, 0x81], Some(DispatchError::ClassNotSupported(Status::LogicalChannelNotSupported))),
+    );
+    // An error of the app itself is not the dispatcher's
+    assert_eq!(poll(&mut apdu_dispatch, &[0x00u8, 0x11, 0x00, 0x00]), (vec![0x6D, 0x00], None));
+
+    // A chain longer than the command buffer
+    let mut part = vec![0x10u8, 0x10, 0x00, 0x00, 0xFF];
+    part.extend_from_slice(&[0x42; 0xFF]);
+    for _ in 0..(command::SIZE / 0xFF) {
+        assert_eq!(poll(&mut apdu_dispatch, &part), (vec![0x90, 0x00], None));
+    }
+    assert_eq!(poll(&mut apdu_dispatch, &part), (vec![0x6A, 0x84], Some(DispatchError::ChainTooLong)));
+    // the rest of the chain is dropped, but reported only once
+    assert_eq!(poll(&mut apdu_dispatch, &part), (vec![0x6A, 0x84], None));
+    // the next command is a fresh one
+    assert_eq!(poll(&mut apdu_dispatch, &[0x00u8, 0x10, 0x00, 0x00]), (vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x90, 0x00], None));
+
+    // Declined upfront
+    assert_eq!(poll(&mut apdu_dispatch, &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x09]), (vec![0x90, 0x00], None));
+    assert_eq!(
+        poll(&mut apdu_dispatch, &[0x00u8, 0x50, 0x00, 0x00]),
+        (vec![0x6D, 0x00], Some(DispatchError::Declined)),
+    );
+
+    // Response does not fit, and is not chained
+    assert_eq!(poll(&mut apdu_dispatch, &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x04]), (vec![0x90, 0x00], None));
+    assert_eq!(
+        poll(&mut apdu_dispatch, &[0x00u8, 0x30, 0x00, 0x00]),
+        (vec![0x67, 0x00], Some(DispatchError::ResponseTooLong)),
+    );
+}
+
+#[test]
+#[serial]
+fn debug_state(){
+    use apdu_dispatch::dispatch::BufferState;
+
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (_contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app2 = TestApp2{};
+    let mut process = |apdu_dispatch: &mut apdu_dispatch::dispatch::ApduDispatch, apdu: &[u8]| {
+        apdu_dispatch.process_raw(dispatch::Interface::Contactless, apdu, &mut [&mut app2]).unwrap()
+    };
+
+    let state = apdu_dispatch.debug_state();
+    assert!(state.selected_aid.is_none());
+    assert_eq!(state.buffer, BufferState::Empty);
+
+    // Select 2
+    process(&mut apdu_dispatch, &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x02]);
+    let state = apdu_dispatch.debug_state();
+    assert_eq!(format!("{:?}", state.selected_aid.unwrap()), "0A01000002");
+    assert_eq!(state.interface, dispatch::Interface::Contactless);
+    assert_eq!(state.buffer, BufferState::Empty);
+
+    // a chain, for the long fibonacci response
+    process(&mut apdu_dispatch, &[0x10u8, 0x30, 0x00, 0x00, 0x02, 0x01, 0x02]);
+    assert_eq!(apdu_dispatch.debug_state().buffer, BufferState::BufferingRequest(2));
+    process(&mut apdu_dispatch, &[0x10u8, 0x30, 0x00, 0x00, 0x03, 0x03, 0x04, 0x05]);
+    assert_eq!(apdu_dispatch.debug_state().buffer, BufferState::BufferingRequest(5));
+
+    // the first 256 bytes are sent right away
+    process(&mut apdu_dispatch, &[0x00u8, 0x30, 0x00, 0x00, 0x01, 0x06]);
+    let state = apdu_dispatch.debug_state();
+    assert_eq!(state.buffer, BufferState::BufferedResponse(2048 - 256));
+    assert!(state.was_request_chained);
+}
+
+#[test]
+#[serial]
+fn chain_overflow(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (_contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app1 = TestApp1{};
+    let mut process = |apdu_dispatch: &mut apdu_dispatch::dispatch::ApduDispatch, apdu: &[u8]| {
+        apdu_dispatch.process_raw(dispatch::Interface::Contact, apdu, &mut [&mut app1]).unwrap()
+    };
+
+    // Select 1
+    assert_eq!(process(&mut apdu_dispatch, &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01]).as_slice(), &[0x90, 0x00]);
+
+    // Echo, chained in parts of 255 bytes
+    let mut part = vec![0x10u8, 0x10, 0x00, 0x00, 0xFF];
+    part.extend_from_slice(&[0x42; 0xFF]);
+    let mut last_part = part.clone();
+    last_part[0] = 0x00;
+    let parts_that_fit = command::SIZE / 0xFF;
+    let echo: &[u8] = &[0x00u8, 0x10, 0x00, 0x00, 0x01, 0x01];
+    let echoed: &[u8] = &[0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x90, 0x00];
+
+    // the part that overflows is rejected, and so is the rest of the chain,
+    // instead of passing a truncated command to the app
+    for _ in 0..parts_that_fit {
+        assert_eq!(process(&mut apdu_dispatch, &part).as_slice(), &[0x90, 0x00]);
+    }
+    for _ in 0..3 {
+        assert_eq!(process(&mut apdu_dispatch, &part).as_slice(), &[0x6A, 0x84]);
+    }
+    // the first command without the chaining bit is not part of the dropped chain
+    let mut last_part_echoed = vec![0x00u8; 5];
+    last_part_echoed.extend_from_slice(&[0x42; 0xFF]);
+    last_part_echoed.extend_from_slice(&[0x90, 0x00]);
+    assert_eq!(process(&mut apdu_dispatch, &last_part).as_slice(), last_part_echoed.as_slice());
+    assert_eq!(process(&mut apdu_dispatch, echo).as_slice(), echoed);
+
+    // the last part overflows
+    for _ in 0..parts_that_fit {
+        assert_eq!(process(&mut apdu_dispatch, &part).as_slice(), &[0x90, 0x00]);
+    }
+    assert_eq!(process(&mut apdu_dispatch, &last_part).as_slice(), &[0x6A, 0x84]);
+    assert_eq!(process(&mut apdu_dispatch, echo).as_slice(), echoed);
+}
+
+#[test]
+#[serial]
+fn select_file(){
+    use apdu_dispatch::dispatch::DispatchError;
+
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (mut contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app1 = TestApp1{};
+    let mut files = FileApp{};
+    let mut poll = |apdu_dispatch: &mut apdu_dispatch::dispatch::ApduDispatch, apdu: &[u8]| {
+        contact_requester.request(&interchanges::Data::try_from_slice(apdu).unwrap())
+            .expect("could not deposit command");
+        let error = apdu_dispatch.poll(&mut [&mut app1, &mut files]).error;
+        (contact_requester.take_response().unwrap().as_slice().to_vec(), error)
+    };
+
+    // by file identifier, and by path from the MF
+    let select_ef: &[u8] = &[0x00u8, 0xA4, 0x00, 0x0C, 0x02, 0xE1, 0x04];
+    let select_path: &[u8] = &[0x00u8, 0xA4, 0x08, 0x0C, 0x04, 0x3F, 0x00, 0xE1, 0x04];
+
+    // no app selected
+    assert_eq!(poll(&mut apdu_dispatch, select_ef), (vec![0x6A, 0x82], Some(DispatchError::NoAppSelected)));
+
+    // by AID, the dispatcher selects the app
+    assert_eq!(poll(&mut apdu_dispatch, &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x0A]), (vec![0x90, 0x00], None));
+
+    // files are selected by the app
+    assert_eq!(poll(&mut apdu_dispatch, select_ef), (vec![0x00, 0xE1, 0x04, 0x90, 0x00], None));
+    assert_eq!(poll(&mut apdu_dispatch, select_path), (vec![0x08, 0x3F, 0x00, 0xE1, 0x04, 0x90, 0x00], None));
+    assert_eq!(poll(&mut apdu_dispatch, &[0x00u8, 0xA4, 0x09, 0x0C, 0x02, 0xE1, 0x04]), (vec![0x09, 0xE1, 0x04, 0x90, 0x00], None));
+    assert_eq!(poll(&mut apdu_dispatch, &[0x00u8, 0xA4, 0x03, 0x0C]), (vec![0x03, 0x90, 0x00], None));
+
+    // an app without files
+    assert_eq!(poll(&mut apdu_dispatch, &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01]), (vec![0x90, 0x00], None));
+    for apdu in [select_ef, select_path].iter() {
+        assert_eq!(poll(&mut apdu_dispatch, apdu), (vec![0x6A, 0x81], Some(DispatchError::FileSelectionNotSupported)));
+    }
+    // which stays selected
+    assert_eq!(
+        poll(&mut apdu_dispatch, &[0x00u8, 0x10, 0x00, 0x00, 0x01, 0x01]),
+        (vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x90, 0x00], None),
+    );
+}
+
+#[test]
+#[serial]
+fn abort_request(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (mut contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app0 = PanicApp{};
+    let mut app1 = TestApp1{};
+
+    // Select the panic app
+    contact_requester.request(&interchanges::Data::try_from_slice(
+        &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x03],
+    ).unwrap()).expect("could not deposit command");
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        apdu_dispatch.poll(&mut [&mut app0, &mut app1]);
+    }));
+    assert!(panicked.is_err());
+    assert!(contact_requester.take_response().is_none());
+
+    apdu_dispatch.abort_request(Status::UnspecifiedNonpersistentExecutionError);
+    assert_eq!(contact_requester.take_response().unwrap().as_slice(), &[0x64, 0x00]);
+
+    // Select 1, without deselecting the panic app
+    contact_requester.request(&interchanges::Data::try_from_slice(
+        &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+    ).unwrap()).expect("could not deposit command");
+    apdu_dispatch.poll(&mut [&mut app0, &mut app1]);
+    assert_eq!(contact_requester.take_response().unwrap().as_slice(), &[0x90, 0x00]);
+}
+
+#[test]
+#[serial]
+fn deselect_from_interface(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (_contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app = InterfaceApp{ deselected_from: None };
+    let mut app2 = TestApp2{};
+    let select = &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x0B];
+    let select_2 = &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x02];
+
+    for &interface in &[dispatch::Interface::Contactless, dispatch::Interface::Contact] {
+        let response = apdu_dispatch.process_raw(interface, select, &mut [&mut app, &mut app2]).unwrap();
+        assert_eq!(response.as_slice(), &[0x90, 0x00]);
+        let response = apdu_dispatch.process_raw(interface, select_2, &mut [&mut app, &mut app2]).unwrap();
+        assert_eq!(response.as_slice(), &[0x90, 0x00]);
+        assert_eq!(app.deselected_from.take(), Some(interface));
+    }
+}
+
+#[test]
+#[serial]
+fn pending_response_rounds(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (_contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app1 = TestApp1{};
+    let mut process = |apdu_dispatch: &mut apdu_dispatch::dispatch::ApduDispatch, apdu: &[u8]| {
+        apdu_dispatch.process_raw(dispatch::Interface::Contact, apdu, &mut [&mut app1]).unwrap()
+    };
+
+    assert_eq!(apdu_dispatch.pending_response_rounds(), 0);
+    process(&mut apdu_dispatch, &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01]);
+    assert_eq!(apdu_dispatch.pending_response_rounds(), 0);
+
+    // echo 595 bytes in a chain, for a 600 byte response: 256 + 256 + 88
+    for (class, length) in &[(0x10u8, 255u8), (0x10, 255), (0x00, 85)] {
+        let mut apdu = vec![*class, 0x10, 0x00, 0x00, *length];
+        apdu.resize(5 + *length as usize, 0x55);
+        let response = process(&mut apdu_dispatch, &apdu);
+        if *class == 0x10 {
+            assert_eq!(response.as_slice(), &[0x90, 0x00]);
+            assert_eq!(apdu_dispatch.pending_response_rounds(), 0);
+        } else {
+            assert_eq!(response.len(), 256 + 2);
+            assert_eq!(&response[256..], &[0x61, 0x00]);
+        }
+    }
+
+    // the first chunk went out with the reply to the last command
+    assert_eq!(apdu_dispatch.pending_response_rounds(), 2);
+    let response = process(&mut apdu_dispatch, &[0x00u8, 0xC0, 0x00, 0x00, 0x00]);
+    assert_eq!(&response[256..], &[0x61, 88]);
+    assert_eq!(apdu_dispatch.pending_response_rounds(), 1);
+    let response = process(&mut apdu_dispatch, &[0x00u8, 0xC0, 0x00, 0x00, 0x00]);
+    assert_eq!(response.len(), 88 + 2);
+    assert_eq!(&response[88..], &[0x90, 0x00]);
+    assert_eq!(apdu_dispatch.pending_response_rounds(), 0);
+}
+
+#[test]
+#[serial]
+fn success_with_warning(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (_contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app = WarningApp{};
+    let mut process = |apdu_dispatch: &mut apdu_dispatch::dispatch::ApduDispatch, apdu: &[u8]| {
+        apdu_dispatch.process_raw(dispatch::Interface::Contact, apdu, &mut [&mut app]).unwrap()
+    };
+
+    // apps that only implement `call` keep answering 9000, e.g. on select
+    let response = process(&mut apdu_dispatch, &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x0C]);
+    assert_eq!(response.as_slice(), &[0x90, 0x00]);
+
+    let response = process(&mut apdu_dispatch, &[0x00u8, 0x01, 0x02, 0x00]);
+    assert_eq!(response.as_slice(), &[0x55, 0x55, 0x62, 0x81]);
+
+    // a chained response ends with the warning, after 61XX for the parts before
+    process(&mut apdu_dispatch, &[0x10u8, 0x01, 0x00, 0x00, 0x01, 0x00]);
+    let response = process(&mut apdu_dispatch, &[0x00u8, 0x01, 0x00, 0x00, 0x01, 0x00]);
+    assert_eq!(response.len(), 256 + 2);
+    assert_eq!(&response[256..], &[0x61, 44]);
+    let response = process(&mut apdu_dispatch, &[0x00u8, 0xC0, 0x00, 0x00, 0x00]);
+    assert_eq!(response.len(), 44 + 2);
+    assert_eq!(&response[44..], &[0x62, 0x81]);
+}
+
+#[test]
+#[serial]
+fn command_body(){
+    run_apdus(
+        &[
+            // Select 1
+            &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+            &[0x90, 0x00],
+
+            // Header only
+            &[0x00u8, 0x18, 0x00, 0x00],
+            &[0x00, 0x90, 0x00],
+
+            // No data, but Le
+            &[0x00u8, 0x18, 0x00, 0x00, 0x00],
+            &[0x01, 0x90, 0x00],
+            &[0x00u8, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00],
+            &[0x01, 0x90, 0x00],
+
+            // Data
+            &[0x00u8, 0x18, 0x00, 0x00, 0x01, 0x01],
+            &[0x01, 0x90, 0x00],
+        ]
+    )
+}
+
+#[test]
+#[serial]
+fn response_chain_lock(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (mut contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (mut contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    apdu_dispatch.set_response_chain_lock(true);
+    let mut app1 = TestApp1{};
+    let select = interchanges::Data::try_from_slice(
+        &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+    ).unwrap();
+
+    contact_requester.request(&select).expect("could not deposit command");
+    apdu_dispatch.poll(&mut[&mut app1]);
+    assert_eq!(contact_requester.take_response().unwrap().as_slice(), &[0x90, 0x00]);
+
+    // echo 295 bytes in a chain, for a 300 byte response: 256 + 44
+    for (class, length) in &[(0x10u8, 255u8), (0x00, 40)] {
+        let mut apdu = vec![*class, 0x10, 0x00, 0x00, *length];
+        apdu.resize(5 + *length as usize, 0x55);
+        contact_requester.request(&interchanges::Data::try_from_slice(&apdu).unwrap()).unwrap();
+        apdu_dispatch.poll(&mut[&mut app1]);
+        let response = contact_requester.take_response().unwrap();
+        if *class == 0x10 {
+            assert_eq!(response.as_slice(), &[0x90, 0x00]);
+        } else {
+            assert_eq!(&response[256..], &[0x61, 44]);
+        }
+    }
+
+    // contactless waits while the contact reader has not fetched the rest
+    contactless_requester.request(&select).expect("could not deposit command");
+    apdu_dispatch.poll(&mut[&mut app1]);
+    assert!(contactless_requester.take_response().is_none());
+
+    contact_requester.request(&interchanges::Data::try_from_slice(&[0x00, 0xC0, 0x00, 0x00, 0x00]).unwrap()).unwrap();
+    apdu_dispatch.poll(&mut[&mut app1]);
+    assert!(contactless_requester.take_response().is_none());
+    let response = contact_requester.take_response().unwrap();
+    assert_eq!(response.len(), 44 + 2);
+    assert_eq!(&response[44..], &[0x90, 0x00]);
+
+    // the chain is done
+    apdu_dispatch.poll(&mut[&mut app1]);
+    assert_eq!(contactless_requester.take_response().unwrap().as_slice(), &[0x90, 0x00]);
+}
+
+#[test]
+#[serial]
+fn selection_per_interface(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (_contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app = InterfaceApp{ deselected_from: None };
+    let mut app2 = TestApp2{};
+    let mut process = |apdu_dispatch: &mut apdu_dispatch::dispatch::ApduDispatch, interface, apdu: &[u8]| {
+        apdu_dispatch.process_raw(interface, apdu, &mut [&mut app, &mut app2]).unwrap()
+    };
+    let contact = dispatch::Interface::Contact;
+    let contactless = dispatch::Interface::Contactless;
+    let select = &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x0B];
+    let select_2 = &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x02];
+    let command = &[0x00u8, 0x20, 0x00, 0x00, 0x01, 0x77];
+
+    // selected over contact only
+    assert_eq!(process(&mut apdu_dispatch, contact, select).as_slice(), &[0x90, 0x00]);
+    assert_eq!(process(&mut apdu_dispatch, contactless, command).as_slice(), &[0x6A, 0x82]);
+    assert_eq!(process(&mut apdu_dispatch, contact, command).as_slice(), &[0x90, 0x00]);
+
+    // a selection over contactless leaves the one over contact alone
+    assert_eq!(process(&mut apdu_dispatch, contactless, select_2).as_slice(), &[0x90, 0x00]);
+    assert_eq!(process(&mut apdu_dispatch, contactless, command).as_slice(), &[0, 0, 0, 0, 0, 0x77, 0x90, 0x00]);
+    assert_eq!(process(&mut apdu_dispatch, contact, command).as_slice(), &[0x90, 0x00]);
+
+    // an app selected on both interfaces is only deselected once neither uses it
+    assert_eq!(process(&mut apdu_dispatch, contactless, select).as_slice(), &[0x90, 0x00]);
+    assert_eq!(process(&mut apdu_dispatch, contact, select_2).as_slice(), &[0x90, 0x00]);
+    assert_eq!(app.deselected_from, None);
+    let response = apdu_dispatch.process_raw(contactless, select_2, &mut [&mut app, &mut app2]).unwrap();
+    assert_eq!(response.as_slice(), &[0x90, 0x00]);
+    assert_eq!(app.deselected_from, Some(contactless));
+}
+
+#[test]
+#[serial]
+fn get_response_per_interface(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (_contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app2 = TestApp2{};
+    let mut process = |apdu_dispatch: &mut apdu_dispatch::dispatch::ApduDispatch, interface, apdu: &[u8]| {
+        apdu_dispatch.process_raw(interface, apdu, &mut [&mut app2]).unwrap()
+    };
+    let contact = dispatch::Interface::Contact;
+    let contactless = dispatch::Interface::Contactless;
+    let select_2 = &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x02];
+    let get_response = &[0x00u8, 0xC0, 0x00, 0x00];
+
+    let mut fibonacci = vec![0u8, 1, 1];
+    for i in 3..2048 {
+        fibonacci.push(fibonacci[i - 1].wrapping_add(fibonacci[i - 2]));
+    }
+
+    // a long response over contact
+    assert_eq!(process(&mut apdu_dispatch, contact, select_2).as_slice(), &[0x90, 0x00]);
+    assert_eq!(process(&mut apdu_dispatch, contact, &[0x10u8, 0x30, 0x00, 0x00, 0x01, 0x01]).as_slice(), &[0x90, 0x00]);
+    let response = process(&mut apdu_dispatch, contact, &[0x00u8, 0x30, 0x00, 0x00, 0x01, 0x01]);
+    assert_eq!(&response[..256], &fibonacci[..256]);
+    assert_eq!(&response[256..], &[0x61, 0x00]);
+
+    // is not for contactless to take, and stays for contact
+    assert_eq!(process(&mut apdu_dispatch, contactless, get_response).as_slice(), &[0x69, 0x85]);
+    let response = process(&mut apdu_dispatch, contact, get_response);
+    assert_eq!(&response[..256], &fibonacci[256..512]);
+    assert_eq!(&response[256..], &[0x61, 0x00]);
+    assert_eq!(apdu_dispatch.pending_response_rounds(), 6);
+}
+
+#[test]
+#[serial]
+fn chain_per_interface(){
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (_contact_requester, contact_responder) = interchanges::Contact::claim()
+        .expect("could not setup ccid ApduInterchange");
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim()
+        .expect("could not setup iso14443 ApduInterchange");
+
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    let mut app1 = TestApp1{};
+    let mut process = |apdu_dispatch: &mut apdu_dispatch::dispatch::ApduDispatch, interface, apdu: &[u8]| {
+        apdu_dispatch.process_raw(interface, apdu, &mut [&mut app1]).unwrap()
+    };
+    let contact = dispatch::Interface::Contact;
+    let contactless = dispatch::Interface::Contactless;
+    let select_1 = &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01];
+    let part = &[0x10u8, 0x10, 0x00, 0x00, 0x02, 0xAA, 0xBB];
+    let last_part = &[0x00u8, 0x10, 0x00, 0x00, 0x01, 0xCC];
+    let echo = &[0x00u8, 0x10, 0x00, 0x00, 0x01, 0x01];
+
+    assert_eq!(process(&mut apdu_dispatch, contact, select_1).as_slice(), &[0x90, 0x00]);
+    assert_eq!(process(&mut apdu_dispatch, contactless, select_1).as_slice(), &[0x90, 0x00]);
+
+    // a command over contactless is not appended to the chain over contact
+    assert_eq!(process(&mut apdu_dispatch, contact, part).as_slice(), &[0x90, 0x00]);
+    assert_eq!(process(&mut apdu_dispatch, contactless, echo).as_slice(), &[0, 0, 0, 0, 0, 0x01, 0x90, 0x00]);
+
+    // the interrupted chain is dropped, its last part is a fresh command
+    assert_eq!(process(&mut apdu_dispatch, contact, part).as_slice(), &[0x69, 0x85]);
+    assert_eq!(process(&mut apdu_dispatch, contact, last_part).as_slice(), &[0, 0, 0, 0, 0, 0xCC, 0x90, 0x00]);
+
+    // and contactless is not affected by it
+    assert_eq!(process(&mut apdu_dispatch, contactless, part).as_slice(), &[0x90, 0x00]);
+    assert_eq!(process(&mut apdu_dispatch, contactless, last_part).as_slice(), &[0, 0, 0, 0, 0, 0xAA, 0xBB, 0xCC, 0x90, 0x00]);
+}
+
+#[test]
+#[serial]
+fn reader_select_command_chain(){
+    let mut reader = Reader::new();
+    let mut app1 = TestApp1{};
+    let mut app2 = TestApp2{};
+    let select_1 = &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01];
+    let select_2 = &[0x00u8, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x02];
+
+    for &interface in &[dispatch::Interface::Contact, dispatch::Interface::Contactless] {
+        assert_eq!(reader.send(interface, select_2, &mut [&mut app1, &mut app2]), &[0x90, 0x00]);
+        assert_eq!(
+            reader.send(interface, &[0x00, 0x20, 0x00, 0x00, 0x02, 0x77, 0x88], &mut [&mut app1, &mut app2]),
+            &[0, 0, 0, 0, 0, 0x77, 0x88, 0x90, 0x00],
+        );
+
+        // echo 295 bytes in a chain: the 300 byte response takes a GetResponse
+        assert_eq!(reader.send(interface, select_1, &mut [&mut app1, &mut app2]), &[0x90, 0x00]);
+        let mut first = vec![0x10, 0x10, 0x00, 0x00, 0xFF];
+        first.resize(5 + 255, 0x55);
+        assert_eq!(reader.send(interface, &first, &mut [&mut app1, &mut app2]), &[0x90, 0x00]);
+        let mut last = vec![0x00, 0x10, 0x00, 0x00, 40];
+        last.resize(5 + 40, 0x66);
+
+        let response = reader.send(interface, &last, &mut [&mut app1, &mut app2]);
+        assert_eq!(response.len(), 300 + 2);
+        assert_eq!(&response[..5], &[0; 5]);
+        assert!(response[5..260].iter().all(|&byte| byte == 0x55));
+        assert!(response[260..300].iter().all(|&byte| byte == 0x66));
+        assert_eq!(&response[300..], &[0x90, 0x00]);
+    }
+}
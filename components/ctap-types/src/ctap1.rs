@@ -3,6 +3,8 @@ use iso7816::{
     Instruction,
 };
 
+use core::convert::TryFrom;
+
 use crate::{Bytes, consts};
 
 pub const NO_ERROR: u16 = 0x9000;
@@ -12,31 +14,79 @@ pub use iso7816::Status as Error;
 #[repr(u8)]
 #[derive(Copy,Clone,Debug, Eq,PartialEq)]
 pub enum ControlByte {
-	// Conor:
-    // I think U2F check-only maps to FIDO2 MakeCredential with the credID in the excludeList,
-    // and pinAuth="" so the request will fail before UP check.
-    // I  think this is what the windows hello API does to silently check if a credential is
-	// on an authenticator
+    /// Only check whether the key handle was created by this authenticator, without signing.
+    /// A known key handle is signaled with `ConditionsOfUseNotSatisfied`
+    /// (the spec's "test-of-user-presence required"), an unknown one with `IncorrectDataParameter`.
+    ///
+    /// See `Authenticate::check_only`.
     CheckOnly = 0x07,
+    /// Check user presence, then sign. The response's user presence byte is `0x01`.
     EnforceUserPresenceAndSign = 0x03,
+    /// Sign without checking user presence. The response's user presence byte is `0x00`.
     DontEnforceUserPresenceAndSign = 0x08,
 }
 
+impl ControlByte {
+    /// Whether user presence must be verified before signing.
+    pub fn enforces_user_presence(self) -> bool {
+        self == ControlByte::EnforceUserPresenceAndSign
+    }
+
+    /// Whether this only probes for the key handle, and no signature is made.
+    pub fn is_check_only(self) -> bool {
+        self == ControlByte::CheckOnly
+    }
+}
+
 impl core::convert::TryFrom<u8> for ControlByte {
-    type Error = Error;
+    type Error = CommandError;
 
-    fn try_from(byte: u8) -> Result<ControlByte> {
+    fn try_from(byte: u8) -> core::result::Result<ControlByte, CommandError> {
         match byte {
             0x07 => Ok(ControlByte::CheckOnly),
             0x03 => Ok(ControlByte::EnforceUserPresenceAndSign),
             0x08 => Ok(ControlByte::DontEnforceUserPresenceAndSign),
-            _ => Err(Error::IncorrectDataParameter),
+            _ => Err(CommandError::InvalidControlByte),
         }
     }
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// Why an APDU is not a valid U2F command.
+///
+/// Converts into the status word to answer it with.
+#[derive(Copy,Clone,Debug, Eq,PartialEq)]
+pub enum CommandError {
+    /// Only CLA 0 is defined.
+    ClassNotSupported,
+    UnsupportedInstruction,
+    /// P1 of an authenticate is not one of the `ControlByte`s.
+    InvalidControlByte,
+    /// The request data is too short or too long for the instruction.
+    BadLength,
+    /// The key handle length byte does not match the remaining request data.
+    KeyHandleLengthMismatch,
+    /// Key handles are at most 255 bytes.
+    KeyHandleTooLong,
+    /// Le asks for less than the version string, the reader should resend with Le = 6.
+    VersionLe,
+}
+
+impl From<CommandError> for Error {
+    fn from(error: CommandError) -> Self {
+        match error {
+            CommandError::ClassNotSupported => Error::ClassNotSupported,
+            CommandError::UnsupportedInstruction => Error::InstructionNotSupportedOrInvalid,
+            CommandError::InvalidControlByte => Error::IncorrectDataParameter,
+            CommandError::BadLength => Error::IncorrectDataParameter,
+            CommandError::KeyHandleLengthMismatch => Error::IncorrectDataParameter,
+            CommandError::KeyHandleTooLong => Error::WrongLength,
+            CommandError::VersionLe => Error::WrongLe(U2F_VERSION.len() as u8),
+        }
+    }
+}
+
 #[derive(Clone,Debug, Eq,PartialEq)]
 pub struct Register {
     pub challenge: Bytes<consts::U32>,
@@ -60,6 +110,55 @@ pub struct Authenticate {
     pub key_handle: Bytes<consts::U255>,
 }
 
+/// Outcome of a check-only `Authenticate`.
+#[derive(Copy,Clone,Debug, Eq,PartialEq)]
+pub enum KeyHandleCheck {
+    /// The key handle was created by this authenticator, for this application.
+    Mine,
+    NotMine,
+}
+
+impl KeyHandleCheck {
+    /// The status that answers the check-only request.
+    pub fn status(self) -> Error {
+        match self {
+            KeyHandleCheck::Mine => Error::ConditionsOfUseNotSatisfied,
+            KeyHandleCheck::NotMine => Error::IncorrectDataParameter,
+        }
+    }
+}
+
+impl Authenticate {
+    /// For a check-only request, probes whether the key handle is known, `None` otherwise.
+    ///
+    /// This corresponds to a CTAP2 MakeCredential with the key handle as the only
+    /// credential ID in its excludeList, and the application parameter as RP ID hash:
+    /// `exists` is passed these two, and looks up the credential without user presence.
+    pub fn check_only(&self, exists: impl FnOnce(&Bytes<consts::U32>, &[u8]) -> bool) -> Option<KeyHandleCheck> {
+        if !self.control_byte.is_check_only() {
+            return None;
+        }
+        Some(if exists(&self.app_id, &self.key_handle) {
+            KeyHandleCheck::Mine
+        } else {
+            KeyHandleCheck::NotMine
+        })
+    }
+
+    /// Before signing, recovers the private key behind the key handle with `verify`,
+    /// which is passed the application parameter and the key handle, like `check_only`.
+    ///
+    /// The key handle must have been created by this authenticator, for this application.
+    /// Whatever `verify` fails with (foreign or corrupt handle, other application), the
+    /// request is answered `IncorrectDataParameter`, i.e. SW_WRONG_DATA.
+    pub fn verify_key_handle<K, E>(
+        &self,
+        verify: impl FnOnce(&Bytes<consts::U32>, &[u8]) -> core::result::Result<K, E>,
+    ) -> Result<K> {
+        verify(&self.app_id, &self.key_handle).map_err(|_| Error::IncorrectDataParameter)
+    }
+}
+
 #[derive(Clone,Debug, Eq,PartialEq)]
 pub struct AuthenticateResponse {
     user_presence: u8,
@@ -74,6 +173,53 @@ pub enum Command {
     Version,
 }
 
+impl Command {
+    /// The INS byte this command was parsed from.
+    pub fn instruction_byte(&self) -> u8 {
+        match self {
+            Command::Register(_) => 0x01,
+            Command::Authenticate(_) => 0x02,
+            Command::Version => 0x03,
+        }
+    }
+}
+
+/// The instruction and parameter bytes of a U2F request, as received.
+///
+/// Parsing only keeps the parameters a command needs (P1 of `Authenticate`, as its
+/// control byte). This keeps all of them, to log what a reader actually sent.
+#[derive(Copy,Clone,Debug, Eq,PartialEq)]
+pub struct Header {
+    pub instruction: u8,
+    pub p1: u8,
+    pub p2: u8,
+}
+
+impl<SIZE> From<&ApduCommand<SIZE>> for Header
+where SIZE: heapless_bytes::ArrayLength<u8> {
+    fn from(apdu: &ApduCommand<SIZE>) -> Self {
+        Self {
+            instruction: apdu.instruction().into(),
+            p1: apdu.p1,
+            p2: apdu.p2,
+        }
+    }
+}
+
+/// The only version of U2F raw messages, as reported by `Command::Version`.
+pub const U2F_VERSION: [u8; 6] = *b"U2F_V2";
+
+/// How a U2F response reaches the client, which decides who appends the status word.
+#[derive(Copy,Clone,Debug, Eq,PartialEq)]
+pub enum U2fTransport {
+    /// Over CCID or NFC, as the data of an ISO 7816 response: `apdu-dispatch` appends
+    /// the status word.
+    Apdu,
+    /// Inside a U2FHID_MSG, which carries the complete response APDU: the status word
+    /// is part of the message.
+    Hid,
+}
+
 #[derive(Clone,Debug, Eq,PartialEq)]
 pub enum Response {
     Register(RegisterResponse),
@@ -82,6 +228,7 @@ pub enum Response {
 }
 
 impl RegisterResponse {
+    /// Panics if the public key is malformed or the key handle too long, see `try_new`.
     pub fn new(
         header_byte: u8,
         public_key: &crate::cose::EcdhEsHkdf256PublicKey,
@@ -89,11 +236,32 @@ impl RegisterResponse {
         signature: Bytes<consts::U72>,
         attestation_certificate: &[u8],
     ) -> Self {
+        Self::try_new(header_byte, public_key, key_handle, signature, attestation_certificate)
+            .expect("malformed register response")
+    }
+
+    /// Fails if the coordinates of the public key are not 32 bytes each, or both zero.
+    /// Fails with `WrongLength` if the key handle is longer than 255 bytes, or the
+    /// attestation certificate longer than 1024 bytes.
+    ///
+    /// This does not check that the point is on the curve.
+    pub fn try_new(
+        header_byte: u8,
+        public_key: &crate::cose::EcdhEsHkdf256PublicKey,
+        key_handle: &[u8],
+        signature: Bytes<consts::U72>,
+        attestation_certificate: &[u8],
+    ) -> Result<Self> {
 
-        debug_assert!(key_handle.len()<=255);
-        debug_assert!(attestation_certificate.len()<=1024);
         debug_assert!(signature.len()<=72);
 
+        if public_key.x.len() != 32 || public_key.y.len() != 32 {
+            return Err(Error::UnspecifiedNonpersistentExecutionError);
+        }
+        if public_key.x.iter().chain(public_key.y.iter()).all(|&byte| byte == 0) {
+            return Err(Error::UnspecifiedNonpersistentExecutionError);
+        }
+
         let mut public_key_bytes = Bytes::new();
         let mut key_handle_bytes = Bytes::new();
         let mut cert_bytes = Bytes::new();
@@ -102,17 +270,23 @@ impl RegisterResponse {
         public_key_bytes.extend_from_slice(&public_key.x).unwrap();
         public_key_bytes.extend_from_slice(&public_key.y).unwrap();
 
-        key_handle_bytes.extend_from_slice(key_handle).unwrap();
+        key_handle_bytes.extend_from_slice(key_handle).map_err(|_| Error::WrongLength)?;
 
-        cert_bytes.extend_from_slice(attestation_certificate).unwrap();
+        cert_bytes.extend_from_slice(attestation_certificate).map_err(|_| Error::WrongLength)?;
 
-        Self {
+        Ok(Self {
             header_byte: header_byte,
             public_key: public_key_bytes,
             key_handle: key_handle_bytes,
             attestation_certificate: cert_bytes,
             signature: signature,
-        }
+        })
+    }
+
+    /// The number of bytes `Response::serialize` writes for this response.
+    pub fn serialized_len(&self) -> usize {
+        1 + self.public_key.len() + 1 + self.key_handle.len()
+            + self.attestation_certificate.len() + self.signature.len()
     }
 }
 
@@ -128,23 +302,84 @@ impl AuthenticateResponse {
             signature: signature,
         }
     }
+
+    /// Sets the user presence byte from whether presence was confirmed, e.g. by the
+    /// platform's consent check (`0x01`), or was not checked at all (`0x00`).
+    ///
+    /// A failed check must not get this far: the authenticator answers
+    /// `ConditionsOfUseNotSatisfied` instead.
+    pub fn from_presence(
+        user_present: bool,
+        count: u32,
+        signature: Bytes<consts::U72>,
+    ) -> Self {
+        Self::new(user_present as u8, count, signature)
+    }
+
+    /// `0x01` if user presence was verified, `0x00` otherwise.
+    pub fn user_presence(&self) -> u8 {
+        self.user_presence
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn signature(&self) -> &Bytes<consts::U72> {
+        &self.signature
+    }
+
+    /// The number of bytes `Response::serialize` writes for this response.
+    pub fn serialized_len(&self) -> usize {
+        1 + 4 + self.signature.len()
+    }
 }
 
 impl Response {
+    /// The response to `Command::Version`.
+    pub fn version() -> Self {
+        Response::Version(U2F_VERSION)
+    }
+
+    /// The number of bytes `serialize` writes, without a status word.
+    pub fn serialized_len(&self) -> usize {
+        match self {
+            Response::Register(reg) => reg.serialized_len(),
+            Response::Authenticate(auth) => auth.serialized_len(),
+            Response::Version(version) => version.len(),
+        }
+    }
+
+    /// Appends the response data to `buf`, without a status word.
+    ///
+    /// Fails if `buf` has no room for all of it, leaving `buf` as it was. The caller
+    /// must then reply an error status instead, not append `9000` to a partial response.
     pub fn serialize<SIZE>(&self, buf: &mut iso7816::response::Data<SIZE>) -> core::result::Result<(),()>
+    where SIZE: heapless_bytes::ArrayLength<u8> {
+        let len = buf.len();
+        let result = self.try_serialize(buf);
+        if result.is_err() {
+            buf.truncate(len);
+        }
+        result
+    }
+
+    fn try_serialize<SIZE>(&self, buf: &mut iso7816::response::Data<SIZE>) -> core::result::Result<(),()>
     where SIZE: heapless_bytes::ArrayLength<u8> {
         match self {
             Response::Register(reg) => {
-                buf.push(reg.header_byte).ok();
-                buf.extend_from_slice(&reg.public_key).ok();
-                buf.push(reg.key_handle.len() as u8).ok();
-                buf.extend_from_slice(&reg.key_handle).ok();
-                buf.extend_from_slice(&reg.attestation_certificate).ok();
+                // the length is a single byte, never truncate it
+                let key_handle_length = u8::try_from(reg.key_handle.len()).map_err(|_| ())?;
+                buf.push(reg.header_byte).map_err(|_| ())?;
+                buf.extend_from_slice(&reg.public_key)?;
+                buf.push(key_handle_length).map_err(|_| ())?;
+                buf.extend_from_slice(&reg.key_handle)?;
+                buf.extend_from_slice(&reg.attestation_certificate)?;
                 buf.extend_from_slice(&reg.signature)
             },
             Response::Authenticate(auth) => {
-                buf.push(auth.user_presence).ok();
-                buf.extend_from_slice(&auth.count.to_be_bytes()).ok();
+                buf.push(auth.user_presence).map_err(|_| ())?;
+                buf.extend_from_slice(&auth.count.to_be_bytes())?;
                 buf.extend_from_slice(&auth.signature)
             },
             Response::Version(version) => {
@@ -152,11 +387,24 @@ impl Response {
             }
         }
     }
+
+    /// Like `serialize`, with the status word if `transport` needs it.
+    pub fn serialize_framed<SIZE>(&self, transport: U2fTransport, buf: &mut iso7816::response::Data<SIZE>) -> core::result::Result<(),()>
+    where SIZE: heapless_bytes::ArrayLength<u8> {
+        let len = buf.len();
+        self.serialize(buf)?;
+        match transport {
+            U2fTransport::Apdu => Ok(()),
+            U2fTransport::Hid => buf.extend_from_slice(&NO_ERROR.to_be_bytes()).map_err(|_| {
+                buf.truncate(len);
+            }),
+        }
+    }
 }
 impl<SIZE> core::convert::TryFrom<&ApduCommand<SIZE>> for Command
 where SIZE: heapless_bytes::ArrayLength<u8> {
-    type Error = Error;
-    fn try_from(apdu: &ApduCommand<SIZE>) -> Result<Command> {
+    type Error = CommandError;
+    fn try_from(apdu: &ApduCommand<SIZE>) -> core::result::Result<Command, CommandError> {
         let cla = apdu.class().into_inner();
         let ins = match apdu.instruction() {
             Instruction::Unknown(ins) => ins,
@@ -166,12 +414,17 @@ where SIZE: heapless_bytes::ArrayLength<u8> {
         let _p2 = apdu.p2;
 
         if cla != 0 {
-            return Err(Error::ClassNotSupported);
+            return Err(CommandError::ClassNotSupported);
         }
 
         if ins == 0x3 {
             // for some weird historical reason, [0, 3, 0, 0, 0, 0, 0, 0, 0]
             // is valid to send here.
+            // Without Le, or with Le = 0 (i.e. 256), any length is fine.
+            let le = apdu.expected();
+            if le != 0 && le < U2F_VERSION.len() {
+                return Err(CommandError::VersionLe);
+            }
             return Ok(Command::Version);
         };
 
@@ -181,7 +434,7 @@ where SIZE: heapless_bytes::ArrayLength<u8> {
             // register
             0x1 => {
                 if request.len() != 64 {
-                    return Err(Error::IncorrectDataParameter);
+                    return Err(CommandError::BadLength);
                 }
                 Ok(Command::Register(Register {
                     challenge: Bytes::try_from_slice(&request[..32]).unwrap(),
@@ -193,17 +446,23 @@ where SIZE: heapless_bytes::ArrayLength<u8> {
             0x2 => {
                 let control_byte = ControlByte::try_from(p1)?;
                 if request.len() < 65 {
-                    return Err(Error::IncorrectDataParameter);
+                    return Err(CommandError::BadLength);
+                }
+                // the length byte caps key handles at 255 bytes
+                if request.len() > 65 + 255 {
+                    return Err(CommandError::KeyHandleTooLong);
                 }
+                // may be zero, in which case the key handle is empty
                 let key_handle_length = request[64] as usize;
                 if request.len() != 65 + key_handle_length {
-                    return Err(Error::IncorrectDataParameter);
+                    return Err(CommandError::KeyHandleLengthMismatch);
                 }
                 Ok(Command::Authenticate(Authenticate {
                     control_byte,
                     challenge: Bytes::try_from_slice(&request[..32]).unwrap(),
                     app_id: Bytes::try_from_slice(&request[32..64]).unwrap(),
-                    key_handle: Bytes::try_from_slice(&request[65..]).unwrap(),
+                    key_handle: Bytes::try_from_slice(&request[65..])
+                        .map_err(|_| CommandError::KeyHandleTooLong)?,
                 }))
             },
 
@@ -212,7 +471,7 @@ where SIZE: heapless_bytes::ArrayLength<u8> {
                 Ok(Command::Version)
             }
 
-            _ => Err(Error::InstructionNotSupportedOrInvalid),
+            _ => Err(CommandError::UnsupportedInstruction),
         }
     }
 }
@@ -0,0 +1,441 @@
+use core::convert::TryFrom;
+
+use ctap_types::Bytes;
+use ctap_types::cose::EcdhEsHkdf256PublicKey;
+use ctap_types::ctap1::{Authenticate, AuthenticateResponse, Command, CommandError, ControlByte, Error, Header, KeyHandleCheck, RegisterResponse, Response, U2fTransport, U2F_VERSION};
+use heapless::consts;
+use iso7816::Command as ApduCommand;
+
+// extended length APDU, so key handles of any length fit
+fn authenticate(key_handle_length: u8, key_handle: &[u8]) -> Result<Command, CommandError> {
+    let lc = (65 + key_handle.len()) as u16;
+    let mut apdu = vec![0x00, 0x02, 0x03, 0x00, 0x00];
+    apdu.extend_from_slice(&lc.to_be_bytes());
+    apdu.extend_from_slice(&[0x11; 32]);
+    apdu.extend_from_slice(&[0x22; 32]);
+    apdu.push(key_handle_length);
+    apdu.extend_from_slice(key_handle);
+
+    let apdu = ApduCommand::<consts::U1024>::try_from(&apdu).unwrap();
+    Command::try_from(&apdu)
+}
+
+#[test]
+fn authenticate_empty_key_handle() {
+    match authenticate(0, &[]).unwrap() {
+        Command::Authenticate(authenticate) => {
+            assert_eq!(authenticate.control_byte, ControlByte::EnforceUserPresenceAndSign);
+            assert_eq!(authenticate.challenge, &[0x11; 32]);
+            assert_eq!(authenticate.app_id, &[0x22; 32]);
+            assert!(authenticate.key_handle.is_empty());
+        }
+        _ => panic!("expected authenticate"),
+    }
+}
+
+#[test]
+fn authenticate_maximal_key_handle() {
+    let key_handle = [0x33; 255];
+    match authenticate(255, &key_handle).unwrap() {
+        Command::Authenticate(authenticate) => {
+            assert_eq!(authenticate.key_handle, &key_handle[..]);
+        }
+        _ => panic!("expected authenticate"),
+    }
+}
+
+#[test]
+fn authenticate_key_handle_length_mismatch() {
+    assert_eq!(authenticate(0, &[0x33]), Err(CommandError::KeyHandleLengthMismatch));
+    assert_eq!(authenticate(2, &[0x33]), Err(CommandError::KeyHandleLengthMismatch));
+    assert_eq!(Error::from(CommandError::KeyHandleLengthMismatch), Error::IncorrectDataParameter);
+}
+
+#[test]
+fn authenticate_oversized_key_handle() {
+    assert_eq!(authenticate(255, &[0x33; 256]), Err(CommandError::KeyHandleTooLong));
+    assert_eq!(Error::from(CommandError::KeyHandleTooLong), Error::WrongLength);
+}
+
+#[test]
+fn control_bytes() {
+    let check_only = ControlByte::try_from(0x07).unwrap();
+    assert!(check_only.is_check_only());
+    assert!(!check_only.enforces_user_presence());
+
+    let enforce = ControlByte::try_from(0x03).unwrap();
+    assert!(!enforce.is_check_only());
+    assert!(enforce.enforces_user_presence());
+
+    let dont_enforce = ControlByte::try_from(0x08).unwrap();
+    assert!(!dont_enforce.is_check_only());
+    assert!(!dont_enforce.enforces_user_presence());
+
+    assert_eq!(ControlByte::try_from(0x04), Err(CommandError::InvalidControlByte));
+    assert_eq!(Error::from(CommandError::InvalidControlByte), Error::IncorrectDataParameter);
+}
+
+fn parse(apdu: &[u8]) -> Result<Command, CommandError> {
+    let apdu = ApduCommand::<consts::U1024>::try_from(apdu).unwrap();
+    Command::try_from(&apdu)
+}
+
+#[test]
+fn command_errors() {
+    // class
+    assert_eq!(parse(&[0x80, 0x03, 0x00, 0x00]), Err(CommandError::ClassNotSupported));
+    assert_eq!(Error::from(CommandError::ClassNotSupported), Error::ClassNotSupported);
+
+    // instruction
+    assert_eq!(parse(&[0x00, 0x04, 0x00, 0x00]), Err(CommandError::UnsupportedInstruction));
+    assert_eq!(Error::from(CommandError::UnsupportedInstruction), Error::InstructionNotSupportedOrInvalid);
+
+    // control byte
+    let mut apdu = vec![0x00, 0x02, 0x05, 0x00, 65];
+    apdu.extend_from_slice(&[0; 65]);
+    assert_eq!(parse(&apdu), Err(CommandError::InvalidControlByte));
+
+    // register wants exactly challenge and application parameter
+    let mut apdu = vec![0x00, 0x01, 0x00, 0x00, 63];
+    apdu.extend_from_slice(&[0; 63]);
+    assert_eq!(parse(&apdu), Err(CommandError::BadLength));
+    assert_eq!(Error::from(CommandError::BadLength), Error::IncorrectDataParameter);
+
+    // authenticate wants at least the key handle length byte
+    let mut apdu = vec![0x00, 0x02, 0x03, 0x00, 64];
+    apdu.extend_from_slice(&[0; 64]);
+    assert_eq!(parse(&apdu), Err(CommandError::BadLength));
+
+    assert_eq!(parse(&[0x00, 0x03, 0x00, 0x00]), Ok(Command::Version));
+}
+
+fn authenticate_with(control_byte: ControlByte) -> Authenticate {
+    Authenticate {
+        control_byte,
+        challenge: Bytes::try_from_slice(&[0x11; 32]).unwrap(),
+        app_id: Bytes::try_from_slice(&[0x22; 32]).unwrap(),
+        key_handle: Bytes::try_from_slice(&[0x33; 64]).unwrap(),
+    }
+}
+
+#[test]
+fn check_only_known_key_handle() {
+    let authenticate = authenticate_with(ControlByte::CheckOnly);
+    let check = authenticate.check_only(|rp_id_hash, credential_id| {
+        assert_eq!(rp_id_hash, &[0x22; 32]);
+        assert_eq!(credential_id, &[0x33; 64][..]);
+        true
+    });
+    assert_eq!(check, Some(KeyHandleCheck::Mine));
+    assert_eq!(check.unwrap().status(), Error::ConditionsOfUseNotSatisfied);
+}
+
+#[test]
+fn check_only_unknown_key_handle() {
+    let authenticate = authenticate_with(ControlByte::CheckOnly);
+    let check = authenticate.check_only(|_, _| false);
+    assert_eq!(check, Some(KeyHandleCheck::NotMine));
+    assert_eq!(check.unwrap().status(), Error::IncorrectDataParameter);
+
+    // signing requests are not probes
+    let authenticate = authenticate_with(ControlByte::EnforceUserPresenceAndSign);
+    assert_eq!(authenticate.check_only(|_, _| panic!("not a probe")), None);
+}
+
+// A toy authenticator, whose key handles are a key index followed by the first
+// eight bytes of the application parameter they were created for.
+fn toy_key(app_id: &Bytes<consts::U32>, key_handle: &[u8]) -> Result<u8, &'static str> {
+    match key_handle {
+        [index, binding @ ..] if binding.len() == 8 => if binding == &app_id[..8] {
+            Ok(*index)
+        } else {
+            Err("created for another application")
+        },
+        _ => Err("corrupt"),
+    }
+}
+
+#[test]
+fn verify_key_handle() {
+    let mut authenticate = authenticate_with(ControlByte::EnforceUserPresenceAndSign);
+    let mut key_handle = vec![0x07];
+    key_handle.extend_from_slice(&[0x22; 8]);
+    authenticate.key_handle = Bytes::try_from_slice(&key_handle).unwrap();
+    assert_eq!(authenticate.verify_key_handle(toy_key), Ok(0x07));
+
+    // the same key handle, presented by another application
+    let mut other_app = authenticate.clone();
+    other_app.app_id = Bytes::try_from_slice(&[0x44; 32]).unwrap();
+    assert_eq!(other_app.verify_key_handle(toy_key), Err(Error::IncorrectDataParameter));
+
+    let mut corrupt = authenticate.clone();
+    corrupt.key_handle = Bytes::try_from_slice(&key_handle[..5]).unwrap();
+    assert_eq!(corrupt.verify_key_handle(toy_key), Err(Error::IncorrectDataParameter));
+
+    // SW_WRONG_DATA
+    let sw: u16 = Error::IncorrectDataParameter.into();
+    assert_eq!(sw, 0x6a80);
+}
+
+#[test]
+fn authenticate_response_presence_byte() {
+    let signature = Bytes::try_from_slice(&[0x30; 70]).unwrap();
+
+    for &user_presence in &[0x00, 0x01] {
+        let response = AuthenticateResponse::new(user_presence, 0x01020304, signature.clone());
+        assert_eq!(response.user_presence(), user_presence);
+
+        let mut buf = iso7816::response::Data::<consts::U1024>::new();
+        Response::Authenticate(response).serialize(&mut buf).unwrap();
+        assert_eq!(buf[0], user_presence);
+        assert_eq!(&buf[1..5], &[0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(&buf[5..], &signature[..]);
+    }
+}
+
+#[test]
+fn authenticate_response_from_presence() {
+    let signature = Bytes::try_from_slice(&[0x30; 70]).unwrap();
+
+    let confirmed = AuthenticateResponse::from_presence(true, 7, signature.clone());
+    assert_eq!(confirmed.user_presence(), 0x01);
+    assert_eq!(confirmed, AuthenticateResponse::new(0x01, 7, signature.clone()));
+
+    let unchecked = AuthenticateResponse::from_presence(false, 7, signature.clone());
+    assert_eq!(unchecked.user_presence(), 0x00);
+}
+
+#[test]
+fn register_response_maximal_key_handle() {
+    let public_key = EcdhEsHkdf256PublicKey {
+        x: Bytes::try_from_slice(&[0x11; 32]).unwrap(),
+        y: Bytes::try_from_slice(&[0x22; 32]).unwrap(),
+    };
+    let key_handle = [0x33; 255];
+    let signature = Bytes::try_from_slice(&[0x30; 70]).unwrap();
+    let certificate = [0x44; 300];
+    let response = RegisterResponse::new(0x05, &public_key, &key_handle, signature, &certificate);
+
+    let mut buf = iso7816::response::Data::<consts::U1024>::new();
+    Response::Register(response).serialize(&mut buf).unwrap();
+
+    assert_eq!(buf[0], 0x05);
+    assert_eq!(buf[1], 0x04);
+    assert_eq!(buf[66], 255);
+    assert_eq!(&buf[67..][..255], &key_handle[..]);
+    assert_eq!(buf.len(), 67 + 255 + 300 + 70);
+}
+
+#[test]
+fn version_response_buffer_size() {
+    // too small: nothing is written
+    let mut buf = iso7816::response::Data::<consts::U4>::new();
+    assert_eq!(Response::version().serialize(&mut buf), Err(()));
+    assert!(buf.is_empty());
+    // and no status word either
+    assert_eq!(Response::version().serialize_framed(U2fTransport::Hid, &mut buf), Err(()));
+    assert!(buf.is_empty());
+
+    let mut buf = iso7816::response::Data::<consts::U6>::new();
+    assert_eq!(Response::version().serialize(&mut buf), Ok(()));
+    assert_eq!(buf.as_slice(), b"U2F_V2");
+    // room for the version, but not the status word
+    let mut buf = iso7816::response::Data::<consts::U6>::new();
+    assert_eq!(Response::version().serialize_framed(U2fTransport::Hid, &mut buf), Err(()));
+    assert!(buf.is_empty());
+    let mut buf = iso7816::response::Data::<consts::U8>::new();
+    assert_eq!(Response::version().serialize_framed(U2fTransport::Hid, &mut buf), Ok(()));
+    assert_eq!(buf.as_slice(), b"U2F_V2\x90\x00");
+}
+
+#[test]
+fn register_response_buffer_size() {
+    let public_key = EcdhEsHkdf256PublicKey {
+        x: Bytes::try_from_slice(&[0x11; 32]).unwrap(),
+        y: Bytes::try_from_slice(&[0x22; 32]).unwrap(),
+    };
+    let signature = Bytes::try_from_slice(&[0x30; 8]).unwrap();
+    // the certificate does not fit, the signature would
+    let response = Response::Register(RegisterResponse::new(0x05, &public_key, &[0x33; 16], signature, &[0x44; 64]));
+
+    let mut buf = iso7816::response::Data::<consts::U128>::new();
+    buf.push(0xAA).unwrap();
+    assert_eq!(response.serialize(&mut buf), Err(()));
+    assert_eq!(buf.as_slice(), &[0xAA]);
+}
+
+#[test]
+fn register_response_framing() {
+    let public_key = EcdhEsHkdf256PublicKey {
+        x: Bytes::try_from_slice(&[0x11; 32]).unwrap(),
+        y: Bytes::try_from_slice(&[0x22; 32]).unwrap(),
+    };
+    let signature = Bytes::try_from_slice(&[0x30; 70]).unwrap();
+    let response = Response::Register(RegisterResponse::new(0x05, &public_key, &[0x33; 64], signature, &[0x44; 300]));
+
+    let mut unframed = iso7816::response::Data::<consts::U1024>::new();
+    response.serialize(&mut unframed).unwrap();
+
+    // apdu-dispatch appends the status word
+    let mut buf = iso7816::response::Data::<consts::U1024>::new();
+    response.serialize_framed(U2fTransport::Apdu, &mut buf).unwrap();
+    assert_eq!(buf, unframed);
+
+    // U2FHID_MSG carries it along
+    let mut buf = iso7816::response::Data::<consts::U1024>::new();
+    response.serialize_framed(U2fTransport::Hid, &mut buf).unwrap();
+    assert_eq!(&buf[..unframed.len()], &unframed[..]);
+    assert_eq!(&buf[unframed.len()..], &[0x90, 0x00]);
+}
+
+#[test]
+fn register_response_degenerate_public_key() {
+    let register = |x: &[u8], y: &[u8]| {
+        let public_key = EcdhEsHkdf256PublicKey {
+            x: Bytes::try_from_slice(x).unwrap(),
+            y: Bytes::try_from_slice(y).unwrap(),
+        };
+        let signature = Bytes::try_from_slice(&[0x30; 70]).unwrap();
+        RegisterResponse::try_new(0x05, &public_key, &[0x33; 64], signature, &[0x44; 300])
+    };
+
+    assert!(register(&[0x11; 32], &[0x22; 32]).is_ok());
+    // only one coordinate being zero is not rejected
+    assert!(register(&[0x00; 32], &[0x22; 32]).is_ok());
+
+    // the identity
+    assert_eq!(register(&[0x00; 32], &[0x00; 32]), Err(Error::UnspecifiedNonpersistentExecutionError));
+    // truncated coordinates
+    assert_eq!(register(&[0x11; 31], &[0x22; 32]), Err(Error::UnspecifiedNonpersistentExecutionError));
+    assert_eq!(register(&[0x11; 32], &[]), Err(Error::UnspecifiedNonpersistentExecutionError));
+}
+
+#[test]
+fn register_response_oversized() {
+    let public_key = EcdhEsHkdf256PublicKey {
+        x: Bytes::try_from_slice(&[0x11; 32]).unwrap(),
+        y: Bytes::try_from_slice(&[0x22; 32]).unwrap(),
+    };
+    let register = |key_handle: &[u8], certificate: &[u8]| {
+        let signature = Bytes::try_from_slice(&[0x30; 70]).unwrap();
+        RegisterResponse::try_new(0x05, &public_key, key_handle, signature, certificate)
+    };
+
+    assert!(register(&[0x33; 255], &[0x44; 1024]).is_ok());
+    assert_eq!(register(&[0x33; 256], &[0x44; 300]), Err(Error::WrongLength));
+    assert_eq!(register(&[0x33; 64], &[0x44; 1025]), Err(Error::WrongLength));
+}
+
+#[test]
+fn version_le() {
+    // no Le
+    assert_eq!(parse(&[0x00, 0x03, 0x00, 0x00]), Ok(Command::Version));
+    // Le = 0, i.e. up to 256 bytes
+    assert_eq!(parse(&[0x00, 0x03, 0x00, 0x00, 0x00]), Ok(Command::Version));
+    // Le = 6, exactly the version
+    assert_eq!(parse(&[0x00, 0x03, 0x00, 0x00, 0x06]), Ok(Command::Version));
+    // extended Le = 0, i.e. up to 65536 bytes
+    assert_eq!(parse(&[0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00]), Ok(Command::Version));
+
+    // too short: the reader is told to resend with Le = 6
+    assert_eq!(parse(&[0x00, 0x03, 0x00, 0x00, 0x02]), Err(CommandError::VersionLe));
+    let status = Error::from(CommandError::VersionLe);
+    assert_eq!(status, Error::WrongLe(6));
+    let sw: u16 = status.into();
+    assert_eq!(sw, 0x6c06);
+}
+
+#[test]
+fn version_response() {
+    assert_eq!(&U2F_VERSION, b"U2F_V2");
+
+    let mut buf = iso7816::response::Data::<consts::U1024>::new();
+    Response::version().serialize(&mut buf).unwrap();
+    // the status word is appended by the transport
+    assert_eq!(buf.as_slice(), b"U2F_V2");
+}
+
+#[test]
+fn serialized_len() {
+    let public_key = EcdhEsHkdf256PublicKey {
+        x: Bytes::try_from_slice(&[0x11; 32]).unwrap(),
+        y: Bytes::try_from_slice(&[0x22; 32]).unwrap(),
+    };
+    let responses = [
+        Response::Register(RegisterResponse::new(
+            0x05, &public_key, &[0x33; 64], Bytes::try_from_slice(&[0x30; 71]).unwrap(), &[0x44; 300])),
+        Response::Register(RegisterResponse::new(
+            0x05, &public_key, &[0x33; 255], Bytes::try_from_slice(&[0x30; 72]).unwrap(), &[0x44; 1024])),
+        Response::Authenticate(AuthenticateResponse::new(
+            0x01, 7, Bytes::try_from_slice(&[0x30; 70]).unwrap())),
+        Response::version(),
+    ];
+    for response in responses.iter() {
+        let mut buf = iso7816::response::Data::<consts::U2048>::new();
+        response.serialize(&mut buf).unwrap();
+        assert_eq!(response.serialized_len(), buf.len());
+    }
+}
+
+// Fixed inputs and their complete serialization, as sent over U2FHID_MSG. Any change
+// to the byte layout of either response shows up here as a differing vector.
+#[test]
+fn response_vectors() {
+    let public_key = EcdhEsHkdf256PublicKey {
+        x: Bytes::try_from_slice(&[0xA1; 32]).unwrap(),
+        y: Bytes::try_from_slice(&[0xB2; 32]).unwrap(),
+    };
+    let signature = Bytes::try_from_slice(&[0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x02]).unwrap();
+    let register = Response::Register(RegisterResponse::new(0x05, &public_key, &[0xC3, 0xC4, 0xC5], signature.clone(), &[0x30, 0x00]));
+
+    let mut expected = vec![0x05, 0x04];
+    expected.extend_from_slice(&[0xA1; 32]);
+    expected.extend_from_slice(&[0xB2; 32]);
+    expected.extend_from_slice(&[0x03, 0xC3, 0xC4, 0xC5]);
+    expected.extend_from_slice(&[0x30, 0x00]);
+    expected.extend_from_slice(&[0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x02]);
+    expected.extend_from_slice(&[0x90, 0x00]);
+
+    let mut buf = iso7816::response::Data::<consts::U1024>::new();
+    register.serialize_framed(U2fTransport::Hid, &mut buf).unwrap();
+    assert_eq!(buf.as_slice(), &expected[..]);
+
+    let authenticate = Response::Authenticate(AuthenticateResponse::from_presence(true, 0x0000_0102, signature.clone()));
+    let mut buf = iso7816::response::Data::<consts::U1024>::new();
+    authenticate.serialize_framed(U2fTransport::Hid, &mut buf).unwrap();
+    assert_eq!(buf.as_slice(), &[
+        0x01,
+        0x00, 0x00, 0x01, 0x02,
+        0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x02,
+        0x90, 0x00,
+    ]);
+
+    // presence not checked, counter at its limit
+    let authenticate = Response::Authenticate(AuthenticateResponse::from_presence(false, u32::MAX, signature));
+    let mut buf = iso7816::response::Data::<consts::U1024>::new();
+    authenticate.serialize(&mut buf).unwrap();
+    assert_eq!(&buf[..5], &[0x00, 0xFF, 0xFF, 0xFF, 0xFF]);
+}
+
+#[test]
+fn header() {
+    let header = |apdu: &[u8]| {
+        let apdu = ApduCommand::<consts::U1024>::try_from(apdu).unwrap();
+        let command = Command::try_from(&apdu).unwrap();
+        let header = Header::from(&apdu);
+        assert_eq!(command.instruction_byte(), header.instruction);
+        header
+    };
+
+    // browsers send REGISTER with P1 = 0x03, which parsing ignores
+    let mut register = vec![0x00, 0x01, 0x03, 0x00, 0x40];
+    register.extend_from_slice(&[0x11; 64]);
+    assert_eq!(header(&register), Header { instruction: 0x01, p1: 0x03, p2: 0x00 });
+
+    let mut authenticate = vec![0x00, 0x02, 0x07, 0x5A, 0x41];
+    authenticate.extend_from_slice(&[0x22; 64]);
+    authenticate.push(0x00);
+    assert_eq!(header(&authenticate), Header { instruction: 0x02, p1: 0x07, p2: 0x5A });
+
+    assert_eq!(header(&[0x00, 0x03, 0x12, 0x34, 0x00]), Header { instruction: 0x03, p1: 0x12, p2: 0x34 });
+}
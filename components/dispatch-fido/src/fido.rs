@@ -6,7 +6,7 @@ use ctap_types::{
     authenticator::Error as AuthenticatorError,
     authenticator::Request as AuthenticatorRequest,
     serde::{cbor_serialize},
-    ctap1::{Command as U2fCommand},
+    ctap1::{Command as U2fCommand, Header as U2fHeader, U2fTransport},
 };
 
 use crate::cbor::{parse_cbor};
@@ -119,7 +119,7 @@ where UP: UserPresence,
     fn call_authenticator_u2f_with_bytes(&mut self, request: &response::Data, reply: &mut response::Data) -> app::Result {
         match &Command::try_from(request) {
             Ok(command) => {
-                self.call_authenticator_u2f(command, reply)
+                self.call_authenticator_u2f(command, reply, U2fTransport::Hid)
             },
             _ => {
                 Err(Status::IncorrectDataParameter)
@@ -128,13 +128,17 @@ where UP: UserPresence,
     }
 
     #[inline(never)]
-    fn call_authenticator_u2f(&mut self, apdu: &Command, reply: &mut response::Data) -> app::Result {
+    fn call_authenticator_u2f(&mut self, apdu: &Command, reply: &mut response::Data, transport: U2fTransport) -> app::Result {
+        let header = U2fHeader::from(apdu);
+        info!("U2F INS {:02x} P1 {:02x} P2 {:02x}", header.instruction, header.p1, header.p2);
+        // the parse error becomes the status word here
         let u2f_command = U2fCommand::try_from(apdu)?;
         let result = self.authenticator.call_u2f(&u2f_command);
         match result {
             Ok(u2f_response) => {
-                u2f_response.serialize(reply).unwrap();
-                Ok(())
+                // too large for the reply: answer with an error status only
+                u2f_response.serialize_framed(transport, reply)
+                    .map_err(|_| Status::UnspecifiedNonpersistentExecutionError)
             }
             Err(err)=> Err(err)
         }
@@ -169,23 +173,22 @@ where UP: UserPresence,
 
 
     fn select(&mut self, _apdu: &Command, reply: &mut response::Data) -> app::Result {
-        // U2F_V2
-        reply.extend_from_slice(& [0x55, 0x32, 0x46, 0x5f, 0x56, 0x32,]).unwrap();
+        reply.extend_from_slice(&ctap_types::ctap1::U2F_VERSION).unwrap();
         Ok(())
     }
 
-    fn deselect(&mut self) {}
+    fn deselect(&mut self, _interface: app::Interface) {}
 
-    fn call(&mut self, _type: app::Interface, apdu: &Command, reply: &mut response::Data) -> app::Result {
+    fn call(&mut self, interface: app::Interface, _chained: bool, apdu: &Command, reply: &mut response::Data) -> iso7816::Result<Status> {
         let instruction = apdu.instruction();
 
-        match instruction {
+        let result = match instruction {
             Instruction::Unknown(ins) => {
                 // TODO need to tidy up these ins codes somewhere
                 match ins {
                     // U2F ins codes
                     0x00 | 0x01 | 0x02 => {
-                        self.call_authenticator_u2f(apdu, reply)
+                        self.call_authenticator_u2f(apdu, reply, U2fTransport::Apdu)
                     }
                     _ => {
                         match FidoCommand::try_from(ins) {
@@ -204,10 +207,10 @@ where UP: UserPresence,
                                 }
                             }
                             Ok(FidoCommand::Msg) => {
-                                self.call_authenticator_u2f(apdu, reply)
+                                self.call_authenticator_u2f(apdu, reply, U2fTransport::Apdu)
                             }
                             Ok(FidoCommand::Deselect) => {
-                                self.deselect();
+                                self.deselect(interface);
                                 Ok(())
                             }
                             _ => {
@@ -223,7 +226,8 @@ where UP: UserPresence,
                 info!("Unsupported ins for fido app");
                 Err(Status::InstructionNotSupportedOrInvalid)
             }
-        }
+        };
+        result.map(|()| Status::Success)
     }
 
 }
@@ -274,10 +278,8 @@ where UP: UserPresence,
                 let result = self.call_authenticator_u2f_with_bytes(request, response);
                 match result {
                     Ok(()) => {
-                        info!("U2F response {} bytes", data.len());
-                        // Need to add x9000 success code (normally the apdu-dispatch does this, but
-                        // since u2f uses apdus over hid, we must do it here.)
-                        response.extend_from_slice(&[0x90, 0x00]).ok();
+                        // the success code is already framed, see `U2fTransport::Hid`
+                        info!("U2F response {} bytes", response.len());
                     },
                     Err(status) => {
                         let code: [u8; 2] = status.into();
@@ -3,3 +3,6 @@ pub const FIDO2_UP_TIMEOUT: u32 = 30_000;
 pub const U2F_UP_TIMEOUT: u32 =    0_250;
 
 pub const ATTESTATION_CERT_ID: u8 = 0;
+
+pub const U2F_SIGNATURE_COUNTER: signature_counter::SignatureCounter =
+    signature_counter::SignatureCounter::new(b"u2f-signature-counter");
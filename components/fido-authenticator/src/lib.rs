@@ -267,74 +267,74 @@ where UP: UserPresence,
                 };
 
 
-                Ok(U2fResponse::Register(ctap1::RegisterResponse::new(
+                Ok(U2fResponse::Register(ctap1::RegisterResponse::try_new(
                     0x05,
                     &cose_key,
                     &credential_id.0,
                     signature,
                     &cert,
-                )))
+                )?))
             }
             U2fCommand::Authenticate(auth) => {
 
-                let cred = Credential::try_from_bytes(self, &auth.app_id, &auth.key_handle);
+                // if the control byte is set to 0x07 by the FIDO Client,
+                // the U2F token is supposed to simply check whether the
+                // provided key handle was originally created by this token
+                if let Some(check) = auth.check_only(|app_id, key_handle| {
+                    Credential::try_from_bytes(self, app_id, key_handle).is_ok()
+                }) {
+                    return Err(check.status());
+                }
 
-                let user_presence_byte = match auth.control_byte {
-                    ctap1::ControlByte::CheckOnly => {
-                        // if the control byte is set to 0x07 by the FIDO Client,
-                        // the U2F token is supposed to simply check whether the
-                        // provided key handle was originally created by this token
-                        return if cred.is_ok() {
-                            Err(U2fError::ConditionsOfUseNotSatisfied)
-                        } else {
-                            Err(U2fError::IncorrectDataParameter)
-                        };
-                    },
-                    ctap1::ControlByte::EnforceUserPresenceAndSign => {
-                        if !self.up.user_present(&mut self.trussed, constants::U2F_UP_TIMEOUT) {
-                            return Err(U2fError::ConditionsOfUseNotSatisfied);
+                // only a key handle of ours, for this application, gets signed with
+                let key = auth.verify_key_handle(|app_id, key_handle| -> Result<_> {
+                    let cred = Credential::try_from_bytes(self, app_id, key_handle)?;
+                    if cred.algorithm != -7 {
+                        info!("Unexpected mechanism for u2f");
+                        return Err(Error::InvalidCredential);
+                    }
+                    let bytes = match &cred.key {
+                        Key::WrappedKey(bytes) => bytes,
+                        _ => return Err(Error::InvalidCredential),
+                    };
+                    let wrapping_key = self.state.persistent.key_wrapping_key(&mut self.trussed)?;
+                    let key = syscall!(self.trussed.unwrap_key_chacha8poly1305(
+                        wrapping_key,
+                        bytes,
+                        b"",
+                        Location::Volatile,
+                    )).key;
+                    match key {
+                        Some(key) => {
+                            info!("loaded u2f key!");
+                            Ok(key)
                         }
-                        0x01
-                    },
-                    ctap1::ControlByte::DontEnforceUserPresenceAndSign => 0x00,
-                };
-
-                let cred = cred.map_err(|_| U2fError::IncorrectDataParameter)?;
-
-                let key = match &cred.key {
-                    Key::WrappedKey(bytes) => {
-                        let wrapping_key = self.state.persistent.key_wrapping_key(&mut self.trussed)
-                            .map_err(|_| U2fError::IncorrectDataParameter)?;
-                        let key_result = syscall!(self.trussed.unwrap_key_chacha8poly1305(
-                            wrapping_key,
-                            bytes,
-                            b"",
-                            Location::Volatile,
-                        )).key;
-                        match key_result {
-                            Some(key) => {
-                                info!("loaded u2f key!");
-                                key
-                            }
-                            None => {
-                                info!("issue with unwrapping credential id key");
-                                return Err(U2fError::IncorrectDataParameter);
-                            }
+                        None => {
+                            info!("issue with unwrapping credential id key");
+                            Err(Error::InvalidCredential)
                         }
                     }
-                    _ => return Err(U2fError::IncorrectDataParameter),
-                };
+                })?;
 
-                if cred.algorithm != -7 {
-                    info!("Unexpected mechanism for u2f");
-                    return Err(U2fError::IncorrectDataParameter);
-                }
+                // the user presence byte reflects whether presence was actually checked
+                let user_present = if auth.control_byte.enforces_user_presence() {
+                    if !self.up.user_present(&mut self.trussed, constants::U2F_UP_TIMEOUT) {
+                        syscall!(self.trussed.delete(key));
+                        return Err(U2fError::ConditionsOfUseNotSatisfied);
+                    }
+                    true
+                } else {
+                    false
+                };
 
-                let sig_count = self.state.persistent.timestamp(&mut self.trussed).
-                    map_err(|_| U2fError::UnspecifiedNonpersistentExecutionError)?;
+                // U2F used to count with the timestamp it shares with CTAP2, so continue
+                // above it: relying parties reject counters that go backwards.
+                let sig_count = constants::U2F_SIGNATURE_COUNTER
+                    .increment_from(&mut self.trussed, self.state.persistent.peek_timestamp())
+                    .map_err(|_| U2fError::UnspecifiedNonpersistentExecutionError)?;
 
                 commitment.extend_from_slice(&auth.app_id).unwrap();
-                commitment.push(user_presence_byte).unwrap();
+                commitment.push(user_present as u8).unwrap();
                 commitment.extend_from_slice(&sig_count.to_be_bytes()).unwrap();
                 commitment.extend_from_slice(&auth.challenge).unwrap();
 
@@ -344,17 +344,17 @@ where UP: UserPresence,
                     &commitment,
                     SignatureSerialization::Asn1Der
                 )).signature.to_bytes();
+                syscall!(self.trussed.delete(key));
 
-                Ok(U2fResponse::Authenticate(ctap1::AuthenticateResponse::new(
-                    user_presence_byte,
+                Ok(U2fResponse::Authenticate(ctap1::AuthenticateResponse::from_presence(
+                    user_present,
                     sig_count,
                     signature,
                 )))
 
             }
             U2fCommand::Version => {
-                // "U2F_V2"
-                Ok(U2fResponse::Version([0x55, 0x32, 0x46, 0x5f, 0x56, 0x32]))
+                Ok(U2fResponse::version())
             }
         }
 
@@ -293,6 +293,11 @@ impl PersistentState {
         }
     }
 
+    /// The value the next call to `timestamp` returns, without advancing it.
+    pub fn peek_timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
     pub fn timestamp<T: TrussedClient>(&mut self, trussed: &mut T) -> Result<u32> {
         let now = self.timestamp;
         self.timestamp += 1;
@@ -51,6 +51,13 @@ where SIZE: ArrayLength<u8>
         self.le
     }
 
+    /// Whether the APDU had a body at all (ISO 7816-4 case 2, 3 or 4), as opposed to
+    /// just the header (case 1). A body with no data, e.g. `CLA INS P1 P2 00`, still
+    /// carries Le; Lc is left out when there is no data, it is never 0.
+    pub fn has_body(&self) -> bool {
+        !self.data.is_empty() || self.le != 0
+    }
+
     /// This can be use for APDU chaining to convert
     /// multiple APDU's into one.
     /// * Global Platform GPC_SPE_055 3.10
@@ -189,6 +196,11 @@ fn parse_lengths(body: &[u8]) -> Result<ParsedLengths, FromSliceError> {
         return Err(FromSliceError::InvalidFirstBodyByteForExtended);
     };
 
+    // a zero byte, then an extended length field of two bytes
+    if l < 3 {
+        return Err(FromSliceError::TooShort);
+    }
+
     // Case 2E (no data)
     if l == 3 && b1 == 0 {
         parsed.lc = 0;
@@ -246,6 +258,39 @@ mod test {
         ];
         // let apdu = b"\x10\xdb?\xff\xff\\\x03_\xc1\x05S\x82\x01Zp\x82\x01Q0\x82\x01M0\x81\xf4\xa0\x03\x02\x01\x02\x02\x10\x19\x185\xd2i\xcb\x0b\xf9\xcc\x07)*\xb5QLq0\n\x06\x08*\x86";
 
-        let command = Command::try_from(apdu).unwrap();
+        Command::<Size>::try_from(apdu).unwrap();
+    }
+
+    type Size = heapless_bytes::consts::U1024;
+
+    #[test]
+    fn body() {
+        // case 1: header only
+        let command = Command::<Size>::try_from(&[0x00, 0xCA, 0x00, 0x6E][..]).unwrap();
+        assert!(!command.has_body());
+        assert_eq!(command.expected(), 0);
+
+        // case 2S: no data, Le = 256
+        let command = Command::<Size>::try_from(&[0x00, 0xCA, 0x00, 0x6E, 0x00][..]).unwrap();
+        assert!(command.has_body());
+        assert!(command.data().is_empty());
+        assert_eq!(command.expected(), 256);
+
+        // case 2E: no data, Le = 65536
+        let command = Command::<Size>::try_from(&[0x00, 0xCA, 0x00, 0x6E, 0x00, 0x00, 0x00][..]).unwrap();
+        assert!(command.has_body());
+        assert!(command.data().is_empty());
+        assert_eq!(command.expected(), 65_536);
+
+        // case 3S
+        let command = Command::<Size>::try_from(&[0x00, 0xDA, 0x00, 0x6E, 0x01, 0x42][..]).unwrap();
+        assert!(command.has_body());
+        assert_eq!(command.expected(), 0);
+
+        // neither short nor extended
+        assert_eq!(
+            Command::<Size>::try_from(&[0x00, 0xCA, 0x00, 0x6E, 0x00, 0x05][..]),
+            Err(FromSliceError::TooShort),
+        );
     }
 }
@@ -75,7 +75,7 @@ impl Class {
                     }
                 },
                 Interindustry::Further => {
-                    match (self.cla >> 5)  != 0 {
+                    match self.cla & (1 << 5) != 0 {
                         true => SecureMessaging::Standard,
                         false => SecureMessaging::None,
                     }
@@ -102,7 +102,7 @@ impl Class {
                 self.cla & 0b11
             }
             Range::Interindustry(Interindustry::Further) => {
-                4 + self.cla & 0b111
+                4 + (self.cla & 0b1111)
             }
             _ => return None
         })
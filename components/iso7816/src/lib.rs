@@ -6,7 +6,7 @@ pub use heapless_bytes::Bytes as Bytes;
 // 7816-4, 8.2.1.2
 pub type Aid = Bytes<heapless::consts::U16>;
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Interface {
     Contact,
     Contactless,
@@ -23,10 +23,14 @@ pub enum Status {
 ///////////////////////////////
 
     // 62XX: state of non-volatile memory unchanged (cf. SW2)
+    WarningMemoryUnchanged(u8),
 
     // 63XX: state of non-volatile memory changed (cf. SW2)
     VerificationFailed,
     RemainingRetries(u8),
+    /// 63XX, other than `VerificationFailed` and `RemainingRetries`:
+    /// XX may not be 00 or C0 to CF, encoding one of these panics
+    WarningMemoryChanged(u8),
 
 ////////////////////////////////
 // Execution error (64, 65, 66)
@@ -68,6 +72,7 @@ pub enum Status {
     // 6BXX: wrong parameters P1-P2
 
     // 6CXX: wrong Le field, SW2 encodes available bytes
+    WrongLe(u8),
 
     // 6D00: instruction code not supported or invalid
     InstructionNotSupportedOrInvalid,
@@ -83,11 +88,17 @@ impl Into<u16> for Status {
     #[inline]
     fn into(self) -> u16 {
         match self {
+            Self::WarningMemoryUnchanged(x) => u16::from_be_bytes([0x62, x]),
+
             Self::VerificationFailed => 0x6300,
             Self::RemainingRetries(x) => {
                 assert!(x < 16);
                 u16::from_be_bytes([0x63, 0xc0 + x])
             }
+            Self::WarningMemoryChanged(x) => {
+                assert!(x != 0x00 && x & 0xf0 != 0xc0);
+                u16::from_be_bytes([0x63, x])
+            }
 
             Self::UnspecifiedNonpersistentExecutionError => 0x6400,
             Self::UnspecifiedPersistentExecutionError => 0x6500,
@@ -109,6 +120,8 @@ impl Into<u16> for Status {
             Self::IncorrectP1OrP2Parameter => 0x6a86,
             Self::KeyReferenceNotFound => 0x6a88,
 
+            Self::WrongLe(x) => u16::from_be_bytes([0x6c, x]),
+
             Self::InstructionNotSupportedOrInvalid => 0x6d00,
             Self::ClassNotSupported => 0x6e00,
             Self::UnspecifiedCheckingError => 0x6f00,
@@ -137,3 +150,27 @@ where S: heapless_bytes::ArrayLength<u8>
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn warning_memory_changed() {
+        let sw: u16 = Status::WarningMemoryChanged(0x81).into();
+        assert_eq!(sw, 0x6381);
+        let sw: u16 = Status::WarningMemoryChanged(0xbf).into();
+        assert_eq!(sw, 0x63bf);
+    }
+
+    #[test]
+    #[should_panic]
+    fn warning_memory_changed_is_not_verification_failed() {
+        let _: u16 = Status::WarningMemoryChanged(0x00).into();
+    }
+
+    #[test]
+    #[should_panic]
+    fn warning_memory_changed_is_not_remaining_retries() {
+        let _: u16 = Status::WarningMemoryChanged(0xc3).into();
+    }
+}
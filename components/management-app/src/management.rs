@@ -116,9 +116,9 @@ where T: TrussedClient
         Ok(())
     }
 
-    fn deselect(&mut self) {}
+    fn deselect(&mut self, _interface: apdu::Interface) {}
 
-    fn call(&mut self, interface: apdu::Interface, apdu: &Command, reply: &mut response::Data) -> apdu::Result {
+    fn call(&mut self, interface: apdu::Interface, _chained: bool, apdu: &Command, reply: &mut response::Data) -> apdu_dispatch::iso7816::Result<Status> {
         let instruction: u8 = apdu.instruction().into();
 
         let command: VendorCommand = instruction.try_into().map_err(|_e| Status::InstructionNotSupportedOrInvalid)?;
@@ -158,7 +158,7 @@ where T: TrussedClient
             }
 
         }
-        Ok(())
+        Ok(Status::Success)
 
     }
 }
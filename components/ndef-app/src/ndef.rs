@@ -49,9 +49,9 @@ impl<'a> app::App<CommandSize, ResponseSize> for App<'a> {
         Ok(())
     }
 
-    fn deselect(&mut self) {}
+    fn deselect(&mut self, _interface: app::Interface) {}
 
-    fn call(&mut self, _type: app::Interface, apdu: &Command, reply: &mut response::Data) -> app::Result {
+    fn call(&mut self, _type: app::Interface, _chained: bool, apdu: &Command, reply: &mut response::Data) -> iso7816::Result<Status> {
         let instruction = apdu.instruction();
         let p1 = apdu.p1;
         let p2 = apdu.p2;
@@ -64,10 +64,10 @@ impl<'a> app::App<CommandSize, ResponseSize> for App<'a> {
 
                 if payload.starts_with(&[0xE1u8, 0x03]) {
                     self.reader = &Self::CAPABILITY_CONTAINER;
-                    Ok(())
+                    Ok(Status::Success)
                 } else if payload.starts_with(&[0xE1u8, 0x04]) {
                     self.reader = &Self::NDEF;
-                    Ok(())
+                    Ok(Status::Success)
                 } else {
                     Err(Status::NotFound)
                 }
@@ -86,7 +86,7 @@ impl<'a> app::App<CommandSize, ResponseSize> for App<'a> {
                     };
 
                 reply.extend_from_slice(& self.reader[offset .. offset + len_to_read]).ok();
-                Ok(())
+                Ok(Status::Success)
             }
             _ => {
                 Err(Status::ConditionsOfUseNotSatisfied)
@@ -94,4 +94,8 @@ impl<'a> app::App<CommandSize, ResponseSize> for App<'a> {
         }
 
     }
+
+    fn supports_file_selection(&self) -> bool {
+        true
+    }
 }
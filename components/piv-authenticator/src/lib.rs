@@ -17,6 +17,7 @@ use core::convert::{TryFrom, TryInto};
 use heapless::consts;
 use iso7816::{
     Instruction, Status,
+    command::class::Range,
 };
 use apdu_dispatch::{Command, response};
 use trussed::client;
@@ -63,16 +64,19 @@ where
         // - command chaining not supported
         // - secure messaging not supported
         // - only channel zero supported
+        // - proprietary classes have neither, and are accepted
         // - ensure INS known to us
 
         let class = command.class();
 
-        if !class.secure_messaging().none() {
-            return Err(Status::SecureMessagingNotSupported);
-        }
+        if class.range() != Range::Proprietary {
+            if !class.secure_messaging().none() {
+                return Err(Status::SecureMessagingNotSupported);
+            }
 
-        if class.channel() != Some(0) {
-            return Err(Status::LogicalChannelNotSupported);
+            if class.channel() != Some(0) {
+                return Err(Status::LogicalChannelNotSupported);
+            }
         }
 
         // info_now!("CLA = {:?}", &command.class());
@@ -957,9 +961,9 @@ where
         return Ok(());
     }
 
-    fn deselect(&mut self) {}
+    fn deselect(&mut self, _: iso7816::Interface) {}
 
-    fn call(&mut self, _: iso7816::Interface, apdu: &Command, reply: &mut response::Data) -> Result {
-        self.respond(apdu, reply)
+    fn call(&mut self, _: iso7816::Interface, _chained: bool, apdu: &Command, reply: &mut response::Data) -> iso7816::Result<Status> {
+        self.respond(apdu, reply).map(|()| Status::Success)
     }
 }
@@ -0,0 +1,107 @@
+mod setup;
+
+use apdu_dispatch::dispatch::ApduDispatch;
+use apdu_dispatch::interchanges;
+use apdu_dispatch::{command, response};
+use hex_literal::hex;
+use interchange::{Interchange, Requester};
+
+type Dispatch = (Requester<interchanges::Contact>, ApduDispatch);
+
+fn dispatch() -> Dispatch {
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (contact_requester, contact_responder) = interchanges::Contact::claim().unwrap();
+    let (_contactless_requester, contactless_responder) = interchanges::Contactless::claim().unwrap();
+    (contact_requester, ApduDispatch::new(contact_responder, contactless_responder))
+}
+
+// Send one raw APDU through the dispatcher to the PIV app, return the raw response.
+fn transmit(dispatch: &mut Dispatch, piv: &mut setup::Piv, apdu: &[u8]) -> Vec<u8> {
+    let (requester, dispatch) = dispatch;
+    requester.request(&interchanges::Data::try_from_slice(apdu).unwrap()).unwrap();
+    dispatch.poll(&mut [&mut *piv as &mut dyn apdu_dispatch::App<command::Size, response::Size>]);
+    requester.take_response().unwrap().as_slice().to_vec()
+}
+
+// Send a command with the given data, chained in short APDUs,
+// and collect the (possibly chained) response.
+fn exchange(dispatch: &mut Dispatch, piv: &mut setup::Piv, header: [u8; 4], data: &[u8]) -> (Vec<u8>, [u8; 2]) {
+    let mut chunks = data.chunks(255).peekable();
+    let mut response = loop {
+        let chunk = chunks.next().unwrap();
+        let mut apdu = header.to_vec();
+        if chunks.peek().is_some() {
+            apdu[0] |= 0x10;
+        }
+        apdu.push(chunk.len() as u8);
+        apdu.extend_from_slice(chunk);
+
+        let response = transmit(dispatch, piv, &apdu);
+        if chunks.peek().is_none() {
+            break response;
+        }
+        assert_eq!(response, [0x90, 0x00]);
+    };
+
+    let mut data = Vec::new();
+    loop {
+        let status = response.split_off(response.len() - 2);
+        data.extend_from_slice(&response);
+        if status[0] != 0x61 {
+            return (data, [status[0], status[1]]);
+        }
+        response = transmit(dispatch, piv, &hex!("00 C0 00 00"));
+    }
+}
+
+#[test]
+fn put_and_get_data() {
+    setup::piv(|piv| {
+        let mut dispatch = dispatch();
+
+        // SELECT PIV
+        let (_, status) = exchange(&mut dispatch, piv, hex!("00 A4 04 00"), &piv_authenticator::constants::PIV_AID);
+        assert_eq!(status, [0x90, 0x00]);
+
+        // a certificate-sized object, needs command chaining
+        let certificate: Vec<u8> = (0..500u16).map(|i| i as u8).collect();
+
+        // PUT DATA, X.509 Certificate for PIV Authentication
+        let mut data = hex!("5C 03 5F C1 05  53 82 01 F4").to_vec();
+        data.extend_from_slice(&certificate);
+        let (response, status) = exchange(&mut dispatch, piv, hex!("00 DB 3F FF"), &data);
+        assert_eq!(status, [0x90, 0x00]);
+        assert!(response.is_empty());
+
+        // GET DATA; the chained request makes the dispatcher chain the response
+        let data = hex!("5C 03 5F C1 05");
+        let (response, status) = exchange(&mut dispatch, piv, hex!("10 CB 3F FF"), &data[..2]);
+        assert_eq!(status, [0x90, 0x00]);
+        assert!(response.is_empty());
+        let (response, status) = exchange(&mut dispatch, piv, hex!("00 CB 3F FF"), &data[2..]);
+        assert_eq!(status, [0x90, 0x00]);
+
+        let mut expected = hex!("53 82 01 F4").to_vec();
+        expected.extend_from_slice(&certificate);
+        assert_eq!(response, expected);
+    });
+}
+
+#[test]
+fn proprietary_class() {
+    setup::piv(|piv| {
+        let mut dispatch = dispatch();
+
+        // SELECT PIV
+        let (_, status) = exchange(&mut dispatch, piv, hex!("00 A4 04 00"), &piv_authenticator::constants::PIV_AID);
+        assert_eq!(status, [0x90, 0x00]);
+
+        // ykGetVersion, in the proprietary class
+        let response = transmit(&mut dispatch, piv, &hex!("80 FD 00 00"));
+        assert_eq!(response[response.len() - 2..], [0x90, 0x00]);
+
+        // logical channel 1 is still not supported
+        assert_eq!(transmit(&mut dispatch, piv, &hex!("01 FD 00 00")), [0x68, 0x81]);
+    });
+}
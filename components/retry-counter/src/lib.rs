@@ -0,0 +1,129 @@
+//! # retry-counter
+//!
+//! A persistent count of the attempts left to enter a PIN (or any other secret),
+//! kept as a file in the trussed store.
+//!
+//! An attempt is spent, and the new count written, *before* the secret is checked.
+//! Cutting the power between the check and its answer (e.g. to try a PIN without the
+//! failure being recorded) therefore costs an attempt, just like a wrong guess.
+//! Only a successful check gives the attempts back.
+//!
+//! The count is stored as a single byte. Each update rewrites the whole file, which
+//! littlefs commits atomically.
+#![no_std]
+
+#[macro_use]
+extern crate delog;
+generate_macros!();
+
+use trussed::{
+    try_syscall,
+    Client as TrussedClient,
+    types::{Location, Message, PathBuf},
+};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// No attempts are left.
+    Blocked,
+    /// The counter file exists, but does not hold a valid count.
+    Corrupted,
+    /// The counter file could not be read, or the new count could not be written.
+    Storage,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// What `RetryCounter::verify` found.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Verification {
+    /// The secret was correct, all attempts are available again.
+    Verified,
+    /// The secret was wrong, `remaining` attempts are left.
+    Failed { remaining: u8 },
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RetryCounter {
+    location: Location,
+    path: &'static [u8],
+    max: u8,
+}
+
+impl RetryCounter {
+    /// A counter allowing `max` consecutive failed attempts, in internal flash,
+    /// at a path relative to the client's directory.
+    pub const fn new(path: &'static [u8], max: u8) -> Self {
+        Self { location: Location::Internal, path, max }
+    }
+
+    pub fn max(&self) -> u8 {
+        self.max
+    }
+
+    /// The attempts left; a counter that was never used has all of them.
+    ///
+    /// Only a missing file has all attempts. A file that exists but fails to read is `Storage`,
+    /// which `consume` and `verify` pass on without checking the secret.
+    pub fn remaining<T: TrussedClient>(&self, trussed: &mut T) -> Result<u8> {
+        // the file is only written on the first attempt. `read_file` fails the same way
+        // for a missing file and a damaged one, so look for it first.
+        let located = try_syscall!(trussed.locate_file(self.location, None, PathBuf::from(self.path)))
+            .map_err(|_| Error::Storage)?
+            .path;
+        if located.is_none() {
+            return Ok(self.max);
+        }
+
+        let data = try_syscall!(trussed.read_file(self.location, PathBuf::from(self.path)))
+            .map_err(|_| {
+                info!("retry counter exists, but could not be read");
+                Error::Storage
+            })?
+            .data;
+
+        match data[..] {
+            [remaining] if remaining <= self.max => Ok(remaining),
+            _ => {
+                info!("retry counter has {} bytes", data.len());
+                Err(Error::Corrupted)
+            }
+        }
+    }
+
+    /// Spends an attempt, before the secret is checked, and returns the attempts left.
+    ///
+    /// The new count is written before this returns, so the check must only run after.
+    pub fn consume<T: TrussedClient>(&self, trussed: &mut T) -> Result<u8> {
+        let remaining = self.remaining(trussed)?
+            .checked_sub(1)
+            .ok_or(Error::Blocked)?;
+        self.write(trussed, remaining)?;
+        Ok(remaining)
+    }
+
+    /// Gives back all attempts, after a successful check.
+    pub fn reset<T: TrussedClient>(&self, trussed: &mut T) -> Result<()> {
+        self.write(trussed, self.max)
+    }
+
+    /// Spends an attempt, then runs `check`, and gives the attempts back if it succeeds.
+    ///
+    /// `check` is not run once no attempts are left.
+    pub fn verify<T: TrussedClient>(&self, trussed: &mut T, check: impl FnOnce(&mut T) -> bool) -> Result<Verification> {
+        let remaining = self.consume(trussed)?;
+        if check(trussed) {
+            self.reset(trussed)?;
+            Ok(Verification::Verified)
+        } else {
+            Ok(Verification::Failed { remaining })
+        }
+    }
+
+    fn write<T: TrussedClient>(&self, trussed: &mut T, remaining: u8) -> Result<()> {
+        let data = Message::try_from_slice(&[remaining]).unwrap();
+        try_syscall!(trussed.write_file(self.location, PathBuf::from(self.path), data, None))
+            .map(drop)
+            .map_err(|_| Error::Storage)
+    }
+}
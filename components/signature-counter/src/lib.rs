@@ -0,0 +1,104 @@
+//! # signature-counter
+//!
+//! A persistent, monotonic counter for signatures (e.g. the CTAP1 `counter`),
+//! kept as a file in the trussed store.
+//!
+//! The counter is stored as four big-endian bytes. Each increment rewrites the whole
+//! file, which littlefs commits atomically: after a power loss, the file holds either
+//! the old or the new value, never a mix of both.
+#![no_std]
+
+#[macro_use]
+extern crate delog;
+generate_macros!();
+
+use core::convert::TryInto;
+
+use trussed::{
+    try_syscall,
+    Client as TrussedClient,
+    types::{Location, Message, PathBuf},
+};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The counter file exists, but does not hold a counter.
+    Corrupted,
+    /// The counter reached `u32::MAX`, it would wrap on the next increment.
+    Exhausted,
+    /// The counter file could not be read, or the new value could not be written.
+    Storage,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SignatureCounter {
+    location: Location,
+    path: &'static [u8],
+}
+
+impl SignatureCounter {
+    pub const DEFAULT_PATH: &'static [u8] = b"signature-counter";
+
+    /// A counter in internal flash, at a path relative to the client's directory.
+    pub const fn new(path: &'static [u8]) -> Self {
+        Self { location: Location::Internal, path }
+    }
+
+    /// The current value; a counter that was never incremented is 0.
+    ///
+    /// Only a missing file reads as 0, a file that exists but fails to read is `Storage`.
+    pub fn read<T: TrussedClient>(&self, trussed: &mut T) -> Result<u32> {
+        // the file is only written on the first increment. `read_file` fails the same way
+        // for a missing file and a damaged one, so look for it first.
+        let located = try_syscall!(trussed.locate_file(self.location, None, PathBuf::from(self.path)))
+            .map_err(|_| Error::Storage)?
+            .path;
+        if located.is_none() {
+            return Ok(0);
+        }
+
+        let data = try_syscall!(trussed.read_file(self.location, PathBuf::from(self.path)))
+            .map_err(|_| {
+                info!("signature counter exists, but could not be read");
+                Error::Storage
+            })?
+            .data;
+
+        let bytes: [u8; 4] = data[..].try_into().map_err(|_| {
+            info!("signature counter has {} bytes", data.len());
+            Error::Corrupted
+        })?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    /// Increments the counter, persists it, and returns the new value.
+    ///
+    /// The new value is only returned once it is written, so a value is never handed out twice.
+    pub fn increment<T: TrussedClient>(&self, trussed: &mut T) -> Result<u32> {
+        self.increment_from(trussed, 0)
+    }
+
+    /// Increments the counter as if it held at least `floor`, persists it, and returns the new value.
+    ///
+    /// This takes over from a counter kept elsewhere before: with that counter's value as `floor`,
+    /// the first value returned here is above anything it handed out.
+    pub fn increment_from<T: TrussedClient>(&self, trussed: &mut T, floor: u32) -> Result<u32> {
+        let counter = self.read(trussed)?
+            .max(floor)
+            .checked_add(1)
+            .ok_or(Error::Exhausted)?;
+
+        let data = Message::try_from_slice(&counter.to_be_bytes()).unwrap();
+        try_syscall!(trussed.write_file(self.location, PathBuf::from(self.path), data, None))
+            .map_err(|_| Error::Storage)?;
+        Ok(counter)
+    }
+}
+
+impl Default for SignatureCounter {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_PATH)
+    }
+}
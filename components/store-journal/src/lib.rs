@@ -0,0 +1,193 @@
+//! # store-journal
+//!
+//! Updates several files of a trussed store at once: after a crash or power loss,
+//! either all of them have their new contents, or none.
+//!
+//! A `Transaction` first records the paths it is about to write in a manifest
+//! (`.journal`), marked pending, and writes the new contents next to it
+//! (`.journal.0`, `.journal.1`, ...). Committing marks the manifest committed, which
+//! littlefs does atomically, then renames the staged files over their targets and
+//! removes the manifest.
+//!
+//! `recover` must run after mounting, before the files are used. It discards the
+//! staged files of a pending transaction, and finishes the renames of a committed one.
+#![no_std]
+
+#[macro_use]
+extern crate delog;
+generate_macros!();
+
+use heapless::{consts, Vec};
+use trussed::{
+    store::{self, Store},
+    types::{Location, PathBuf},
+};
+
+/// Files a single transaction can write.
+pub const MAX_FILES: usize = 8;
+type MaxFiles = consts::U8;
+
+const MANIFEST: &[u8] = b".journal";
+const PENDING: u8 = 0;
+const COMMITTED: u8 = 1;
+
+// state byte, then a length-prefixed path per file
+type ManifestSize = consts::U2048;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// More than `MAX_FILES` files, or paths too long to fit in the manifest.
+    TooLarge,
+    /// The manifest exists, but cannot be parsed.
+    Corrupted,
+    Storage,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// What `recover` found.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Recovery {
+    /// No transaction was interrupted.
+    Clean,
+    /// A transaction was interrupted before its commit, its writes were discarded.
+    RolledBack,
+    /// A transaction was interrupted during its commit, its writes were completed.
+    RolledForward,
+}
+
+pub struct Transaction<S: Store> {
+    store: S,
+    location: Location,
+    paths: Vec<PathBuf, MaxFiles>,
+}
+
+impl<S: Store> Transaction<S> {
+    /// Starts a transaction on the files of `location`. Only one may be in progress
+    /// at a time, and the previous one must have been committed or recovered.
+    pub fn begin(store: S, location: Location) -> Self {
+        Self { store, location, paths: Vec::new() }
+    }
+
+    /// Stages `contents` for `path`, which keeps its old contents until `commit`.
+    pub fn write(&mut self, path: &[u8], contents: &[u8]) -> Result<()> {
+        let index = self.paths.len();
+        self.paths.push(PathBuf::from(path)).map_err(|_| Error::TooLarge)?;
+        // record the staged file before creating it, so `recover` can find it
+        if let Err(error) = write_manifest(self.store, self.location, PENDING, &self.paths) {
+            self.paths.pop();
+            return Err(error);
+        }
+        store::store(self.store, self.location, &staged_path(index), contents)
+            .map_err(|_| Error::Storage)
+    }
+
+    /// Replaces all staged files at once.
+    pub fn commit(self) -> Result<()> {
+        if self.paths.is_empty() {
+            return Ok(());
+        }
+        write_manifest(self.store, self.location, COMMITTED, &self.paths)?;
+        apply(self.store, self.location, &self.paths)
+    }
+
+    /// Discards the staged files.
+    pub fn abort(self) -> Result<()> {
+        discard(self.store, self.location, self.paths.len())
+    }
+}
+
+/// Completes or discards a transaction interrupted by a crash. Call after mounting.
+pub fn recover<S: Store>(store: S, location: Location) -> Result<Recovery> {
+    let manifest_path = PathBuf::from(MANIFEST);
+    if !store::exists(store, location, &manifest_path) {
+        return Ok(Recovery::Clean);
+    }
+    let manifest = store::read::<ManifestSize>(store, location, &manifest_path)
+        .map_err(|_| Error::Storage)?;
+    let (state, paths) = parse_manifest(&manifest)?;
+    if state == COMMITTED {
+        info!("finishing an interrupted commit of {} files", paths.len());
+        apply(store, location, &paths)?;
+        Ok(Recovery::RolledForward)
+    } else {
+        info!("discarding {} staged files", paths.len());
+        discard(store, location, paths.len())?;
+        Ok(Recovery::RolledBack)
+    }
+}
+
+fn staged_path(index: usize) -> PathBuf {
+    let mut name: Vec<u8, consts::U16> = Vec::new();
+    name.extend_from_slice(MANIFEST).ok();
+    name.push(b'.').ok();
+    name.push(b'0' + index as u8).ok();
+    PathBuf::from(&name[..])
+}
+
+fn write_manifest<S: Store>(store: S, location: Location, state: u8, paths: &[PathBuf]) -> Result<()> {
+    let mut manifest: Vec<u8, ManifestSize> = Vec::new();
+    manifest.push(state).map_err(|_| Error::TooLarge)?;
+    for path in paths {
+        let path: &str = path.as_ref();
+        let path = path.as_bytes();
+        if path.len() > u8::MAX as usize {
+            return Err(Error::TooLarge);
+        }
+        manifest.push(path.len() as u8).map_err(|_| Error::TooLarge)?;
+        manifest.extend_from_slice(path).map_err(|_| Error::TooLarge)?;
+    }
+    store::store(store, location, &PathBuf::from(MANIFEST), &manifest)
+        .map_err(|_| Error::Storage)
+}
+
+fn parse_manifest(manifest: &[u8]) -> Result<(u8, Vec<PathBuf, MaxFiles>)> {
+    let (&state, mut rest) = manifest.split_first().ok_or(Error::Corrupted)?;
+    if state != PENDING && state != COMMITTED {
+        return Err(Error::Corrupted);
+    }
+    let mut paths = Vec::new();
+    while let Some((&len, tail)) = rest.split_first() {
+        if tail.len() < len as usize {
+            return Err(Error::Corrupted);
+        }
+        let (path, tail) = tail.split_at(len as usize);
+        paths.push(PathBuf::from(path)).map_err(|_| Error::Corrupted)?;
+        rest = tail;
+    }
+    Ok((state, paths))
+}
+
+// Renames the staged files that are still there, then forgets the transaction.
+fn apply<S: Store>(store: S, location: Location, paths: &[PathBuf]) -> Result<()> {
+    for (index, path) in paths.iter().enumerate() {
+        let staged = staged_path(index);
+        // already renamed before an interruption
+        if !store::exists(store, location, &staged) {
+            continue;
+        }
+        // littlefs renames over existing files
+        let renamed = match location {
+            Location::Internal => store.ifs().rename(&staged, path),
+            Location::External => store.efs().rename(&staged, path),
+            Location::Volatile => store.vfs().rename(&staged, path),
+        };
+        renamed.map_err(|_| Error::Storage)?;
+    }
+    store::delete(store, location, &PathBuf::from(MANIFEST));
+    Ok(())
+}
+
+fn discard<S: Store>(store: S, location: Location, count: usize) -> Result<()> {
+    for index in 0..count {
+        let staged = staged_path(index);
+        if store::exists(store, location, &staged) {
+            store::delete(store, location, &staged);
+        }
+    }
+    let manifest_path = PathBuf::from(MANIFEST);
+    if store::exists(store, location, &manifest_path) && !store::delete(store, location, &manifest_path) {
+        return Err(Error::Storage);
+    }
+    Ok(())
+}
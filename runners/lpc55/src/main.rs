@@ -128,7 +128,7 @@ const APP: () = {
                 app::Delogger::flush();
             }
 
-            match apdu_dispatch.poll(&mut [ndef, piv, totp, fido, management]) {
+            match apdu_dispatch.poll(&mut [ndef, piv, totp, fido, management]).responded {
 
                 Some(apdu_dispatch::dispatch::Interface::Contact) => {
                     rtic::pend(USB_INTERRUPT);
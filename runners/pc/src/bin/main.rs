@@ -1,9 +1,8 @@
-use std::{fs::File, io::Write};
 pub use embedded_hal::blocking::rng;
 use littlefs2::{
     const_ram_storage,
 };
-use littlefs2::fs::{Allocation, Filesystem};
+use littlefs2::fs::Filesystem;
 use trussed::types::{LfsResult, LfsStorage};
 
 use trussed::platform::{
@@ -12,6 +11,11 @@ use trussed::platform::{
     consent,
 };
 use trussed::{platform, store};
+use solo_pc::FileFlash;
+use std::sync::Arc;
+use solo_pc::control::{Control, Reboot};
+use solo_pc::trace;
+use interchange::Interchange;
 use ctap_types::consts;
 
 pub use generic_array::{
@@ -19,95 +23,22 @@ pub use generic_array::{
     typenum::{U16, U512},
 };
 
-use generic_array::typenum::{U256, U1022};
-
-
 const SOLO_STATE: &'static str = "solo-state.bin";
 
-#[allow(non_camel_case_types)]
-pub mod littlefs_params {
-    use super::*;
-    pub const READ_SIZE: usize = 16;
-    pub const WRITE_SIZE: usize = 512;
-    pub const BLOCK_SIZE: usize = 512;
-
-    pub const BLOCK_COUNT: usize = 256;
-    // no wear-leveling for now
-    pub const BLOCK_CYCLES: isize = -1;
-
-    pub type CACHE_SIZE = U512;
-    pub type LOOKAHEADWORDS_SIZE = U16;
-    /// TODO: We can't actually be changed currently
-    pub type FILENAME_MAX_PLUS_ONE = U256;
-    pub type PATH_MAX_PLUS_ONE = U256;
-    pub const FILEBYTES_MAX: usize = littlefs2::ll::LFS_FILE_MAX as _;
-    /// TODO: We can't actually be changed currently
-    pub type ATTRBYTES_MAX = U1022;
-}
-
-pub struct FileFlash {
-    state: [u8; 128 * 1024],
-}
-impl FileFlash {
-    pub fn new() -> Self {
-        let mut state = [0u8; 128 * 1024];
-
-        if let Ok(contents) = std::fs::read(SOLO_STATE) {
-            println!("loaded {}", SOLO_STATE);
-            state.copy_from_slice( contents.as_slice() );
-            Self {state}
-        } else {
-            println!("No state yet, creating");
-            Self {state}
-        }
-    }
-}
-
-impl littlefs2::driver::Storage for FileFlash {
-    const READ_SIZE: usize = littlefs_params::READ_SIZE;
-    const WRITE_SIZE: usize = littlefs_params::WRITE_SIZE;
-    const BLOCK_SIZE: usize = littlefs_params::BLOCK_SIZE;
-
-    const BLOCK_COUNT: usize = littlefs_params::BLOCK_COUNT;
-    const BLOCK_CYCLES: isize = littlefs_params::BLOCK_CYCLES;
-
-    type CACHE_SIZE = littlefs_params::CACHE_SIZE;
-    type LOOKAHEADWORDS_SIZE = littlefs_params::LOOKAHEADWORDS_SIZE;
-    type FILENAME_MAX_PLUS_ONE = littlefs_params::FILENAME_MAX_PLUS_ONE;
-    type PATH_MAX_PLUS_ONE = littlefs_params::PATH_MAX_PLUS_ONE;
-    const FILEBYTES_MAX: usize = littlefs_params::FILEBYTES_MAX;
-    type ATTRBYTES_MAX = littlefs_params::ATTRBYTES_MAX;
-
-
-    fn read(&self, off: usize, buf: &mut [u8]) -> LfsResult<usize> {
-        for i in 0 .. buf.len() {
-            buf[i] = self.state[i + off];
-        }
-        Ok(buf.len())
-    }
-
-    fn write(&mut self, off: usize, data: &[u8]) -> LfsResult<usize> {
-        for i in 0 .. data.len() {
-            self.state[i + off] = data[i];
-        }
-        let mut buffer = File::create(SOLO_STATE).unwrap();
-        buffer.write(&self.state).unwrap();
-
-        Ok(data.len())
-    }
-
-    fn erase(&mut self, off: usize, len: usize) -> LfsResult<usize> {
-        for i in 0 .. len {
-            self.state[i + off] = 0;
-        }
-        let mut buffer = File::create(SOLO_STATE).unwrap();
-        buffer.write(&self.state).unwrap();
-        Ok(len)
-    }
+/// Number of authenticators that can run side by side (`--count N`).
+const MAX_INSTANCES: usize = 4;
 
-}
+/// Size of the volatile filesystem in bytes: 8KB by default, more with the
+/// `volatile-16k` or `volatile-32k` feature (the larger one wins if both are set).
+#[cfg(not(any(feature = "volatile-16k", feature = "volatile-32k")))]
+const VOLATILE_SIZE: usize = 8192;
+#[cfg(all(feature = "volatile-16k", not(feature = "volatile-32k")))]
+const VOLATILE_SIZE: usize = 16384;
+#[cfg(feature = "volatile-32k")]
+const VOLATILE_SIZE: usize = 32768;
 
-// 8KB of RAM
+// The size must stay a multiple of the 128 byte block size, and at most
+// 256 blocks (32KB) are covered by the 8 lookahead words.
 const_ram_storage!(
     name=VolatileStorage,
     trait=LfsStorage,
@@ -119,25 +50,33 @@ const_ram_storage!(
     // https://git.io/JeHp9
     block_size=128,
     // block_size=128,
-    block_count=8192/128,
+    block_count=VOLATILE_SIZE/128,
     lookaheadwords_size_ty=consts::U8,
     filename_max_plus_one_ty=consts::U256,
     path_max_plus_one_ty=consts::U256,
     result=LfsResult,
 );
 
-// minimum: 2 blocks
+// littlefs needs at least 2 blocks for the superblock pair, and more for any file
 // TODO: make this optional
-const_ram_storage!(ExternalStorage, 1024);
-
-store!(Store,
-    Internal: FileFlash,
-    External: ExternalStorage,
-    Volatile: VolatileStorage
+const_ram_storage!(
+    name=ExternalStorage,
+    trait=LfsStorage,
+    erase_value=0x00,
+    read_size=1,
+    write_size=1,
+    cache_size_ty=consts::U128,
+    block_size=128,
+    block_count=4096/128,
+    lookaheadwords_size_ty=consts::U1,
+    filename_max_plus_one_ty=consts::U256,
+    path_max_plus_one_ty=consts::U256,
+    result=LfsResult,
 );
 
 
 
+
 // #[derive(Default)]
 // pub struct Rng {
 //     count: u64,
@@ -158,12 +97,17 @@ store!(Store,
 
 #[derive(Default)]
 pub struct UserInterface {
+    control: Option<Arc<Control>>,
 }
 
 impl trussed::platform::UserInterface for UserInterface
 {
     fn check_user_presence(&mut self) -> consent::Level {
-        consent::Level::Normal
+        // `deny` on the control socket refuses a check, otherwise all are confirmed
+        match self.control.as_ref().and_then(|control| control.user_presence()) {
+            Some(false) => consent::Level::None,
+            _ => consent::Level::Normal,
+        }
     }
 
     fn set_status(&mut self, status: ui::Status) {
@@ -173,7 +117,12 @@ impl trussed::platform::UserInterface for UserInterface
     }
 
     fn refresh(&mut self) {
-
+        if let Some(to) = self.control.as_ref().and_then(|control| control.take_reboot()) {
+            self.reboot(match to {
+                Reboot::Application => reboot::To::Application,
+                Reboot::Bootloader => reboot::To::ApplicationUpdate,
+            });
+        }
     }
 
     fn uptime(&mut self) -> core::time::Duration {
@@ -182,71 +131,352 @@ impl trussed::platform::UserInterface for UserInterface
 
     fn reboot(&mut self, to: reboot::To) -> ! {
         println!("Restart!  ({:?})", to);
-        std::process::exit(25);
+        solo_pc::persist_logs();
+        std::process::exit(match to {
+            reboot::To::Application => solo_pc::EXIT_REBOOT,
+            reboot::To::ApplicationUpdate => solo_pc::EXIT_REBOOT_TO_UPDATE,
+        });
     }
 
 }
 
-platform!(Board,
-    R: chacha20::ChaCha8Rng,
-    S: Store,
-    UI: UserInterface,
-);
+// trussed stores are singletons, so each authenticator gets its own `Store` and `Board` type.
+macro_rules! instance {
+    ($store:ident, $board:ident, $run:ident, $instance:expr) => {
+        store!($store,
+            Internal: FileFlash,
+            External: ExternalStorage,
+            Volatile: VolatileStorage
+        );
+
+        platform!($board,
+            R: solo_pc::PlatformRng,
+            S: $store,
+            UI: UserInterface,
+        );
+
+        fn $run(state_file: &str, factory_reset: bool, control: Option<Arc<Control>>) -> (trussed::service::Service<$board>, Arc<solo_pc::Wear>) {
+            let internal_storage = Box::leak(Box::new(FileFlash::new(state_file)));
+            let wear = internal_storage.wear();
+            if let Some(control) = control.as_ref() {
+                control.watch_wear(wear.clone());
+            }
+            let internal_fs_alloc = Box::leak(Box::new(Filesystem::allocate()));
+            let external_storage = Box::leak(Box::new(ExternalStorage::new()));
+            let external_fs_alloc = Box::leak(Box::new(Filesystem::allocate()));
+            let volatile_storage = Box::leak(Box::new(VolatileStorage::new()));
+            let volatile_fs_alloc = Box::leak(Box::new(Filesystem::allocate()));
+
+            if factory_reset {
+                solo_pc::factory_reset(internal_storage).expect("can factory reset");
+            }
+
+            // RAM starts out blank on every run, internal flash on the first one.
+            // Each is formatted on its own, a failed mount never formats all of them.
+            let formatted = solo_pc::format_unmountable(internal_storage, external_storage, volatile_storage)
+                .expect("can format storage");
+            if formatted.internal {
+                println!("Internal flash not yet formatted!  Formatted it.");
+            }
+
+            let store = $store::claim().unwrap();
+
+            store.mount(
+                internal_fs_alloc,
+                internal_storage,
+                external_fs_alloc,
+                external_storage,
+                volatile_fs_alloc,
+                volatile_storage,
+                // to trash existing data, set to true
+                false,
+            ).expect("can mount freshly checked filesystems");
+
+            // finish or undo whatever was cut short by the previous run
+            match store_journal::recover(store, trussed::types::Location::Internal) {
+                Ok(store_journal::Recovery::Clean) => {}
+                Ok(recovery) => println!("Interrupted transaction: {:?}", recovery),
+                Err(error) => println!("Could not recover interrupted transaction: {:?}", error),
+            }
+
+            // no format changes yet, the stored data stays at version 1
+            solo_pc::migrate(store, &[]).expect("can migrate stored data");
+
+            // every instance seeds its own RNG, differently even from a shared seed
+            let rng = solo_pc::PlatformRng::from_env_for_instance($instance);
+            let pc_interface = UserInterface { control };
+
+            let board = $board::new(rng, store, pc_interface);
+            (trussed::service::Service::new(board), wear)
+        }
+    }
+}
 
-fn main () {
+instance!(Store0, Board0, service0, 0);
+instance!(Store1, Board1, service1, 1);
+instance!(Store2, Board2, service2, 2);
+instance!(Store3, Board3, service3, 3);
+
+struct Args {
+    count: usize,
+    /// Print the erase counts of each instance's internal flash at exit (`--wear-report`).
+    wear_report: bool,
+    /// Replace the state files at startup, with `SOLO2_STATE_SEED` if set (`--reset`).
+    reset: bool,
+    /// Wipe internal flash at startup, keeping a copy of it (`--factory-reset`).
+    factory_reset: bool,
+    /// Copy the state files here at exit (`--snapshot PATH`).
+    snapshot: Option<String>,
+    /// Check the first authenticator's storage and crypto, then exit (`--self-test`).
+    self_test: bool,
+    /// Fail the request instead of exiting when an app panics (`--continue-on-panic`).
+    panic_policy: solo_pc::PanicPolicy,
+}
 
-    let filesystem = FileFlash::new();
+fn parse_args() -> Args {
+    let mut args = std::env::args().skip(1);
+    let mut count = 1;
+    let mut wear_report = false;
+    let mut reset = false;
+    let mut factory_reset = false;
+    let mut snapshot = None;
+    let mut self_test = false;
+    let mut panic_policy = solo_pc::PanicPolicy::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--count" => {
+                count = args.next()
+                    .and_then(|count| count.parse().ok())
+                    .expect("--count expects a number");
+            }
+            "--wear-report" => {
+                wear_report = true;
+            }
+            "--reset" => {
+                reset = true;
+            }
+            "--factory-reset" => {
+                factory_reset = true;
+            }
+            "--snapshot" => {
+                snapshot = Some(args.next().expect("--snapshot expects a path"));
+            }
+            "--self-test" => {
+                self_test = true;
+            }
+            "--continue-on-panic" => {
+                panic_policy = solo_pc::PanicPolicy::Continue;
+            }
+            _ => {
+                eprintln!("unknown argument: {}", arg);
+                std::process::exit(2);
+            }
+        }
+    }
+    if count == 0 || count > MAX_INSTANCES {
+        eprintln!("--count must be between 1 and {}", MAX_INSTANCES);
+        std::process::exit(2);
+    }
+    Args { count, wear_report, reset, factory_reset, snapshot, self_test, panic_policy }
+}
 
-    static mut INTERNAL_STORAGE: Option<FileFlash> = None;
-    unsafe { INTERNAL_STORAGE = Some(filesystem); }
-    static mut INTERNAL_FS_ALLOC: Option<Allocation<FileFlash>> = None;
-    unsafe { INTERNAL_FS_ALLOC = Some(Filesystem::allocate()); }
+fn state_file(index: usize, count: usize) -> String {
+    if count == 1 {
+        SOLO_STATE.to_string()
+    } else {
+        format!("solo-state-{}.bin", index)
+    }
+}
 
-    static mut EXTERNAL_STORAGE: ExternalStorage = ExternalStorage::new();
-    static mut EXTERNAL_FS_ALLOC: Option<Allocation<ExternalStorage>> = None;
-    unsafe { EXTERNAL_FS_ALLOC = Some(Filesystem::allocate()); }
+// with several instances, each gets its own snapshot
+fn snapshot_file(path: &str, index: usize, count: usize) -> String {
+    if count == 1 {
+        path.to_string()
+    } else {
+        format!("{}.{}", path, index)
+    }
+}
 
-    static mut VOLATILE_STORAGE: VolatileStorage = VolatileStorage::new();
-    static mut VOLATILE_FS_ALLOC: Option<Allocation<VolatileStorage>> = None;
-    unsafe { VOLATILE_FS_ALLOC = Some(Filesystem::allocate()); }
+/// Replays the trace named by `SOLO2_APDU_REPLAY` against the FIDO app of the first
+/// authenticator, recording to `SOLO2_APDU_RECORD` if that is set as well.
+fn replay_trace(trussed: &mut trussed::service::Service<Board0>, path: &str, policy: solo_pc::PanicPolicy) {
+    let records = trace::load(path).unwrap_or_else(|err| {
+        eprintln!("cannot load trace {}: {}", path, err);
+        std::process::exit(2);
+    });
+
+    let client = trussed.try_as_new_client("fido").unwrap();
+    let authnr = fido_authenticator::Authenticator::new(
+        client,
+        fido_authenticator::SilentAuthenticator {},
+    );
+    let mut fido = dispatch_fido::Fido::new(authnr);
+
+    let (mut contact, contact_responder) = apdu_dispatch::interchanges::Contact::claim().unwrap();
+    let (mut contactless, contactless_responder) = apdu_dispatch::interchanges::Contactless::claim().unwrap();
+    let mut apdu_dispatch = apdu_dispatch::dispatch::ApduDispatch::new(contact_responder, contactless_responder);
+    if let Some(recorder) = trace::Recorder::from_env() {
+        apdu_dispatch.set_tap(Box::leak(Box::new(recorder)));
+    }
 
+    match trace::replay(&records, &mut apdu_dispatch, &mut [&mut fido], &mut contact, &mut contactless, policy) {
+        Ok(commands) => println!("replayed {} command(s) from {}", commands, path),
+        Err(mismatch) => {
+            eprintln!("{}", mismatch);
+            solo_pc::Delogger::flush();
+            std::process::exit(1);
+        }
+    }
+}
 
-    let store = Store::claim().unwrap();
+/// Waits for `reboot` on the control socket, flushing logs meanwhile.
+fn wait_for_reboot(control: &Control) -> Reboot {
+    loop {
+        if let Some(to) = control.take_reboot() {
+            println!("Restart!  ({:?})", to);
+            return to;
+        }
+        solo_pc::Delogger::flush();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+}
 
-    let result = store.mount(
-        unsafe { INTERNAL_FS_ALLOC.as_mut().unwrap() },
-        // unsafe { &mut INTERNAL_STORAGE },
-        unsafe { INTERNAL_STORAGE.as_mut().unwrap() },
-        unsafe { EXTERNAL_FS_ALLOC.as_mut().unwrap() },
-        unsafe { &mut EXTERNAL_STORAGE },
-        unsafe { VOLATILE_FS_ALLOC.as_mut().unwrap() },
-        unsafe { &mut VOLATILE_STORAGE },
-        // to trash existing data, set to true
-        false,
-    );
+/// Checks that the first authenticator can store files and sign, by driving its
+/// trussed service directly (`--self-test`). Cleans up after itself.
+fn self_test(trussed: &mut trussed::service::Service<Board0>) -> bool {
+    use trussed::client::{CryptoClient, FilesystemClient, P256};
+    use trussed::try_syscall;
+    use trussed::types::{Location, Message, PathBuf, SignatureSerialization};
+
+    let mut client = trussed.try_as_new_client("self-test").unwrap();
+    let mut passed = true;
+    let mut report = |step: &str, ok: bool| {
+        println!("self-test: {:<8} {}", step, if ok { "ok" } else { "FAILED" });
+        passed &= ok;
+        ok
+    };
+
+    let path = PathBuf::from(b"self-test");
+    let data = Message::try_from_slice(b"self-test").unwrap();
+    let stored = try_syscall!(client.write_file(Location::Internal, path.clone(), data.clone(), None)).is_ok();
+    let read = stored && try_syscall!(client.read_file(Location::Internal, path.clone()))
+        .map(|reply| reply.data == data).unwrap_or(false);
+    report("storage", read);
+    if stored {
+        try_syscall!(client.remove_file(Location::Internal, path)).ok();
+    }
+
+    let private_key = try_syscall!(client.generate_p256_private_key(Location::Volatile)).map(|reply| reply.key).ok();
+    if report("keygen", private_key.is_some()) {
+        let private_key = private_key.unwrap();
+        let public_key = try_syscall!(client.derive_p256_public_key(private_key.clone(), Location::Volatile))
+            .map(|reply| reply.key).ok();
+        let message = b"hello trussed";
+        let signature = try_syscall!(client.sign_p256(private_key.clone(), message, SignatureSerialization::Raw))
+            .map(|reply| reply.signature).ok();
+        report("sign", signature.is_some());
+        if let (Some(public_key), Some(signature)) = (public_key.as_ref(), signature.as_ref()) {
+            let valid = try_syscall!(client.verify_p256(public_key.clone(), message, signature))
+                .map(|reply| reply.valid).unwrap_or(false);
+            // and a signature does not cover a different message
+            let forged = try_syscall!(client.verify_p256(public_key.clone(), b"hello trussed?", signature))
+                .map(|reply| reply.valid).unwrap_or(true);
+            report("verify", valid && !forged);
+        } else {
+            report("verify", false);
+        }
 
-    if result.is_err() {
-        println!("Not yet formatted!  Formatting..");
-        store.mount(
-            unsafe { INTERNAL_FS_ALLOC.as_mut().unwrap() },
-            // unsafe { &mut INTERNAL_STORAGE },
-            unsafe { INTERNAL_STORAGE.as_mut().unwrap() },
-            unsafe { EXTERNAL_FS_ALLOC.as_mut().unwrap() },
-            unsafe { &mut EXTERNAL_STORAGE },
-            unsafe { VOLATILE_FS_ALLOC.as_mut().unwrap() },
-            unsafe { &mut VOLATILE_STORAGE },
-            // to trash existing data, set to true
-            true,
-        ).unwrap();
+        if let Some(public_key) = public_key {
+            try_syscall!(client.delete(public_key)).ok();
+        }
+        try_syscall!(client.delete(private_key)).ok();
     }
 
+    passed
+}
 
-    use trussed::service::SeedableRng;
-    let rng = chacha20::ChaCha8Rng::from_seed([0u8; 32]);
-    let pc_interface: UserInterface = Default::default();
+fn main () {
 
-    let board = Board::new(rng, store, pc_interface);
-    let mut _trussed = trussed::service::Service::new(board);
+    solo_pc::init_logging();
+    solo_pc::install_panic_hook();
+
+    let args = parse_args();
+    let count = args.count;
+
+    // every instance starts from the same seed
+    let seed = std::env::var_os(solo_pc::STATE_SEED_ENV).map(std::path::PathBuf::from);
+    for index in 0..count {
+        let state_file = state_file(index, count);
+        match solo_pc::seed_state(&state_file, seed.as_deref(), args.reset) {
+            Ok(true) => println!("reset {}", state_file),
+            Ok(false) => {}
+            Err(err) => {
+                eprintln!("cannot seed {}: {}", state_file, err);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let control = Control::from_env().unwrap_or_else(|err| {
+        eprintln!("cannot open control socket: {}", err);
+        std::process::exit(2);
+    });
+
+    let (mut _trussed0, wear0) = service0(&state_file(0, count), args.factory_reset, control.clone());
+    let mut wear = vec![wear0];
+    let mut _trussed1 = if count > 1 {
+        let (trussed, wear1) = service1(&state_file(1, count), args.factory_reset, control.clone());
+        wear.push(wear1);
+        Some(trussed)
+    } else { None };
+    let mut _trussed2 = if count > 2 {
+        let (trussed, wear2) = service2(&state_file(2, count), args.factory_reset, control.clone());
+        wear.push(wear2);
+        Some(trussed)
+    } else { None };
+    let mut _trussed3 = if count > 3 {
+        let (trussed, wear3) = service3(&state_file(3, count), args.factory_reset, control.clone());
+        wear.push(wear3);
+        Some(trussed)
+    } else { None };
+
+    println!("hello trussed, running {} authenticator(s)", count);
+
+    if args.self_test {
+        let passed = self_test(&mut _trussed0);
+        solo_pc::Delogger::flush();
+        std::process::exit(if passed { 0 } else { 1 });
+    }
 
-    println!("hello trussed");
+    if let Ok(path) = std::env::var(trace::REPLAY_ENV) {
+        replay_trace(&mut _trussed0, &path, args.panic_policy);
+    }
+
+    // a scripted run goes on until the script asks for a reboot
+    let reboot = control.as_ref().map(|control| wait_for_reboot(control));
+
+    if let Some(snapshot) = args.snapshot.as_ref() {
+        for index in 0..count {
+            let out = snapshot_file(snapshot, index, count);
+            if let Err(err) = solo_pc::snapshot_state(state_file(index, count), &out) {
+                eprintln!("cannot snapshot to {}: {}", out, err);
+            }
+        }
+    }
+
+    if args.wear_report {
+        for (index, wear) in wear.iter().enumerate() {
+            println!("erases of {} (block: count):", state_file(index, count));
+            for (block, erases) in wear.report() {
+                println!("  {:3}: {}", block, erases);
+            }
+        }
+    }
+    solo_pc::Delogger::flush();
+
+    match reboot {
+        Some(Reboot::Application) => std::process::exit(solo_pc::EXIT_REBOOT),
+        Some(Reboot::Bootloader) => std::process::exit(solo_pc::EXIT_REBOOT_TO_UPDATE),
+        None => {}
+    }
 }
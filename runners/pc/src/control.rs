@@ -0,0 +1,200 @@
+//! A local control channel, to drive the runner from test scripts.
+//!
+//! If `SOLO2_CONTROL_SOCKET` names a path, the runner listens there on a Unix socket.
+//! Each line sent is one command, and gets one line back: `ok`, `ok <details>`
+//! or `error <reason>`.
+//!
+//! ```text
+//! press                         confirm the next user presence check
+//! deny                          refuse the next user presence check
+//! reboot [application|bootloader]
+//!                               reboot at the next chance (default: application)
+//! dump                          report the runner's state
+//! loglevel off|error|warn|info|debug|trace
+//!                               change which logs are recorded from now on
+//! ```
+//!
+//! `press` and `deny` queue up, each answers one check in order. Checks with nothing
+//! queued are confirmed, as without the control channel.
+//!
+//! `dump` answers with space separated `key=value` pairs, e.g.
+//! `ok presence=press,deny reboot=none loglevel=debug erases=0:12,1:3`, where
+//! `erases` lists the total erase count of each authenticator's internal flash.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::Wear;
+
+/// Environment variable naming the Unix socket to accept control commands on;
+/// there is no control channel if unset.
+pub const CONTROL_SOCKET_ENV: &str = "SOLO2_CONTROL_SOCKET";
+
+/// Where `reboot` sends the runner, see `EXIT_REBOOT` and `EXIT_REBOOT_TO_UPDATE`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Reboot {
+    Application,
+    Bootloader,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Request {
+    Press,
+    Deny,
+    Reboot(Reboot),
+    Dump,
+    LogLevel(log::LevelFilter),
+}
+
+impl Request {
+    /// Parses a line of the grammar above, surrounding whitespace is ignored.
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().ok_or_else(|| "empty command".to_string())?;
+        let argument = words.next();
+        if words.next().is_some() {
+            return Err(format!("too many arguments to {}", command));
+        }
+
+        match (command, argument) {
+            ("press", None) => Ok(Request::Press),
+            ("deny", None) => Ok(Request::Deny),
+            ("reboot", None) | ("reboot", Some("application")) => Ok(Request::Reboot(Reboot::Application)),
+            ("reboot", Some("bootloader")) => Ok(Request::Reboot(Reboot::Bootloader)),
+            ("dump", None) => Ok(Request::Dump),
+            ("loglevel", Some(level)) => level.parse()
+                .map(Request::LogLevel)
+                .map_err(|_| format!("unknown log level {}", level)),
+            ("press", Some(_)) | ("deny", Some(_)) | ("reboot", Some(_)) | ("dump", Some(_)) | ("loglevel", None) =>
+                Err(format!("wrong arguments to {}", command)),
+            _ => Err(format!("unknown command {}", command)),
+        }
+    }
+}
+
+/// The runner state the control channel acts on, shared with the `UserInterface`.
+#[derive(Debug, Default)]
+pub struct Control {
+    presence: Mutex<VecDeque<bool>>,
+    reboot: Mutex<Option<Reboot>>,
+    wear: Mutex<Vec<Arc<Wear>>>,
+}
+
+impl Control {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Listens on the socket named by `SOLO2_CONTROL_SOCKET`, if set.
+    pub fn from_env() -> io::Result<Option<Arc<Self>>> {
+        let path = match std::env::var_os(CONTROL_SOCKET_ENV) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let control = Arc::new(Self::new());
+        control.serve(path)?;
+        Ok(Some(control))
+    }
+
+    /// Includes `wear` in `dump`, in the order added.
+    pub fn watch_wear(&self, wear: Arc<Wear>) {
+        lock(&self.wear).push(wear);
+    }
+
+    /// The answer to the next user presence check, if one was queued with `press` or `deny`.
+    pub fn user_presence(&self) -> Option<bool> {
+        lock(&self.presence).pop_front()
+    }
+
+    /// A requested reboot, which is then no longer pending.
+    pub fn take_reboot(&self) -> Option<Reboot> {
+        lock(&self.reboot).take()
+    }
+
+    /// Runs one command, and returns the line to answer with (without newline).
+    pub fn execute(&self, line: &str) -> String {
+        let request = match Request::parse(line) {
+            Ok(request) => request,
+            Err(reason) => return format!("error {}", reason),
+        };
+
+        match request {
+            Request::Press => lock(&self.presence).push_back(true),
+            Request::Deny => lock(&self.presence).push_back(false),
+            Request::Reboot(to) => *lock(&self.reboot) = Some(to),
+            Request::LogLevel(level) => log::set_max_level(level),
+            Request::Dump => return format!("ok {}", self.dump()),
+        }
+        "ok".to_string()
+    }
+
+    fn dump(&self) -> String {
+        let presence: Vec<&str> = lock(&self.presence).iter()
+            .map(|&present| if present { "press" } else { "deny" })
+            .collect();
+        let reboot = match *lock(&self.reboot) {
+            None => "none",
+            Some(Reboot::Application) => "application",
+            Some(Reboot::Bootloader) => "bootloader",
+        };
+        let erases: Vec<String> = lock(&self.wear).iter()
+            .enumerate()
+            .map(|(index, wear)| {
+                let total: u32 = wear.report().iter().map(|&(_, erases)| erases).sum();
+                format!("{}:{}", index, total)
+            })
+            .collect();
+        format!(
+            "presence={} reboot={} loglevel={} erases={}",
+            if presence.is_empty() { "none".to_string() } else { presence.join(",") },
+            reboot,
+            log::max_level().to_string().to_lowercase(),
+            if erases.is_empty() { "none".to_string() } else { erases.join(",") },
+        )
+    }
+
+    /// Accepts connections on a Unix socket at `path`, replacing a stale one, and
+    /// answers their commands on a background thread, one connection at a time.
+    #[cfg(unix)]
+    pub fn serve(self: &Arc<Self>, path: impl AsRef<Path>) -> io::Result<std::thread::JoinHandle<()>> {
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::net::UnixListener;
+
+        let path = path.as_ref();
+        // a runner that did not exit cleanly leaves its socket behind, anything else stays
+        if std::fs::symlink_metadata(path).map(|meta| meta.file_type().is_socket()).unwrap_or(false) {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        println!("control socket at {}", path.display());
+
+        let control = self.clone();
+        Ok(std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let handled = stream.and_then(|stream| {
+                    let mut writer = stream.try_clone()?;
+                    for line in BufReader::new(stream).lines() {
+                        let answer = control.execute(&line?);
+                        writeln!(writer, "{}", answer)?;
+                    }
+                    Ok(())
+                });
+                if let Err(error) = handled {
+                    eprintln!("control connection failed: {}", error);
+                }
+            }
+        }))
+    }
+
+    #[cfg(not(unix))]
+    pub fn serve(self: &Arc<Self>, _path: impl AsRef<Path>) -> io::Result<std::thread::JoinHandle<()>> {
+        Err(io::Error::new(io::ErrorKind::Other, "the control socket needs Unix sockets"))
+    }
+}
+
+// the queued decisions stay usable after a panic on another thread
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -1 +1,708 @@
+#[macro_use]
+extern crate delog;
 
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use generic_array::typenum::{U16, U256, U512, U1022};
+use rand_core::{CryptoRng, OsRng, RngCore, SeedableRng};
+use trussed::types::LfsResult;
+
+pub mod control;
+pub mod trace;
+
+// Logging
+
+/// Environment variable naming the log file; file logging is off if unset.
+pub const LOG_FILE_ENV: &str = "SOLO2_LOG_FILE";
+/// Environment variable for the size (in bytes) at which the log file is rotated.
+pub const LOG_FILE_SIZE_ENV: &str = "SOLO2_LOG_FILE_SIZE";
+/// Environment variable for the number of rotated log files to keep.
+pub const LOG_FILE_KEEP_ENV: &str = "SOLO2_LOG_FILE_KEEP";
+
+/// Logs still buffered at reboot are kept here, and flushed first on the next start.
+pub const PERSISTED_LOGS: &str = "solo-log.bin";
+
+pub const DEFAULT_LOG_FILE_SIZE: u64 = 1024 * 1024;
+pub const DEFAULT_LOG_FILE_KEEP: usize = 3;
+
+struct LogFile {
+    writer: LineWriter<File>,
+    size: u64,
+}
+
+/// Appends flushed logs to a file, rotating it to `<path>.1`, `<path>.2`, ...
+/// once it would grow beyond `max_size` bytes.
+///
+/// Writes are line-buffered, so a crash leaves at most a partial last line behind.
+#[derive(Debug)]
+pub struct FileFlusher {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: Mutex<Option<LogFile>>,
+}
+
+impl core::fmt::Debug for LogFile {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("LogFile").field("size", &self.size).finish()
+    }
+}
+
+impl FileFlusher {
+    pub fn new(path: impl Into<PathBuf>, max_size: u64, keep: usize) -> Self {
+        Self {
+            path: path.into(),
+            max_size,
+            keep,
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Configure from `SOLO2_LOG_FILE` (and optionally `SOLO2_LOG_FILE_SIZE`,
+    /// `SOLO2_LOG_FILE_KEEP`). Returns `None` if no log file is requested.
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var_os(LOG_FILE_ENV)?;
+        let max_size = std::env::var(LOG_FILE_SIZE_ENV).ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(DEFAULT_LOG_FILE_SIZE);
+        let keep = std::env::var(LOG_FILE_KEEP_ENV).ok()
+            .and_then(|keep| keep.parse().ok())
+            .unwrap_or(DEFAULT_LOG_FILE_KEEP);
+        Some(Self::new(path, max_size, keep))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn open(&self) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile { writer: LineWriter::new(file), size })
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        // the oldest file (if any) is overwritten by the rename
+        for index in (1..self.keep).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(from, self.rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))
+    }
+
+    fn write(&self, logs: &str) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if file.is_none() {
+            *file = Some(self.open()?);
+        }
+
+        let len = logs.len() as u64;
+        let current = file.as_ref().unwrap().size;
+        // never rotate an empty file, even if a single chunk exceeds the cap
+        if current > 0 && current + len > self.max_size {
+            if let Some(mut old) = file.take() {
+                old.writer.flush()?;
+            }
+            self.rotate()?;
+            *file = Some(self.open()?);
+        }
+
+        let file = file.as_mut().unwrap();
+        file.writer.write_all(logs.as_bytes())?;
+        file.size += len;
+        Ok(())
+    }
+}
+
+impl delog::Flusher for FileFlusher {
+    fn flush(&self, logs: &str) {
+        if let Err(error) = self.write(logs) {
+            eprintln!("could not write logs to {}: {}", self.path.display(), error);
+        }
+    }
+}
+
+/// Prints logs to stdout, and additionally to the file configured via `SOLO2_LOG_FILE`.
+#[derive(Debug, Default)]
+pub struct Flusher {
+    file: Option<FileFlusher>,
+}
+
+impl Flusher {
+    pub fn from_env() -> Self {
+        Self { file: FileFlusher::from_env() }
+    }
+}
+
+// set while `persist_logs` drains the logger
+static PERSISTING: AtomicBool = AtomicBool::new(false);
+
+impl delog::Flusher for Flusher {
+    fn flush(&self, logs: &str) {
+        if PERSISTING.load(Ordering::SeqCst) {
+            let persisted = OpenOptions::new().create(true).append(true).open(PERSISTED_LOGS)
+                .and_then(|mut file| file.write_all(logs.as_bytes()));
+            if let Err(error) = persisted {
+                eprintln!("could not persist logs to {}: {}", PERSISTED_LOGS, error);
+            }
+            return;
+        }
+
+        print!("{}", logs);
+        if let Some(file) = self.file.as_ref() {
+            delog::Flusher::flush(file, logs);
+        }
+    }
+}
+
+delog!(Delogger, 16*1024, 3*1024, Flusher);
+
+/// Installs the logger; the flusher lives for the remainder of the program.
+pub fn init_logging() {
+    let flusher: &'static Flusher = Box::leak(Box::new(Flusher::from_env()));
+    if let Some(file) = flusher.file.as_ref() {
+        println!("logging to {}", file.path().display());
+    }
+    Delogger::init_default(delog::LevelFilter::Debug, flusher).ok();
+
+    // logs from before the last reboot come first
+    if let Ok(logs) = fs::read_to_string(PERSISTED_LOGS) {
+        delog::Flusher::flush(flusher, &logs);
+        fs::remove_file(PERSISTED_LOGS).ok();
+    }
+}
+
+/// Writes the logs that have not been flushed yet to `solo-log.bin`, to be restored
+/// by `init_logging` after a reboot.
+pub fn persist_logs() {
+    PERSISTING.store(true, Ordering::SeqCst);
+    Delogger::flush();
+    PERSISTING.store(false, Ordering::SeqCst);
+}
+
+/// Flushes buffered logs when panicking, so the records leading up to a crash are not lost.
+///
+/// Opt-in; any previously installed hook still runs afterwards.
+pub fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        Delogger::flush();
+        previous(info);
+    }));
+}
+
+/// What the runner does when an app panics while handling a request.
+///
+/// Panics are logged either way, see `install_panic_hook`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PanicPolicy {
+    /// Let the panic end the process (the default).
+    Abort,
+    /// Catch the panic, answer the request with an error, and carry on (`--continue-on-panic`).
+    ///
+    /// For fuzzing and soak tests only: the panic is caught with `catch_unwind`, which
+    /// gives no guarantee that anything the app touched is still consistent. The app
+    /// is forgotten without being deselected, keeping whatever state it had half
+    /// updated, and trussed may still hold a half-finished request of its client.
+    Continue,
+}
+
+impl Default for PanicPolicy {
+    fn default() -> Self {
+        PanicPolicy::Abort
+    }
+}
+
+// Reboots
+
+/// Exit code when the firmware reboots into itself (`reboot::To::Application`).
+/// A wrapper script relaunching the runner on this code emulates the reboot.
+pub const EXIT_REBOOT: i32 = 25;
+/// Exit code when the firmware reboots into the bootloader to be updated
+/// (`reboot::To::ApplicationUpdate`). There is no bootloader to run on PC: a wrapper
+/// script can install the new firmware here, then relaunch the runner.
+pub const EXIT_REBOOT_TO_UPDATE: i32 = 26;
+
+// Storage
+
+#[allow(non_camel_case_types)]
+pub mod littlefs_params {
+    use super::*;
+    pub const READ_SIZE: usize = 16;
+    pub const WRITE_SIZE: usize = 512;
+    pub const BLOCK_SIZE: usize = 512;
+
+    pub const BLOCK_COUNT: usize = 256;
+    // no wear-leveling for now
+    pub const BLOCK_CYCLES: isize = -1;
+
+    pub type CACHE_SIZE = U512;
+    pub type LOOKAHEADWORDS_SIZE = U16;
+    /// TODO: We can't actually be changed currently
+    pub type FILENAME_MAX_PLUS_ONE = U256;
+    pub type PATH_MAX_PLUS_ONE = U256;
+    pub const FILEBYTES_MAX: usize = littlefs2::ll::LFS_FILE_MAX as _;
+    /// TODO: We can't actually be changed currently
+    pub type ATTRBYTES_MAX = U1022;
+}
+
+/// Operations on one block of flash.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BlockWear {
+    pub reads: u32,
+    pub writes: u32,
+    pub erases: u32,
+}
+
+#[derive(Debug, Default)]
+struct BlockCounters {
+    reads: AtomicU32,
+    writes: AtomicU32,
+    erases: AtomicU32,
+}
+
+/// Per-block operation counts of a `FileFlash`, to find wear hotspots.
+///
+/// Shared, so the counts can be read while a filesystem owns the flash.
+#[derive(Debug)]
+pub struct Wear {
+    blocks: Vec<BlockCounters>,
+}
+
+impl Wear {
+    fn new() -> Self {
+        Self { blocks: (0..littlefs_params::BLOCK_COUNT).map(|_| Default::default()).collect() }
+    }
+
+    // operations may straddle blocks, each touched block is counted
+    fn count(&self, off: usize, len: usize, counter: impl Fn(&BlockCounters) -> &AtomicU32) {
+        let first = off / littlefs_params::BLOCK_SIZE;
+        let last = (off + len.max(1) - 1) / littlefs_params::BLOCK_SIZE;
+        for block in &self.blocks[first..=last] {
+            counter(block).fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn block(&self, block: usize) -> BlockWear {
+        let counters = &self.blocks[block];
+        BlockWear {
+            reads: counters.reads.load(Ordering::Relaxed),
+            writes: counters.writes.load(Ordering::Relaxed),
+            erases: counters.erases.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The erase count of every block that was erased at all, in block order.
+    pub fn report(&self) -> Vec<(usize, u32)> {
+        self.blocks.iter()
+            .map(|counters| counters.erases.load(Ordering::Relaxed))
+            .enumerate()
+            .filter(|&(_, erases)| erases > 0)
+            .collect()
+    }
+
+    fn reset(&self) {
+        for counters in self.blocks.iter() {
+            counters.reads.store(0, Ordering::Relaxed);
+            counters.writes.store(0, Ordering::Relaxed);
+            counters.erases.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Size of the internal flash, and of its state file.
+pub const STATE_SIZE: usize = littlefs_params::BLOCK_SIZE * littlefs_params::BLOCK_COUNT;
+
+/// Environment variable naming a state file to start from, see `seed_state`.
+pub const STATE_SEED_ENV: &str = "SOLO2_STATE_SEED";
+
+/// Copies `seed` over `state_file` if `reset` is set or there is no state file yet,
+/// e.g. to start integration tests from a provisioned authenticator.
+/// Without a seed, `reset` just removes the state file, so the store gets formatted.
+///
+/// Returns whether the state file was replaced. A seed of the wrong size is rejected,
+/// leaving the state file untouched.
+pub fn seed_state(state_file: impl AsRef<Path>, seed: Option<&Path>, reset: bool) -> io::Result<bool> {
+    let state_file = state_file.as_ref();
+    if !reset && state_file.exists() {
+        return Ok(false);
+    }
+    match seed {
+        Some(seed) => {
+            let contents = fs::read(seed)?;
+            if contents.len() != STATE_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} has {} bytes, expected {}", seed.display(), contents.len(), STATE_SIZE),
+                ));
+            }
+            fs::write(state_file, contents)?;
+            Ok(true)
+        }
+        None if state_file.exists() => {
+            fs::remove_file(state_file)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Copies the state file to `out`, to be used as a seed later.
+pub fn snapshot_state(state_file: impl AsRef<Path>, out: impl AsRef<Path>) -> io::Result<()> {
+    fs::copy(state_file, out).map(drop)
+}
+
+/// Internal flash, mirrored to a file so the state survives restarts.
+///
+/// Counts the operations on each block since it was loaded or reformatted, see `wear`.
+pub struct FileFlash {
+    path: String,
+    state: [u8; STATE_SIZE],
+    wear: Arc<Wear>,
+}
+impl FileFlash {
+    pub fn new(path: &str) -> Self {
+        let mut state = [0u8; STATE_SIZE];
+        let path = path.to_string();
+        let wear = Arc::new(Wear::new());
+
+        if let Ok(contents) = std::fs::read(&path) {
+            println!("loaded {}", path);
+            state.copy_from_slice( contents.as_slice() );
+            Self {path, state, wear}
+        } else {
+            println!("No state yet, creating {}", path);
+            Self {path, state, wear}
+        }
+    }
+
+    /// Handle on the operation counts, which stays valid after the flash is mounted.
+    pub fn wear(&self) -> Arc<Wear> {
+        self.wear.clone()
+    }
+
+    pub fn wear_report(&self) -> Vec<(usize, u32)> {
+        self.wear.report()
+    }
+
+    /// Start counting from zero, e.g. when formatting.
+    pub fn reset_wear(&mut self) {
+        self.wear.reset();
+    }
+}
+
+impl littlefs2::driver::Storage for FileFlash {
+    const READ_SIZE: usize = littlefs_params::READ_SIZE;
+    const WRITE_SIZE: usize = littlefs_params::WRITE_SIZE;
+    const BLOCK_SIZE: usize = littlefs_params::BLOCK_SIZE;
+
+    const BLOCK_COUNT: usize = littlefs_params::BLOCK_COUNT;
+    const BLOCK_CYCLES: isize = littlefs_params::BLOCK_CYCLES;
+
+    type CACHE_SIZE = littlefs_params::CACHE_SIZE;
+    type LOOKAHEADWORDS_SIZE = littlefs_params::LOOKAHEADWORDS_SIZE;
+    type FILENAME_MAX_PLUS_ONE = littlefs_params::FILENAME_MAX_PLUS_ONE;
+    type PATH_MAX_PLUS_ONE = littlefs_params::PATH_MAX_PLUS_ONE;
+    const FILEBYTES_MAX: usize = littlefs_params::FILEBYTES_MAX;
+    type ATTRBYTES_MAX = littlefs_params::ATTRBYTES_MAX;
+
+
+    fn read(&self, off: usize, buf: &mut [u8]) -> LfsResult<usize> {
+        self.wear.count(off, buf.len(), |block| &block.reads);
+        buf.copy_from_slice(&self.state[off..][..buf.len()]);
+        Ok(buf.len())
+    }
+
+    fn write(&mut self, off: usize, data: &[u8]) -> LfsResult<usize> {
+        self.wear.count(off, data.len(), |block| &block.writes);
+        self.state[off..][..data.len()].copy_from_slice(data);
+        let mut buffer = File::create(&self.path).unwrap();
+        buffer.write_all(&self.state).unwrap();
+
+        Ok(data.len())
+    }
+
+    fn erase(&mut self, off: usize, len: usize) -> LfsResult<usize> {
+        self.wear.count(off, len, |block| &block.erases);
+        self.state[off..][..len].iter_mut().for_each(|byte| *byte = 0);
+        let mut buffer = File::create(&self.path).unwrap();
+        buffer.write_all(&self.state).unwrap();
+        Ok(len)
+    }
+
+}
+
+/// The filesystems `format_unmountable` had to format.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Formatted {
+    pub internal: bool,
+    pub external: bool,
+    pub volatile: bool,
+}
+
+/// Formats each filesystem that cannot be mounted, and only those, so that the store
+/// can then be mounted without formatting. Unlike formatting in `store.mount`, a blank
+/// or broken external or volatile filesystem never wipes internal flash.
+///
+/// Internal flash that cannot be mounted but is not blank is copied to
+/// `<state file>.unmountable` before it is formatted.
+pub fn format_unmountable<E, V>(internal: &mut FileFlash, external: &mut E, volatile: &mut V) -> io::Result<Formatted>
+where
+    E: littlefs2::driver::Storage,
+    V: littlefs2::driver::Storage,
+{
+    use littlefs2::fs::Filesystem;
+    let format_error = |_| io::Error::new(io::ErrorKind::Other, "cannot format");
+
+    let mut formatted = Formatted::default();
+    if !Filesystem::is_mountable(internal) {
+        // the erase value is 0
+        if internal.state.iter().any(|&byte| byte != 0) {
+            let backup = format!("{}.unmountable", internal.path);
+            fs::write(&backup, &internal.state[..])?;
+            println!("Internal flash cannot be mounted, copied it to {}", backup);
+        }
+        Filesystem::format(internal).map_err(format_error)?;
+        // the erases of formatting are not wear from using the device
+        internal.reset_wear();
+        formatted.internal = true;
+    }
+    if !Filesystem::is_mountable(external) {
+        Filesystem::format(external).map_err(format_error)?;
+        formatted.external = true;
+    }
+    if !Filesystem::is_mountable(volatile) {
+        Filesystem::format(volatile).map_err(format_error)?;
+        formatted.volatile = true;
+    }
+    Ok(formatted)
+}
+
+/// Wipes internal flash, so all credentials and app state are gone, then formats it.
+/// Like `format_unmountable`, internal flash that is not blank is first copied, here to
+/// `<state file>.factory-reset`, so a reset by mistake can be undone by hand.
+pub fn factory_reset(internal: &mut FileFlash) -> io::Result<()> {
+    use littlefs2::driver::Storage;
+    use littlefs2::fs::Filesystem;
+    let format_error = |_| io::Error::new(io::ErrorKind::Other, "cannot format");
+
+    // the erase value is 0
+    if internal.state.iter().any(|&byte| byte != 0) {
+        let backup = format!("{}.factory-reset", internal.path);
+        fs::write(&backup, &internal.state[..])?;
+        println!("Factory reset, copied internal flash to {}", backup);
+    }
+    // formatting alone leaves the contents of unused blocks behind
+    internal.erase(0, STATE_SIZE).map_err(format_error)?;
+    Filesystem::format(internal).map_err(format_error)?;
+    // the wipe and format are not wear from using the device
+    internal.reset_wear();
+    Ok(())
+}
+
+/// Records the version of the data in internal flash, in decimal.
+/// Data without it is at version 1.
+pub const STORE_VERSION_FILE: &[u8] = b"store-version";
+
+/// Upgrades the data in a mounted store by one version.
+pub type Migration<S> = fn(S) -> io::Result<()>;
+
+/// Brings the data in a mounted store to version `migrations.len() + 1`, running
+/// `migrations[n - 1]` to go from version `n` to `n + 1`. Call after mounting and
+/// recovering the journal, before the apps start.
+///
+/// The version is recorded after each migration, so an interrupted upgrade resumes
+/// with the migration that was cut short. Returns the version the data was at.
+/// Data written by a newer firmware is left alone, and reported as an error.
+pub fn migrate<S: trussed::store::Store>(store: S, migrations: &[Migration<S>]) -> io::Result<u32> {
+    use trussed::store;
+    use trussed::types::{Location, PathBuf};
+    let path = PathBuf::from(STORE_VERSION_FILE);
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let found = if store::exists(store, Location::Internal, &path) {
+        let version = store::read::<U16>(store, Location::Internal, &path)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "cannot read store version"))?;
+        core::str::from_utf8(&version).ok()
+            .and_then(|version| version.parse::<u32>().ok())
+            .filter(|&version| version >= 1)
+            .ok_or_else(|| invalid(format!("store version {:?} is not a number", &version[..])))?
+    } else {
+        1
+    };
+    let latest = migrations.len() as u32 + 1;
+    if found > latest {
+        return Err(invalid(format!("store version {} is newer than {}", found, latest)));
+    }
+
+    for (version, migration) in (found..latest).zip(&migrations[found as usize - 1..]) {
+        println!("Migrating stored data from version {} to {}", version, version + 1);
+        migration(store)?;
+        store::store(store, Location::Internal, &path, (version + 1).to_string().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "cannot write store version"))?;
+    }
+    Ok(found)
+}
+
+// Randomness
+
+/// Environment variable selecting the RNG at boot: `os` for the operating system's
+/// source, anything else (or unset) for ChaCha8 seeded from it.
+pub const RNG_ENV: &str = "SOLO2_RNG";
+
+/// Environment variable holding a fixed ChaCha8 seed, as 64 hex digits.
+///
+/// When set, it takes precedence over `SOLO2_RNG`: key generation, nonces and key handles
+/// repeat from run to run, so responses can be compared against recorded vectors.
+/// Never use this outside of tests.
+pub const RNG_SEED_ENV: &str = "SOLO2_RNG_SEED";
+
+/// Environment variable naming a file with a fixed ChaCha8 seed, as exactly 32 raw bytes.
+///
+/// Like `SOLO2_RNG_SEED`, for a fuzzer to store the seed of each iteration and replay
+/// the one that failed; with `SOLO2_STATE_SEED` as well, a run is fully reproducible.
+/// Only one of the two may be set.
+pub const SEED_FILE_ENV: &str = "SOLO2_SEED_FILE";
+
+/// The RNG handed to the trussed platform, chosen at boot.
+///
+/// Apps only see `RngCore + CryptoRng`, so the source can change without touching them.
+pub enum PlatformRng {
+    ChaCha(chacha20::ChaCha8Rng),
+    Os(OsRng),
+}
+
+impl PlatformRng {
+    /// ChaCha8, seeded from the operating system.
+    pub fn chacha() -> Self {
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+        PlatformRng::ChaCha(chacha20::ChaCha8Rng::from_seed(seed))
+    }
+
+    /// ChaCha8 with a fixed seed, for deterministic test runs.
+    pub fn seeded(seed: [u8; 32]) -> Self {
+        PlatformRng::ChaCha(chacha20::ChaCha8Rng::from_seed(seed))
+    }
+
+    /// ChaCha8 seeded from `path`, which must hold exactly 32 bytes.
+    pub fn from_seed_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        read_seed_file(path).map(Self::seeded)
+    }
+
+    /// Every request goes straight to the operating system.
+    pub fn os() -> Self {
+        PlatformRng::Os(OsRng)
+    }
+
+    /// Panics if `SOLO2_RNG_SEED` is set but not 64 hex digits, if `SOLO2_SEED_FILE`
+    /// cannot be used, or if both are set, rather than silently running with a random seed.
+    pub fn from_env() -> Self {
+        Self::from_env_for_instance(0)
+    }
+
+    /// Like `from_env`, for the authenticator `instance` of several run side by side.
+    /// A seed from the environment is made distinct per instance, see `instance_seed`.
+    pub fn from_env_for_instance(instance: usize) -> Self {
+        if let Some(path) = std::env::var_os(SEED_FILE_ENV) {
+            if std::env::var_os(RNG_SEED_ENV).is_some() {
+                panic!("set only one of {} and {}", SEED_FILE_ENV, RNG_SEED_ENV);
+            }
+            let seed = read_seed_file(&path)
+                .unwrap_or_else(|err| panic!("cannot seed from {}: {}", SEED_FILE_ENV, err));
+            return Self::seeded(instance_seed(seed, instance));
+        }
+        if let Ok(seed) = std::env::var(RNG_SEED_ENV) {
+            let seed = parse_rng_seed(&seed)
+                .unwrap_or_else(|| panic!("{} must be 64 hex digits", RNG_SEED_ENV));
+            return Self::seeded(instance_seed(seed, instance));
+        }
+        match std::env::var(RNG_ENV).as_deref() {
+            Ok("os") => Self::os(),
+            _ => Self::chacha(),
+        }
+    }
+}
+
+fn read_seed_file(path: impl AsRef<Path>) -> io::Result<[u8; 32]> {
+    let path = path.as_ref();
+    let contents = fs::read(path)?;
+    let mut seed = [0u8; 32];
+    if contents.len() != seed.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "seed file {} has {} bytes, expected {}", path.display(), contents.len(), seed.len())));
+    }
+    seed.copy_from_slice(&contents);
+    Ok(seed)
+}
+
+/// The seed of authenticator `instance`, when several share one seed from the environment:
+/// otherwise they would generate the same keys. The first instance keeps `seed` as is,
+/// the others have their index mixed into its last bytes.
+pub fn instance_seed(mut seed: [u8; 32], instance: usize) -> [u8; 32] {
+    for (byte, index) in seed[24..].iter_mut().zip((instance as u64).to_le_bytes().iter()) {
+        *byte ^= index;
+    }
+    seed
+}
+
+/// Parses the 32 byte seed of `SOLO2_RNG_SEED` from 64 hex digits.
+pub fn parse_rng_seed(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut seed = [0u8; 32];
+    for (byte, digits) in seed.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(core::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(seed)
+}
+
+impl RngCore for PlatformRng {
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            PlatformRng::ChaCha(rng) => rng.next_u32(),
+            PlatformRng::Os(rng) => rng.next_u32(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self {
+            PlatformRng::ChaCha(rng) => rng.next_u64(),
+            PlatformRng::Os(rng) => rng.next_u64(),
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self {
+            PlatformRng::ChaCha(rng) => rng.fill_bytes(dest),
+            PlatformRng::Os(rng) => rng.fill_bytes(dest),
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        match self {
+            PlatformRng::ChaCha(rng) => rng.try_fill_bytes(dest),
+            PlatformRng::Os(rng) => rng.try_fill_bytes(dest),
+        }
+    }
+}
+
+impl CryptoRng for PlatformRng {}
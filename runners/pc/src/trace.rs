@@ -0,0 +1,260 @@
+//! Recording APDU exchanges, and replaying them against a fresh authenticator.
+//!
+//! A trace is a text file with one APDU per line:
+//!
+//! ```text
+//! # milliseconds interface direction apdu
+//! 0 contact command 00A4040008A0000006472F0001
+//! 2 contact response 5532465F56329000
+//! ```
+//!
+//! Responses include the status word. Empty lines and lines starting with `#` are ignored.
+
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use apdu_dispatch::app::Status;
+use apdu_dispatch::dispatch::{ApduDispatch, ApduTap, Interface};
+use apdu_dispatch::{App, command, interchanges, response};
+use interchange::{Interchange, Requester};
+
+use crate::PanicPolicy;
+
+/// Environment variable naming the file to record APDU exchanges to.
+pub const RECORD_ENV: &str = "SOLO2_APDU_RECORD";
+/// Environment variable naming a trace to replay instead of waiting for a host.
+pub const REPLAY_ENV: &str = "SOLO2_APDU_REPLAY";
+
+const HEADER: &str = "# milliseconds interface direction apdu";
+
+// a deferred response must be available after this many polls
+const MAX_POLLS: usize = 100;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    Command,
+    Response,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    /// Time since the recording started.
+    pub timestamp_ms: u64,
+    pub interface: Interface,
+    pub direction: Direction,
+    pub apdu: Vec<u8>,
+}
+
+impl core::fmt::Display for Record {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let interface = match self.interface {
+            Interface::Contact => "contact",
+            Interface::Contactless => "contactless",
+        };
+        let direction = match self.direction {
+            Direction::Command => "command",
+            Direction::Response => "response",
+        };
+        write!(f, "{} {} {} ", self.timestamp_ms, interface, direction)?;
+        for byte in self.apdu.iter() {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl Record {
+    /// Parses a line of a trace, as written by `Display`.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+        let timestamp_ms = fields.next()?.parse().ok()?;
+        let interface = match fields.next()? {
+            "contact" => Interface::Contact,
+            "contactless" => Interface::Contactless,
+            _ => return None,
+        };
+        let direction = match fields.next()? {
+            "command" => Direction::Command,
+            "response" => Direction::Response,
+            _ => return None,
+        };
+        let hex = fields.next()?;
+        if fields.next().is_some() || hex.len() % 2 != 0 {
+            return None;
+        }
+        let apdu = (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some(Self { timestamp_ms, interface, direction, apdu })
+    }
+}
+
+/// Reads a trace file.
+pub fn load(path: impl AsRef<Path>) -> io::Result<Vec<Record>> {
+    let contents = std::fs::read_to_string(path)?;
+    contents.lines().enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(index, line)| Record::parse(line).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line {}: not an APDU record: {}", index + 1, line),
+        )))
+        .collect()
+}
+
+/// Writes every APDU passing through the dispatcher to a trace file.
+pub struct Recorder {
+    writer: LineWriter<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut writer = LineWriter::new(File::create(path)?);
+        writeln!(writer, "{}", HEADER)?;
+        Ok(Self { writer, start: Instant::now() })
+    }
+
+    /// Configure from `SOLO2_APDU_RECORD`. Returns `None` if no recording is requested.
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var_os(RECORD_ENV)?;
+        match Self::create(&path) {
+            Ok(recorder) => Some(recorder),
+            Err(error) => {
+                eprintln!("could not record APDUs to {}: {}", Path::new(&path).display(), error);
+                None
+            }
+        }
+    }
+
+    fn record(&mut self, interface: Interface, direction: Direction, apdu: &[u8]) {
+        let record = Record {
+            timestamp_ms: self.start.elapsed().as_millis() as u64,
+            interface,
+            direction,
+            apdu: apdu.to_vec(),
+        };
+        if let Err(error) = writeln!(self.writer, "{}", record) {
+            eprintln!("could not record APDU: {}", error);
+        }
+    }
+}
+
+impl ApduTap for Recorder {
+    fn command(&mut self, interface: Interface, apdu: &[u8]) {
+        self.record(interface, Direction::Command, apdu);
+    }
+
+    fn response(&mut self, interface: Interface, apdu: &[u8]) {
+        self.record(interface, Direction::Response, apdu);
+    }
+}
+
+/// The first response of a replay that differs from the recording.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Mismatch {
+    /// Index of the command in the trace.
+    pub index: usize,
+    pub command: Vec<u8>,
+    /// `None` if the recording has no response to the command.
+    pub expected: Option<Vec<u8>>,
+    /// `None` if the dispatcher did not respond.
+    pub actual: Option<Vec<u8>>,
+}
+
+impl core::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        fn hex(f: &mut core::fmt::Formatter<'_>, apdu: Option<&[u8]>) -> core::fmt::Result {
+            match apdu {
+                Some(apdu) => apdu.iter().try_for_each(|byte| write!(f, "{:02X}", byte)),
+                None => write!(f, "(none)"),
+            }
+        }
+        write!(f, "record {}: response to ", self.index)?;
+        hex(f, Some(&self.command))?;
+        write!(f, " differs\n  expected ")?;
+        hex(f, self.expected.as_deref())?;
+        write!(f, "\n  actual   ")?;
+        hex(f, self.actual.as_deref())
+    }
+}
+
+// Poll once. With `PanicPolicy::Continue`, a panicking app fails the request instead.
+fn poll(
+    dispatch: &mut ApduDispatch,
+    apps: &mut [&mut dyn App<command::Size, response::Size>],
+    policy: PanicPolicy,
+) {
+    if policy == PanicPolicy::Abort {
+        dispatch.poll(apps);
+        return;
+    }
+    let polled = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        dispatch.poll(apps);
+    }));
+    if polled.is_err() {
+        eprintln!("continuing after panic, dispatcher was at {:?}", dispatch.debug_state());
+        dispatch.abort_request(Status::UnspecifiedNonpersistentExecutionError);
+    }
+}
+
+// Send one command, and poll until it is answered.
+fn exchange<I>(
+    requester: &mut Requester<I>,
+    dispatch: &mut ApduDispatch,
+    apps: &mut [&mut dyn App<command::Size, response::Size>],
+    apdu: &[u8],
+    policy: PanicPolicy,
+) -> Option<Vec<u8>>
+where
+    I: Interchange<REQUEST = interchanges::Data, RESPONSE = interchanges::Data>,
+{
+    let request = interchanges::Data::try_from_slice(apdu).ok()?;
+    requester.request(&request).ok()?;
+    for _ in 0..MAX_POLLS {
+        poll(dispatch, apps, policy);
+        if let Some(response) = requester.take_response() {
+            return Some(response.as_slice().to_vec());
+        }
+    }
+    // free the interchange for the next command
+    requester.cancel().ok();
+    None
+}
+
+/// Sends the recorded commands in order, each on its interface, and compares every
+/// response with the one recorded right after the command.
+///
+/// A command whose app panics is answered `6400` with `PanicPolicy::Continue`.
+///
+/// Returns the number of commands replayed.
+pub fn replay(
+    records: &[Record],
+    dispatch: &mut ApduDispatch,
+    apps: &mut [&mut dyn App<command::Size, response::Size>],
+    contact: &mut Requester<interchanges::Contact>,
+    contactless: &mut Requester<interchanges::Contactless>,
+    policy: PanicPolicy,
+) -> Result<usize, Mismatch> {
+    let mut commands = 0;
+    for (index, record) in records.iter().enumerate() {
+        if record.direction != Direction::Command {
+            continue;
+        }
+        commands += 1;
+
+        let expected = records.get(index + 1)
+            .filter(|next| next.direction == Direction::Response && next.interface == record.interface)
+            .map(|next| next.apdu.clone());
+        let actual = match record.interface {
+            Interface::Contact => exchange(contact, dispatch, apps, &record.apdu, policy),
+            Interface::Contactless => exchange(contactless, dispatch, apps, &record.apdu, policy),
+        };
+
+        if actual != expected {
+            return Err(Mismatch { index, command: record.apdu.clone(), expected, actual });
+        }
+    }
+    Ok(commands)
+}
@@ -0,0 +1,158 @@
+use apdu_dispatch::app::{self, Aid, App};
+use apdu_dispatch::dispatch::{ApduDispatch, Interface};
+use apdu_dispatch::{command, interchanges, response, Command};
+use interchange::{Interchange, Requester};
+use iso7816::Status;
+use serial_test::serial;
+
+use solo_pc::PanicPolicy;
+use solo_pc::trace::{self, Direction, Mismatch, Record, Recorder};
+
+// Echoes the data of instruction 0x10, prefixed with `prefix`. Panics on instruction 0x66.
+struct EchoApp {
+    prefix: u8,
+}
+
+impl Aid for EchoApp {
+    fn aid(&self) -> &'static [u8] {
+        &[0x0A, 1, 0, 0, 1]
+    }
+
+    fn right_truncated_length(&self) -> usize {
+        5
+    }
+}
+
+impl App<command::Size, response::Size> for EchoApp {
+    fn select(&mut self, _apdu: &Command, _reply: &mut response::Data) -> app::Result {
+        Ok(())
+    }
+
+    fn deselect(&mut self, _: Interface) {}
+
+    fn call(&mut self, _: Interface, _: bool, apdu: &Command, reply: &mut response::Data) -> iso7816::Result<Status> {
+        match apdu.instruction().into() {
+            0x10 => {
+                reply.push(self.prefix).unwrap();
+                reply.extend_from_slice(apdu.data()).unwrap();
+                Ok(Status::Success)
+            }
+            0x66 => panic!("instruction 0x66"),
+            _ => Err(Status::InstructionNotSupportedOrInvalid),
+        }
+    }
+}
+
+type Requesters = (Requester<interchanges::Contact>, Requester<interchanges::Contactless>);
+
+fn dispatch() -> (Requesters, ApduDispatch) {
+    unsafe { interchanges::Contact::reset_claims() };
+    unsafe { interchanges::Contactless::reset_claims() };
+    let (contact_requester, contact_responder) = interchanges::Contact::claim().unwrap();
+    let (contactless_requester, contactless_responder) = interchanges::Contactless::claim().unwrap();
+    ((contact_requester, contactless_requester), ApduDispatch::new(contact_responder, contactless_responder))
+}
+
+const COMMANDS: &[&[u8]] = &[
+    // Select
+    &[0x00, 0xA4, 0x04, 0x00, 0x05, 0x0A, 0x01, 0x00, 0x00, 0x01],
+    // Echo
+    &[0x00, 0x10, 0x00, 0x00, 0x03, 0x01, 0x02, 0x03],
+    // Unknown instruction
+    &[0x00, 0x20, 0x00, 0x00],
+];
+
+#[test]
+#[serial]
+fn record_and_replay() {
+    let path = std::env::temp_dir().join(format!("solo-apdu-{}.trace", std::process::id()));
+
+    // record
+    let ((mut contact, _), mut apdu_dispatch) = dispatch();
+    apdu_dispatch.set_tap(Box::leak(Box::new(Recorder::create(&path).unwrap())));
+    let mut app = EchoApp { prefix: 0 };
+    for apdu in COMMANDS {
+        contact.request(&interchanges::Data::try_from_slice(apdu).unwrap()).unwrap();
+        apdu_dispatch.poll(&mut [&mut app]);
+        contact.take_response().unwrap();
+    }
+
+    let records = trace::load(&path).unwrap();
+    std::fs::remove_file(&path).ok();
+    assert_eq!(records.len(), 6);
+    for (index, record) in records.iter().enumerate() {
+        assert_eq!(record.interface, Interface::Contact);
+        let direction = if index % 2 == 0 { Direction::Command } else { Direction::Response };
+        assert_eq!(record.direction, direction);
+    }
+    assert_eq!(records[2].apdu, COMMANDS[1]);
+    assert_eq!(records[3].apdu, [0x00, 0x01, 0x02, 0x03, 0x90, 0x00]);
+
+    // replay against a fresh app
+    let ((mut contact, mut contactless), mut apdu_dispatch) = dispatch();
+    let mut app = EchoApp { prefix: 0 };
+    assert_eq!(
+        trace::replay(&records, &mut apdu_dispatch, &mut [&mut app], &mut contact, &mut contactless, PanicPolicy::Abort),
+        Ok(3),
+    );
+
+    // replay against an app that has changed
+    let ((mut contact, mut contactless), mut apdu_dispatch) = dispatch();
+    let mut app = EchoApp { prefix: 1 };
+    assert_eq!(
+        trace::replay(&records, &mut apdu_dispatch, &mut [&mut app], &mut contact, &mut contactless, PanicPolicy::Abort),
+        Err(Mismatch {
+            index: 2,
+            command: COMMANDS[1].to_vec(),
+            expected: Some(vec![0x00, 0x01, 0x02, 0x03, 0x90, 0x00]),
+            actual: Some(vec![0x01, 0x01, 0x02, 0x03, 0x90, 0x00]),
+        }),
+    );
+}
+
+#[test]
+fn example_trace() {
+    let records = trace::load(concat!(env!("CARGO_MANIFEST_DIR"), "/traces/u2f-version.trace")).unwrap();
+    assert_eq!(records.len(), 4);
+    assert_eq!(records[0].interface, Interface::Contactless);
+    assert_eq!(records[1].apdu, b"U2F_V2\x90\x00");
+
+    // lines are written as they are parsed
+    for record in records.iter() {
+        assert_eq!(trace::Record::parse(&record.to_string()).as_ref(), Some(record));
+    }
+
+    assert_eq!(trace::Record::parse("0 contact command 00A"), None);
+    assert_eq!(trace::Record::parse("0 usb command 00A4"), None);
+}
+
+#[test]
+#[serial]
+fn continue_on_panic() {
+    let record = |direction, apdu: &[u8]| Record {
+        timestamp_ms: 0,
+        interface: Interface::Contact,
+        direction,
+        apdu: apdu.to_vec(),
+    };
+    let records = [
+        record(Direction::Command, COMMANDS[0]),
+        record(Direction::Response, &[0x90, 0x00]),
+        record(Direction::Command, &[0x00, 0x66, 0x00, 0x00]),
+        record(Direction::Response, &[0x64, 0x00]),
+        // the app is no longer selected
+        record(Direction::Command, COMMANDS[1]),
+        record(Direction::Response, &[0x6A, 0x82]),
+        record(Direction::Command, COMMANDS[0]),
+        record(Direction::Response, &[0x90, 0x00]),
+        record(Direction::Command, COMMANDS[1]),
+        record(Direction::Response, &[0x00, 0x01, 0x02, 0x03, 0x90, 0x00]),
+    ];
+
+    let ((mut contact, mut contactless), mut apdu_dispatch) = dispatch();
+    let mut app = EchoApp { prefix: 0 };
+    assert_eq!(
+        trace::replay(&records, &mut apdu_dispatch, &mut [&mut app], &mut contact, &mut contactless, PanicPolicy::Continue),
+        Ok(5),
+    );
+}
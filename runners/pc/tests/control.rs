@@ -0,0 +1,75 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+
+use solo_pc::control::{Control, Reboot, Request};
+
+#[test]
+fn grammar() {
+    assert_eq!(Request::parse("press"), Ok(Request::Press));
+    assert_eq!(Request::parse("  deny \r"), Ok(Request::Deny));
+    assert_eq!(Request::parse("reboot"), Ok(Request::Reboot(Reboot::Application)));
+    assert_eq!(Request::parse("reboot application"), Ok(Request::Reboot(Reboot::Application)));
+    assert_eq!(Request::parse("reboot bootloader"), Ok(Request::Reboot(Reboot::Bootloader)));
+    assert_eq!(Request::parse("dump"), Ok(Request::Dump));
+    assert_eq!(Request::parse("loglevel warn"), Ok(Request::LogLevel(log::LevelFilter::Warn)));
+
+    assert!(Request::parse("").is_err());
+    assert!(Request::parse("reboot elsewhere").is_err());
+    assert!(Request::parse("press twice").is_err());
+    assert!(Request::parse("loglevel").is_err());
+    assert!(Request::parse("loglevel loud").is_err());
+    assert!(Request::parse("loglevel info now").is_err());
+    assert!(Request::parse("format").is_err());
+}
+
+#[test]
+fn drive_over_socket() {
+    let path = std::env::temp_dir().join(format!("solo-control-{}.sock", std::process::id()));
+    // as left behind by a crashed runner
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+    let control = Arc::new(Control::new());
+    control.serve(&path).unwrap();
+
+    let stream = UnixStream::connect(&path).unwrap();
+    let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+    let mut writer = stream;
+    let mut send = |command: &str| -> String {
+        writeln!(writer, "{}", command).unwrap();
+        lines.next().unwrap().unwrap()
+    };
+
+    assert_eq!(send("dump"), format!("ok presence=none reboot=none loglevel={} erases=none",
+        log::max_level().to_string().to_lowercase()));
+
+    // decisions are used up in order, then checks are confirmed again
+    assert_eq!(send("press"), "ok");
+    assert_eq!(send("deny"), "ok");
+    assert!(send("dump").starts_with("ok presence=press,deny "));
+    assert_eq!(control.user_presence(), Some(true));
+    assert_eq!(control.user_presence(), Some(false));
+    assert_eq!(control.user_presence(), None);
+
+    assert_eq!(send("loglevel error"), "ok");
+    assert_eq!(log::max_level(), log::LevelFilter::Error);
+    assert!(send("dump").contains(" loglevel=error "));
+
+    // a bad command does not end the connection
+    assert_eq!(send("reboot sideways"), "error wrong arguments to reboot");
+    assert_eq!(send("explode"), "error unknown command explode");
+
+    assert_eq!(control.take_reboot(), None);
+    assert_eq!(send("reboot bootloader"), "ok");
+    assert!(send("dump").contains(" reboot=bootloader "));
+    assert_eq!(control.take_reboot(), Some(Reboot::Bootloader));
+    assert_eq!(control.take_reboot(), None);
+
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn no_socket_by_default() {
+    std::env::remove_var(solo_pc::control::CONTROL_SOCKET_ENV);
+    assert!(Control::from_env().unwrap().is_none());
+}
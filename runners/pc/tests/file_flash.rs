@@ -0,0 +1,41 @@
+use littlefs2::driver::Storage;
+use solo_pc::{BlockWear, FileFlash};
+
+const BLOCK_SIZE: usize = solo_pc::littlefs_params::BLOCK_SIZE;
+
+#[test]
+fn counts_wear_per_block() {
+    let state_file = std::env::temp_dir().join(format!("solo-wear-{}.bin", std::process::id()));
+    let state_file = state_file.to_str().unwrap();
+    std::fs::remove_file(state_file).ok();
+
+    let mut flash = FileFlash::new(state_file);
+    let wear = flash.wear();
+    assert!(flash.wear_report().is_empty());
+
+    // block 1 is rewritten three times, block 3 once
+    for _ in 0..3 {
+        flash.erase(BLOCK_SIZE, BLOCK_SIZE).unwrap();
+        flash.write(BLOCK_SIZE, &[1; 16]).unwrap();
+    }
+    flash.erase(3 * BLOCK_SIZE, BLOCK_SIZE).unwrap();
+    flash.write(3 * BLOCK_SIZE + 32, &[2; 16]).unwrap();
+
+    let mut buf = [0; 16];
+    flash.read(BLOCK_SIZE, &mut buf).unwrap();
+    assert_eq!(buf, [1; 16]);
+
+    assert_eq!(flash.wear_report(), vec![(1, 3), (3, 1)]);
+    assert_eq!(wear.block(1), BlockWear { reads: 1, writes: 3, erases: 3 });
+    assert_eq!(wear.block(3), BlockWear { reads: 0, writes: 1, erases: 1 });
+    assert_eq!(wear.block(2), BlockWear::default());
+
+    // an erase of several blocks wears each of them
+    flash.erase(0, 2 * BLOCK_SIZE).unwrap();
+    assert_eq!(flash.wear_report(), vec![(0, 1), (1, 4), (3, 1)]);
+
+    flash.reset_wear();
+    assert!(wear.report().is_empty());
+
+    std::fs::remove_file(state_file).ok();
+}
@@ -0,0 +1,81 @@
+use littlefs2::{const_ram_storage, fs::Filesystem};
+use trussed::types::{LfsResult, LfsStorage};
+use trussed::store;
+use trussed::types::{Location, PathBuf};
+
+use solo_pc::FileFlash;
+use store_journal::{Recovery, Transaction};
+
+const_ram_storage!(ExternalStorage, 4096);
+const_ram_storage!(VolatileStorage, 8192);
+
+trussed::store!(Store,
+    Internal: FileFlash,
+    External: ExternalStorage,
+    Volatile: VolatileStorage
+);
+
+// Mount the filesystems like the runner does, reading internal flash back from `state_file`.
+fn mount(store: Store, state_file: &str, format: bool) {
+    let external_storage = Box::leak(Box::new(ExternalStorage::new()));
+    let volatile_storage = Box::leak(Box::new(VolatileStorage::new()));
+    Filesystem::format(external_storage).unwrap();
+    Filesystem::format(volatile_storage).unwrap();
+    store.mount(
+        Box::leak(Box::new(Filesystem::allocate())),
+        Box::leak(Box::new(FileFlash::new(state_file))),
+        Box::leak(Box::new(Filesystem::allocate())),
+        external_storage,
+        Box::leak(Box::new(Filesystem::allocate())),
+        volatile_storage,
+        format,
+    ).unwrap();
+}
+
+fn read(store: Store, path: &[u8]) -> Vec<u8> {
+    let data = store::read::<heapless::consts::U64>(store, Location::Internal, &PathBuf::from(path)).unwrap();
+    data.to_vec()
+}
+
+fn update(store: Store, credential: &[u8], counter: &[u8]) -> Transaction<Store> {
+    let mut transaction = Transaction::begin(store, Location::Internal);
+    transaction.write(b"credential", credential).unwrap();
+    transaction.write(b"counter", counter).unwrap();
+    transaction
+}
+
+#[test]
+fn crash_rolls_back() {
+    let state_file = std::env::temp_dir().join(format!("solo-journal-{}.bin", std::process::id()));
+    let state_file = state_file.to_str().unwrap();
+    std::fs::remove_file(state_file).ok();
+
+    let store = Store::claim().unwrap();
+
+    mount(store, state_file, true);
+    assert_eq!(store_journal::recover(store, Location::Internal), Ok(Recovery::Clean));
+    update(store, b"old credential", b"1").commit().unwrap();
+    assert_eq!(read(store, b"credential"), b"old credential");
+
+    // staged writes leave the files alone, until the process dies before committing
+    {
+        let _uncommitted = update(store, b"new credential", b"2");
+        assert_eq!(read(store, b"credential"), b"old credential");
+    }
+
+    // as after a reboot: the staged files are discarded
+    mount(store, state_file, false);
+    assert_eq!(store_journal::recover(store, Location::Internal), Ok(Recovery::RolledBack));
+    assert_eq!(read(store, b"credential"), b"old credential");
+    assert_eq!(read(store, b"counter"), b"1");
+    assert_eq!(store_journal::recover(store, Location::Internal), Ok(Recovery::Clean));
+
+    // a completed commit survives the reboot
+    update(store, b"new credential", b"2").commit().unwrap();
+    mount(store, state_file, false);
+    assert_eq!(store_journal::recover(store, Location::Internal), Ok(Recovery::Clean));
+    assert_eq!(read(store, b"credential"), b"new credential");
+    assert_eq!(read(store, b"counter"), b"2");
+
+    std::fs::remove_file(state_file).ok();
+}
@@ -0,0 +1,84 @@
+use littlefs2::{const_ram_storage, fs::Filesystem};
+use trussed::types::{LfsResult, LfsStorage};
+use trussed::store;
+use trussed::types::{Location, PathBuf};
+
+use solo_pc::FileFlash;
+
+const_ram_storage!(ExternalStorage, 4096);
+const_ram_storage!(VolatileStorage, 8192);
+
+trussed::store!(Store,
+    Internal: FileFlash,
+    External: ExternalStorage,
+    Volatile: VolatileStorage
+);
+
+// Mount the filesystems like the runner does, reading internal flash back from `state_file`.
+fn mount(store: Store, state_file: &str, format: bool) {
+    let external_storage = Box::leak(Box::new(ExternalStorage::new()));
+    let volatile_storage = Box::leak(Box::new(VolatileStorage::new()));
+    Filesystem::format(external_storage).unwrap();
+    Filesystem::format(volatile_storage).unwrap();
+    store.mount(
+        Box::leak(Box::new(Filesystem::allocate())),
+        Box::leak(Box::new(FileFlash::new(state_file))),
+        Box::leak(Box::new(Filesystem::allocate())),
+        external_storage,
+        Box::leak(Box::new(Filesystem::allocate())),
+        volatile_storage,
+        format,
+    ).unwrap();
+}
+
+fn read(store: Store, path: &[u8]) -> Vec<u8> {
+    let data = store::read::<heapless::consts::U64>(store, Location::Internal, &PathBuf::from(path)).unwrap();
+    data.to_vec()
+}
+
+fn write(store: Store, path: &[u8], contents: &[u8]) {
+    store::store(store, Location::Internal, &PathBuf::from(path), contents).unwrap();
+}
+
+// version 2 adds a user verification flag to the credential
+fn add_uv(store: Store) -> std::io::Result<()> {
+    let mut credential = read(store, b"credential");
+    credential.extend_from_slice(b",uv=0");
+    write(store, b"credential", &credential);
+    Ok(())
+}
+
+#[test]
+fn v1_fixture_to_v2() {
+    let state_file = std::env::temp_dir().join(format!("solo-migrate-{}.bin", std::process::id()));
+    let state_file = state_file.to_str().unwrap();
+    let fixture = format!("{}.v1", state_file);
+    std::fs::remove_file(state_file).ok();
+
+    let store = Store::claim().unwrap();
+
+    // a version 1 store has no version file
+    mount(store, state_file, true);
+    write(store, b"credential", b"rp=example.com");
+    solo_pc::snapshot_state(state_file, &fixture).unwrap();
+
+    // start over from the fixture, as with `--reset` and `SOLO2_STATE_SEED`
+    assert!(solo_pc::seed_state(state_file, Some(fixture.as_ref()), true).unwrap());
+    mount(store, state_file, false);
+    assert_eq!(solo_pc::migrate(store, &[add_uv]).unwrap(), 1);
+    assert_eq!(read(store, b"credential"), b"rp=example.com,uv=0");
+    assert_eq!(read(store, solo_pc::STORE_VERSION_FILE), b"2");
+
+    // the version survives the reboot, so the migration is not repeated
+    mount(store, state_file, false);
+    assert_eq!(solo_pc::migrate(store, &[add_uv]).unwrap(), 2);
+    assert_eq!(read(store, b"credential"), b"rp=example.com,uv=0");
+
+    // data from a newer firmware is left alone
+    write(store, solo_pc::STORE_VERSION_FILE, b"3");
+    assert!(solo_pc::migrate(store, &[add_uv]).is_err());
+    assert_eq!(read(store, b"credential"), b"rp=example.com,uv=0");
+
+    std::fs::remove_file(state_file).ok();
+    std::fs::remove_file(&fixture).ok();
+}
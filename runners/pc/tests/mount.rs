@@ -0,0 +1,79 @@
+use littlefs2::{const_ram_storage, fs::Filesystem, path::PathBuf};
+use trussed::types::{LfsResult, LfsStorage};
+
+use solo_pc::{FileFlash, Formatted};
+
+const_ram_storage!(ExternalStorage, 4096);
+const_ram_storage!(VolatileStorage, 8192);
+
+#[test]
+fn blank_volatile_keeps_internal() {
+    let state_file = std::env::temp_dir().join(format!("solo-mount-{}.bin", std::process::id()));
+    let state_file = state_file.to_str().unwrap();
+    let backup = format!("{}.unmountable", state_file);
+    std::fs::remove_file(state_file).ok();
+    std::fs::remove_file(&backup).ok();
+    let path = PathBuf::from(b"kept");
+
+    // first run: everything is blank
+    let mut internal = FileFlash::new(state_file);
+    let formatted = solo_pc::format_unmountable(&mut internal, &mut ExternalStorage::new(), &mut VolatileStorage::new()).unwrap();
+    assert_eq!(formatted, Formatted { internal: true, external: true, volatile: true });
+    // formatting does not count as wear
+    assert!(internal.wear_report().is_empty());
+    Filesystem::mount_and_then(&mut internal, |fs| fs.write(&path, b"credential")).unwrap();
+
+    // as after a reboot: only the unmountable RAM is formatted
+    let mut internal = FileFlash::new(state_file);
+    let mut external = ExternalStorage::new();
+    Filesystem::format(&mut external).unwrap();
+    let formatted = solo_pc::format_unmountable(&mut internal, &mut external, &mut VolatileStorage::new()).unwrap();
+    assert_eq!(formatted, Formatted { internal: false, external: false, volatile: true });
+    let read = Filesystem::mount_and_then(&mut internal, |fs| fs.read::<heapless::consts::U64>(&path)).unwrap();
+    assert_eq!(&read[..], b"credential");
+    assert!(std::fs::metadata(&backup).is_err());
+
+    // broken internal flash is kept aside before it is formatted
+    let garbage = vec![0x5A; solo_pc::STATE_SIZE];
+    std::fs::write(state_file, &garbage).unwrap();
+    let mut internal = FileFlash::new(state_file);
+    let formatted = solo_pc::format_unmountable(&mut internal, &mut external, &mut VolatileStorage::new()).unwrap();
+    assert!(formatted.internal);
+    assert!(internal.wear_report().is_empty());
+    assert_eq!(std::fs::read(&backup).unwrap(), garbage);
+
+    std::fs::remove_file(state_file).ok();
+    std::fs::remove_file(&backup).ok();
+}
+
+#[test]
+fn factory_reset_wipes_internal() {
+    let state_file = std::env::temp_dir().join(format!("solo-factory-reset-{}.bin", std::process::id()));
+    let state_file = state_file.to_str().unwrap();
+    let backup = format!("{}.factory-reset", state_file);
+    std::fs::remove_file(state_file).ok();
+    std::fs::remove_file(&backup).ok();
+    let path = PathBuf::from(b"credential");
+
+    let mut internal = FileFlash::new(state_file);
+    solo_pc::format_unmountable(&mut internal, &mut ExternalStorage::new(), &mut VolatileStorage::new()).unwrap();
+    Filesystem::mount_and_then(&mut internal, |fs| fs.write(&path, b"resident key")).unwrap();
+    let registered = std::fs::read(state_file).unwrap();
+
+    // as on the next start with `--factory-reset`
+    let mut internal = FileFlash::new(state_file);
+    solo_pc::factory_reset(&mut internal).unwrap();
+    assert!(internal.wear_report().is_empty());
+    let formatted = solo_pc::format_unmountable(&mut internal, &mut ExternalStorage::new(), &mut VolatileStorage::new()).unwrap();
+    assert!(!formatted.internal);
+    let read = Filesystem::mount_and_then(&mut internal, |fs| Ok(fs.read::<heapless::consts::U64>(&path).is_err()));
+    assert!(read.unwrap(), "credential survived the factory reset");
+
+    // nothing of the credential is left in the state file, except in the copy
+    let wiped = std::fs::read(state_file).unwrap();
+    assert!(!wiped.windows(12).any(|window| window == b"resident key"));
+    assert_eq!(std::fs::read(&backup).unwrap(), registered);
+
+    std::fs::remove_file(state_file).ok();
+    std::fs::remove_file(&backup).ok();
+}
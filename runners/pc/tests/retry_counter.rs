@@ -0,0 +1,113 @@
+use littlefs2::{const_ram_storage, fs::Filesystem};
+use trussed::types::{LfsResult, LfsStorage};
+use trussed::platform::{consent, reboot, ui};
+use trussed::{platform, store};
+use trussed::Interchange as _;
+use trussed::syscall;
+use trussed::client::FilesystemClient as _;
+use trussed::types::{Location, Message, PathBuf};
+
+use retry_counter::{Error, RetryCounter, Verification};
+use solo_pc::FileFlash;
+
+const_ram_storage!(ExternalStorage, 4096);
+const_ram_storage!(VolatileStorage, 8192);
+
+store!(Store,
+    Internal: FileFlash,
+    External: ExternalStorage,
+    Volatile: VolatileStorage
+);
+
+platform!(Board,
+    R: solo_pc::PlatformRng,
+    S: Store,
+    UI: UserInterface,
+);
+
+pub struct UserInterface {}
+
+impl trussed::platform::UserInterface for UserInterface {
+    fn check_user_presence(&mut self) -> consent::Level { consent::Level::Normal }
+    fn set_status(&mut self, _status: ui::Status) {}
+    fn refresh(&mut self) {}
+    fn uptime(&mut self) -> core::time::Duration { core::time::Duration::from_millis(1000) }
+    fn reboot(&mut self, _to: reboot::To) -> ! { loop { continue; } }
+}
+
+// Mount the filesystems like the runner does, reading internal flash back from `state_file`.
+fn mount(store: Store, state_file: &str, format: bool) {
+    let external_storage = Box::leak(Box::new(ExternalStorage::new()));
+    let volatile_storage = Box::leak(Box::new(VolatileStorage::new()));
+    Filesystem::format(external_storage).unwrap();
+    Filesystem::format(volatile_storage).unwrap();
+    store.mount(
+        Box::leak(Box::new(Filesystem::allocate())),
+        Box::leak(Box::new(FileFlash::new(state_file))),
+        Box::leak(Box::new(Filesystem::allocate())),
+        external_storage,
+        Box::leak(Box::new(Filesystem::allocate())),
+        volatile_storage,
+        format,
+    ).unwrap();
+}
+
+fn with_client<R>(store: Store, test: impl FnOnce(&mut trussed::ClientImplementation<&mut trussed::service::Service<Board>>) -> R) -> R {
+    unsafe { trussed::pipe::TrussedInterchange::reset_claims(); }
+    let board = Board::new(solo_pc::PlatformRng::os(), store, UserInterface {});
+    let mut service = trussed::service::Service::new(board);
+    let mut client = service.try_as_new_client("piv").unwrap();
+    test(&mut client)
+}
+
+#[test]
+fn crash_costs_an_attempt() {
+    let state_file = std::env::temp_dir().join(format!("solo-retries-{}.bin", std::process::id()));
+    let state_file = state_file.to_str().unwrap();
+    std::fs::remove_file(state_file).ok();
+
+    let pin = RetryCounter::new(b"pin-retries", 3);
+    let store = Store::claim().unwrap();
+
+    mount(store, state_file, true);
+    with_client(store, |client| {
+        assert_eq!(pin.remaining(client), Ok(3));
+        assert_eq!(pin.verify(client, |_| false), Ok(Verification::Failed { remaining: 2 }));
+
+        // the attempt is spent before the PIN is checked, then the device loses power
+        assert_eq!(pin.consume(client), Ok(1));
+    });
+
+    // as after a reboot: the interrupted attempt counts as a failed one
+    mount(store, state_file, false);
+    with_client(store, |client| {
+        assert_eq!(pin.remaining(client), Ok(1));
+        assert_eq!(pin.verify(client, |_| true), Ok(Verification::Verified));
+        assert_eq!(pin.remaining(client), Ok(3));
+    });
+
+    // a crash while checking the last attempt leaves none
+    mount(store, state_file, false);
+    with_client(store, |client| {
+        assert_eq!(pin.verify(client, |_| false), Ok(Verification::Failed { remaining: 2 }));
+        assert_eq!(pin.verify(client, |_| false), Ok(Verification::Failed { remaining: 1 }));
+        let crashed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            pin.verify(client, |_| panic!("power loss"))
+        }));
+        assert!(crashed.is_err());
+    });
+
+    mount(store, state_file, false);
+    with_client(store, |client| {
+        assert_eq!(pin.remaining(client), Ok(0));
+        assert_eq!(pin.verify(client, |_| unreachable!("blocked PIN is checked")), Err(Error::Blocked));
+        assert_eq!(pin.consume(client), Err(Error::Blocked));
+
+        // a counter that cannot be read fails closed, the secret is not checked
+        syscall!(client.write_file(Location::Internal, PathBuf::from(b"pin-retries"), Message::new(), None));
+        assert_eq!(pin.remaining(client), Err(Error::Corrupted));
+        assert_eq!(pin.verify(client, |_| unreachable!("unreadable counter is checked")), Err(Error::Corrupted));
+    });
+
+    std::fs::remove_file(state_file).ok();
+}
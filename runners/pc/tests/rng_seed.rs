@@ -0,0 +1,95 @@
+use rand_core::RngCore;
+
+use solo_pc::{instance_seed, parse_rng_seed, PlatformRng};
+
+const SEED: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+#[test]
+fn seeded_runs_repeat() {
+    let seed = parse_rng_seed(SEED).unwrap();
+    assert_eq!(seed[..4], [0x00, 0x01, 0x02, 0x03]);
+    assert_eq!(seed[31], 0x1f);
+
+    let mut first = [0u8; 64];
+    let mut second = [0u8; 64];
+    PlatformRng::seeded(seed).fill_bytes(&mut first);
+    PlatformRng::seeded(seed).fill_bytes(&mut second);
+    assert_eq!(first, second);
+
+    let mut other = [0u8; 64];
+    PlatformRng::seeded([0x42; 32]).fill_bytes(&mut other);
+    assert_ne!(first, other);
+}
+
+// `--count N` authenticators sharing one seed must not generate the same keys
+#[test]
+fn instances_differ() {
+    let seed = parse_rng_seed(SEED).unwrap();
+    assert_eq!(instance_seed(seed, 0), seed);
+
+    let outputs: Vec<[u8; 64]> = (0..4).map(|instance| {
+        let mut output = [0u8; 64];
+        PlatformRng::seeded(instance_seed(seed, instance)).fill_bytes(&mut output);
+        output
+    }).collect();
+    for (i, first) in outputs.iter().enumerate() {
+        for second in &outputs[i + 1..] {
+            assert_ne!(first[..], second[..]);
+        }
+    }
+
+    // and each still repeats
+    let mut again = [0u8; 64];
+    PlatformRng::seeded(instance_seed(seed, 3)).fill_bytes(&mut again);
+    assert_eq!(again[..], outputs[3][..]);
+}
+
+#[test]
+fn malformed_seeds() {
+    assert_eq!(parse_rng_seed(&SEED.to_uppercase()), parse_rng_seed(SEED));
+    assert!(parse_rng_seed(&format!(" {}\n", SEED)).is_some());
+
+    assert!(parse_rng_seed(&SEED[..62]).is_none());
+    assert!(parse_rng_seed(&format!("{}00", SEED)).is_none());
+    assert!(parse_rng_seed(&SEED.replace("0a", "0g")).is_none());
+    assert!(parse_rng_seed(&SEED.replace("00", "+0")).is_none());
+}
+
+fn seed_file(name: &str, contents: &[u8]) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("solo-{}-{}.bin", name, std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn seed_file_runs_repeat() {
+    let seed = seed_file("rng-seed", &parse_rng_seed(SEED).unwrap());
+
+    // e.g. a P-256 private key, as trussed draws it
+    let key = |rng: &mut PlatformRng| {
+        let mut key = [0u8; 32];
+        rng.fill_bytes(&mut key);
+        key
+    };
+    let first = key(&mut PlatformRng::from_seed_file(&seed).unwrap());
+    let second = key(&mut PlatformRng::from_seed_file(&seed).unwrap());
+    assert_eq!(first, second);
+    // the same as the seed given in hex
+    assert_eq!(first, key(&mut PlatformRng::seeded(parse_rng_seed(SEED).unwrap())));
+
+    std::fs::remove_file(&seed).ok();
+}
+
+#[test]
+fn seed_file_size_mismatch() {
+    for &len in &[0, 31, 33, 64] {
+        let seed = seed_file("rng-seed-size", &vec![0x42; len]);
+        let error = PlatformRng::from_seed_file(&seed).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains(&format!("has {} bytes, expected 32", len)));
+        std::fs::remove_file(&seed).ok();
+    }
+
+    let missing = std::env::temp_dir().join(format!("solo-no-seed-{}.bin", std::process::id()));
+    assert!(PlatformRng::from_seed_file(&missing).is_err());
+}
@@ -0,0 +1,117 @@
+use littlefs2::{const_ram_storage, fs::Filesystem};
+use trussed::types::{LfsResult, LfsStorage};
+use trussed::platform::{consent, reboot, ui};
+use trussed::{platform, store};
+use trussed::Interchange as _;
+use trussed::{syscall, try_syscall};
+use trussed::client::FilesystemClient as _;
+use trussed::types::{Location, Message, PathBuf};
+
+use signature_counter::SignatureCounter;
+use solo_pc::FileFlash;
+
+const_ram_storage!(ExternalStorage, 4096);
+const_ram_storage!(VolatileStorage, 8192);
+
+store!(Store,
+    Internal: FileFlash,
+    External: ExternalStorage,
+    Volatile: VolatileStorage
+);
+
+platform!(Board,
+    R: solo_pc::PlatformRng,
+    S: Store,
+    UI: UserInterface,
+);
+
+pub struct UserInterface {}
+
+impl trussed::platform::UserInterface for UserInterface {
+    fn check_user_presence(&mut self) -> consent::Level { consent::Level::Normal }
+    fn set_status(&mut self, _status: ui::Status) {}
+    fn refresh(&mut self) {}
+    fn uptime(&mut self) -> core::time::Duration { core::time::Duration::from_millis(1000) }
+    fn reboot(&mut self, _to: reboot::To) -> ! { loop { continue; } }
+}
+
+// Mount the filesystems like the runner does, reading internal flash back from `state_file`.
+fn mount(store: Store, state_file: &str, format: bool) {
+    let external_storage = Box::leak(Box::new(ExternalStorage::new()));
+    let volatile_storage = Box::leak(Box::new(VolatileStorage::new()));
+    Filesystem::format(external_storage).unwrap();
+    Filesystem::format(volatile_storage).unwrap();
+    store.mount(
+        Box::leak(Box::new(Filesystem::allocate())),
+        Box::leak(Box::new(FileFlash::new(state_file))),
+        Box::leak(Box::new(Filesystem::allocate())),
+        external_storage,
+        Box::leak(Box::new(Filesystem::allocate())),
+        volatile_storage,
+        format,
+    ).unwrap();
+}
+
+fn with_counter<R>(store: Store, test: impl FnOnce(&mut trussed::ClientImplementation<&mut trussed::service::Service<Board>>) -> R) -> R {
+    unsafe { trussed::pipe::TrussedInterchange::reset_claims(); }
+    let board = Board::new(solo_pc::PlatformRng::os(), store, UserInterface {});
+    let mut service = trussed::service::Service::new(board);
+    let mut client = service.try_as_new_client("fido").unwrap();
+    test(&mut client)
+}
+
+#[test]
+fn persists_across_remount() {
+    let state_file = std::env::temp_dir().join(format!("solo-counter-{}.bin", std::process::id()));
+    let state_file = state_file.to_str().unwrap();
+    std::fs::remove_file(state_file).ok();
+
+    let counter = SignatureCounter::default();
+    let store = Store::claim().unwrap();
+    let data = Message::try_from_slice(b"survives").unwrap();
+
+    mount(store, state_file, true);
+    with_counter(store, |client| {
+        // missing counter reads as 0
+        assert_eq!(counter.read(client), Ok(0));
+        assert_eq!(counter.increment(client), Ok(1));
+        assert_eq!(counter.increment(client), Ok(2));
+        assert_eq!(counter.read(client), Ok(2));
+
+        syscall!(client.write_file(Location::Internal, PathBuf::from(b"kept"), data.clone(), None));
+        syscall!(client.write_file(Location::Volatile, PathBuf::from(b"lost"), data.clone(), None));
+    });
+
+    // as after a reboot: everything in memory is gone, except what is in the file.
+    // The blank RAM filesystems must not cause internal flash to be reformatted.
+    mount(store, state_file, false);
+    with_counter(store, |client| {
+        let read = syscall!(client.read_file(Location::Internal, PathBuf::from(b"kept"))).data;
+        assert_eq!(read, data);
+        assert!(try_syscall!(client.read_file(Location::Volatile, PathBuf::from(b"lost"))).is_err());
+
+        assert_eq!(counter.read(client), Ok(2));
+        assert_eq!(counter.increment(client), Ok(3));
+
+        // other counters are independent
+        let other = SignatureCounter::new(b"other-counter");
+        assert_eq!(other.increment(client), Ok(1));
+        assert_eq!(counter.read(client), Ok(3));
+
+        // U2F counted with the timestamp shared with CTAP2 before it got its own counter.
+        // On such a device, at timestamp 1000, the first U2F authentication continues above it.
+        let u2f = SignatureCounter::new(b"u2f-signature-counter");
+        let timestamp = 1000;
+        assert_eq!(u2f.read(client), Ok(0));
+        let first = u2f.increment_from(client, timestamp).unwrap();
+        assert!(first > timestamp);
+        assert_eq!(u2f.read(client), Ok(first));
+
+        // once the file exists, the floor only matters if it overtakes the counter
+        assert_eq!(u2f.increment_from(client, timestamp), Ok(first + 1));
+        assert_eq!(u2f.increment(client), Ok(first + 2));
+        assert_eq!(u2f.increment_from(client, 5000), Ok(5001));
+    });
+
+    std::fs::remove_file(state_file).ok();
+}
@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+
+use solo_pc::{seed_state, snapshot_state, STATE_SIZE};
+
+fn temp(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("solo-{}-{}.bin", name, std::process::id()));
+    std::fs::remove_file(&path).ok();
+    path
+}
+
+#[test]
+fn seed_and_snapshot() {
+    let state = temp("seeded-state");
+    let seed = temp("seed");
+    let snapshot = temp("snapshot");
+    std::fs::write(&seed, vec![0x5a; STATE_SIZE]).unwrap();
+
+    // no state yet: the seed is applied
+    assert!(seed_state(&state, Some(&seed), false).unwrap());
+    assert_eq!(std::fs::read(&state).unwrap(), vec![0x5a; STATE_SIZE]);
+
+    // existing state is kept, unless reset
+    std::fs::write(&state, vec![0x01; STATE_SIZE]).unwrap();
+    assert!(!seed_state(&state, Some(&seed), false).unwrap());
+    assert_eq!(std::fs::read(&state).unwrap(), vec![0x01; STATE_SIZE]);
+
+    snapshot_state(&state, &snapshot).unwrap();
+    assert!(seed_state(&state, Some(&seed), true).unwrap());
+    assert_eq!(std::fs::read(&state).unwrap(), vec![0x5a; STATE_SIZE]);
+
+    // the snapshot seeds the earlier state back
+    assert!(seed_state(&state, Some(&snapshot), true).unwrap());
+    assert_eq!(std::fs::read(&state).unwrap(), vec![0x01; STATE_SIZE]);
+
+    // reset without a seed starts from scratch
+    assert!(seed_state(&state, None, true).unwrap());
+    assert!(!state.exists());
+    assert!(!seed_state(&state, None, true).unwrap());
+
+    for path in [&state, &seed, &snapshot].iter() {
+        std::fs::remove_file(path).ok();
+    }
+}
+
+#[test]
+fn seed_of_wrong_size() {
+    let state = temp("unseeded-state");
+    let seed = temp("short-seed");
+    std::fs::write(&seed, [0x5a; 16]).unwrap();
+    std::fs::write(&state, vec![0x01; STATE_SIZE]).unwrap();
+
+    let err = seed_state(&state, Some(&seed), true).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(std::fs::read(&state).unwrap(), vec![0x01; STATE_SIZE]);
+
+    std::fs::remove_file(&state).ok();
+    std::fs::remove_file(&seed).ok();
+}
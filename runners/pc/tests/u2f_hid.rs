@@ -0,0 +1,91 @@
+use littlefs2::{const_ram_storage, fs::Filesystem};
+use trussed::types::{LfsResult, LfsStorage};
+use trussed::platform::{consent, reboot, ui};
+use trussed::{platform, store};
+use trussed::Interchange as _;
+
+use ctaphid_dispatch::app as hid;
+use ctaphid_dispatch::dispatch::Dispatch;
+use ctaphid_dispatch::types::HidInterchange;
+use solo_pc::FileFlash;
+
+const_ram_storage!(ExternalStorage, 4096);
+const_ram_storage!(VolatileStorage, 8192);
+
+store!(Store,
+    Internal: FileFlash,
+    External: ExternalStorage,
+    Volatile: VolatileStorage
+);
+
+platform!(Board,
+    R: solo_pc::PlatformRng,
+    S: Store,
+    UI: UserInterface,
+);
+
+pub struct UserInterface {}
+
+impl trussed::platform::UserInterface for UserInterface {
+    fn check_user_presence(&mut self) -> consent::Level { consent::Level::Normal }
+    fn set_status(&mut self, _status: ui::Status) {}
+    fn refresh(&mut self) {}
+    fn uptime(&mut self) -> core::time::Duration { core::time::Duration::from_millis(1000) }
+    fn reboot(&mut self, _to: reboot::To) -> ! { loop { continue; } }
+}
+
+// U2F messages over CTAPHID (U2FHID_MSG) are complete APDUs, in both directions:
+// the response carries its status word, framed by `ctap1::U2fTransport::Hid`.
+#[test]
+fn u2f_over_hid() {
+    let state_file = std::env::temp_dir().join(format!("solo-u2f-hid-{}.bin", std::process::id()));
+    let state_file = state_file.to_str().unwrap();
+    std::fs::remove_file(state_file).ok();
+
+    let store = Store::claim().unwrap();
+    let external_storage = Box::leak(Box::new(ExternalStorage::new()));
+    let volatile_storage = Box::leak(Box::new(VolatileStorage::new()));
+    Filesystem::format(external_storage).unwrap();
+    Filesystem::format(volatile_storage).unwrap();
+    store.mount(
+        Box::leak(Box::new(Filesystem::allocate())),
+        Box::leak(Box::new(FileFlash::new(state_file))),
+        Box::leak(Box::new(Filesystem::allocate())),
+        external_storage,
+        Box::leak(Box::new(Filesystem::allocate())),
+        volatile_storage,
+        true,
+    ).unwrap();
+
+    unsafe { trussed::pipe::TrussedInterchange::reset_claims(); }
+    let board = Board::new(solo_pc::PlatformRng::os(), store, UserInterface {});
+    let mut service = trussed::service::Service::new(board);
+    let client = service.try_as_new_client("fido").unwrap();
+    let authnr = fido_authenticator::Authenticator::new(client, fido_authenticator::SilentAuthenticator {});
+    let mut fido = dispatch_fido::Fido::new(authnr);
+
+    unsafe { HidInterchange::reset_claims(); }
+    let (mut requester, responder) = HidInterchange::claim().unwrap();
+    let mut dispatch = Dispatch::new(responder);
+    let mut exchange = |apdu: &[u8]| -> Vec<u8> {
+        let message = hid::Message::try_from_slice(apdu).unwrap();
+        requester.request(&(hid::Command::Msg, message)).unwrap();
+        assert!(dispatch.poll(&mut [&mut fido]));
+        requester.take_response().unwrap().unwrap().as_slice().to_vec()
+    };
+
+    // VERSION
+    assert_eq!(exchange(&[0x00, 0x03, 0x00, 0x00, 0x00]), b"U2F_V2\x90\x00");
+
+    // REGISTER, with challenge and application parameter. The runner is not provisioned
+    // with an attestation key, so the well-formed answer is `KeyReferenceNotFound` alone.
+    let mut register = vec![0x00, 0x01, 0x03, 0x00, 0x40];
+    register.extend_from_slice(&[0x11; 32]);
+    register.extend_from_slice(&[0x22; 32]);
+    assert_eq!(exchange(&register), [0x6A, 0x88]);
+
+    // the CTAP1 parser only accepts CLA 0
+    assert_eq!(exchange(&[0x80, 0x03, 0x00, 0x00, 0x00]), [0x6E, 0x00]);
+
+    std::fs::remove_file(state_file).ok();
+}